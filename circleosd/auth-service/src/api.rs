@@ -3,13 +3,18 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::net::{UnixListener, UnixStream};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tracing::{info, error};
+use tracing::{info, warn, error};
 
 use crate::db;
 use crate::hash;
+use circleosd_proto::jsonrpc::{self, RpcError, RpcReply};
+use circleosd_proto::peercred::{AccessList, Peer};
 use crate::session::SessionStore;
 use rusqlite::Connection;
 
+/// Action names, also accepted as JSON-RPC 2.0 method names.
+pub const METHODS: &[&str] = &["create_user", "login", "whoami", "ping"];
+
 /// RPC Request variants
 #[derive(Debug, Deserialize)]
 #[serde(tag = "action")]
@@ -22,6 +27,9 @@ pub enum Request {
 
     #[serde(rename = "whoami")]
     WhoAmI { token: String },
+
+    #[serde(rename = "ping")]
+    Ping {},
 }
 
 /// RPC Response
@@ -33,9 +41,19 @@ pub struct Response {
     pub data: Option<serde_json::Value>,
 }
 
+impl RpcReply for Response {
+    fn invalid(message: String) -> Self {
+        Response { ok: false, message: Some(message), data: None }
+    }
+
+    fn into_jsonrpc(self) -> Result<serde_json::Value, RpcError> {
+        jsonrpc::split_reply(self.ok, self.message, self.data, jsonrpc::SERVER_ERROR)
+    }
+}
+
 /// Start serving RPC over the given Unix socket path.
 /// Blocks (awaits) until the listener stops.
-pub async fn serve(socket_path: std::path::PathBuf, conn: Connection) -> Result<()> {
+pub async fn serve(socket_path: std::path::PathBuf, conn: Connection, access: AccessList) -> Result<()> {
     // session store
     let sessions = Arc::new(SessionStore::new());
     // spawn a cleanup task for sessions
@@ -52,22 +70,28 @@ pub async fn serve(socket_path: std::path::PathBuf, conn: Connection) -> Result<
     let _ = std::fs::remove_file(&socket_path);
     let listener = UnixListener::bind(&socket_path)?;
     // restrict permissions to owner only (600)
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        let mut perms = std::fs::metadata(&socket_path)?.permissions();
-        perms.set_mode(0o600);
-        std::fs::set_permissions(&socket_path, perms)?;
-    }
+    access.apply_to_socket(&socket_path)?;
 
     info!("auth-service listening on {}", socket_path.display());
 
     loop {
         let (stream, _) = listener.accept().await?;
+        let peer = match Peer::of(&stream) {
+            Ok(peer) if access.allows(&peer) => peer,
+            Ok(peer) => {
+                warn!("rejected connection from {}", peer);
+                let _ = stream.try_write(b"{\"ok\":false,\"message\":\"permission denied: uid not allowed\"}\n");
+                continue;
+            }
+            Err(e) => {
+                warn!("rejected connection: cannot read peer credentials: {}", e);
+                continue;
+            }
+        };
         let conn = conn.clone();
         let sessions = sessions.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(stream, conn, sessions).await {
+            if let Err(e) = handle_connection(stream, peer, conn, sessions).await {
                 error!("connection error: {:?}", e);
             }
         });
@@ -75,7 +99,8 @@ pub async fn serve(socket_path: std::path::PathBuf, conn: Connection) -> Result<
 }
 
 /// Handle a single client connection; reads newline-separated JSON requests and replies linewise.
-async fn handle_connection(stream: UnixStream, conn: Connection, sessions: Arc<SessionStore>) -> Result<()> {
+async fn handle_connection(stream: UnixStream, peer: Peer, conn: Connection, sessions: Arc<SessionStore>) -> Result<()> {
+    tracing::debug!("connection from {}", peer);
     let (r, mut w) = stream.into_split();
     let mut reader = BufReader::new(r).lines();
 
@@ -83,17 +108,12 @@ async fn handle_connection(stream: UnixStream, conn: Connection, sessions: Arc<S
         if line.trim().is_empty() {
             continue;
         }
-        tracing::debug!("auth-service received: {}", line);
-        let resp = match serde_json::from_str::<Request>(&line) {
-            Ok(req) => process_request(req, &conn, &sessions).await,
-            Err(e) => {
-                tracing::warn!("invalid request: {}", e);
-                Response { ok: false, message: Some(format!("invalid request: {}", e)), data: None }
-            }
-        };
-        let out = serde_json::to_string(&resp)?;
-        w.write_all(out.as_bytes()).await?;
-        w.write_all(b"\n").await?;
+        tracing::debug!("auth-service received from {}: {}", peer, line);
+        let out = jsonrpc::handle_line(&line, METHODS, |req| process_request(req, &conn, &sessions)).await;
+        if let Some(out) = out {
+            w.write_all(out.as_bytes()).await?;
+            w.write_all(b"\n").await?;
+        }
     }
 
     Ok(())
@@ -197,5 +217,7 @@ async fn process_request(req: Request, conn: &Connection, sessions: &Arc<Session
                 None => Response { ok: false, message: Some("invalid or expired token".into()), data: None },
             }
         }
+
+        Request::Ping {} => Response { ok: true, message: Some("pong".into()), data: None },
     }
 }
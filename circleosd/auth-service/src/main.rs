@@ -1,5 +1,6 @@
 use anyhow::Result;
 use std::path::PathBuf;
+use circleosd_proto::peercred::AccessList;
 use tracing::info;
 
 mod api;
@@ -15,7 +16,9 @@ async fn main() -> Result<()> {
 
     // default paths
     let db_path = PathBuf::from("./var/auth.db");
-    let socket_path = PathBuf::from("/tmp/auth-service.sock");
+    // core-daemon passes its run directory; /tmp when started by hand
+    let run_dir = std::env::var_os("CIRCLEOSD_RUN_DIR").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("/tmp"));
+    let socket_path = run_dir.join("auth-service.sock");
 
     // ensure var directory exists
     if let Some(parent) = db_path.parent() {
@@ -24,11 +27,15 @@ async fn main() -> Result<()> {
     // remove stale socket if present
     let _ = std::fs::remove_file(&socket_path);
 
+    // only root and core-daemon's uid; everyone else goes through
+    // core-daemon and its access list
+    let access = AccessList::for_component().map_err(|e| anyhow::anyhow!(e))?;
+
     // initialize database
     let conn = db::init_db(&db_path)?;
 
     // start API listener (takes ownership of conn)
-    api::serve(socket_path, conn).await?;
+    api::serve(socket_path, conn, access).await?;
 
     Ok(())
 }
@@ -1,8 +1,43 @@
 use anyhow::Result;
 use tokio::net::UnixStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, AsyncBufReadExt};
+use tokio::io::{AsyncWriteExt, BufReader, AsyncBufReadExt};
 use serde_json::Value;
 
+use crate::config::CliConfig;
+
+/// Send a request to core-daemon, attaching the session token if we have one.
+pub async fn call(cfg: &CliConfig, mut req: Value) -> Result<Value> {
+    with_token(cfg, &mut req);
+    send_unix_request(&cfg.socket, &req.to_string()).await
+}
+
+/// Send a streaming request (e.g. `subscribe`) and hand every line after
+/// the first reply to `on_line` until core-daemon closes the connection.
+pub async fn stream(cfg: &CliConfig, mut req: Value, mut on_line: impl FnMut(Value)) -> Result<()> {
+    with_token(cfg, &mut req);
+    let mut stream = UnixStream::connect(&cfg.socket).await?;
+    stream.write_all(req.to_string().as_bytes()).await?;
+    stream.write_all(b"\n").await?;
+    let (r, _w) = stream.into_split();
+    let mut reader = BufReader::new(r).lines();
+
+    let first = reader.next_line().await?.ok_or_else(|| anyhow::anyhow!("no response"))?;
+    let reply: Value = serde_json::from_str(&first)?;
+    if reply.get("ok").and_then(Value::as_bool) != Some(true) {
+        anyhow::bail!("{}", reply.get("message").and_then(Value::as_str).unwrap_or("request failed"));
+    }
+    while let Some(line) = reader.next_line().await? {
+        on_line(serde_json::from_str(&line)?);
+    }
+    Ok(())
+}
+
+fn with_token(cfg: &CliConfig, req: &mut Value) {
+    if let (Some(token), Some(obj)) = (&cfg.token, req.as_object_mut()) {
+        obj.insert("token".into(), Value::String(token.clone()));
+    }
+}
+
 pub async fn send_unix_request(socket: &str, req: &str) -> Result<Value> {
     let mut stream = UnixStream::connect(socket).await?;
     stream.write_all(req.as_bytes()).await?;
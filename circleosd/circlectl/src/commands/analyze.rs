@@ -0,0 +1,194 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt::Write as _;
+use std::path::Path;
+use crate::client;
+use crate::config::CliConfig;
+
+/// Reply of `boot_timing`; times are milliseconds since core-daemon started.
+#[derive(Debug, Deserialize)]
+struct BootTiming {
+    started_at: String,
+    ready_ms: Option<u64>,
+    spans: Vec<Span>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Span {
+    name: String,
+    kind: String,
+    start_ms: u64,
+    end_ms: Option<u64>,
+    state: String,
+    #[serde(default)]
+    after: Vec<String>,
+}
+
+impl Span {
+    fn took(&self) -> Option<u64> {
+        self.end_ms.map(|end| end.saturating_sub(self.start_ms))
+    }
+}
+
+/// `circlectl system analyze [--svg FILE]`
+pub async fn run(svg: Option<&Path>) -> Result<()> {
+    let cfg = CliConfig::load_or_default();
+    let resp = client::call(&cfg, json!({"action":"boot_timing"})).await?;
+    if resp.get("ok").and_then(Value::as_bool) != Some(true) {
+        anyhow::bail!("{}", resp.get("message").and_then(Value::as_str).unwrap_or("boot_timing failed"));
+    }
+    let timing: BootTiming = serde_json::from_value(resp.get("data").cloned().unwrap_or_default())?;
+
+    match timing.ready_ms {
+        Some(ms) => println!("Boot finished in {} (started {})", secs(ms), timing.started_at),
+        None => println!("Still booting (started {})", timing.started_at),
+    }
+
+    println!();
+    println!("Critical chain (@ when it was done, + how long it took):");
+    let chain = critical_chain(&timing.spans);
+    if let Some(ms) = timing.ready_ms {
+        println!("system ready @{}", secs(ms));
+    }
+    for (depth, span) in chain.iter().enumerate() {
+        let indent = "  ".repeat(depth);
+        println!(
+            "{}└─{} @{} +{}ms{}",
+            indent,
+            span.name,
+            secs(span.end_ms.unwrap_or_default()),
+            span.took().unwrap_or_default(),
+            state_note(span)
+        );
+    }
+
+    println!();
+    println!("Blame:");
+    let mut blame: Vec<&Span> = timing.spans.iter().filter(|s| s.end_ms.is_some()).collect();
+    blame.sort_by_key(|s| std::cmp::Reverse(s.took()));
+    for span in blame {
+        println!("{:>8}ms {}{}", span.took().unwrap_or_default(), span.name, state_note(span));
+    }
+
+    if let Some(path) = svg {
+        std::fs::write(path, render_svg(&timing))?;
+        println!();
+        println!("Timeline written to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Starting from the step that finished last, follow each step back to the
+/// one it waited for that finished latest.
+fn critical_chain(spans: &[Span]) -> Vec<&Span> {
+    let find = |name: &str| spans.iter().find(|s| s.name == name && s.end_ms.is_some());
+    let mut chain = Vec::new();
+    let mut cur = spans.iter().filter(|s| s.end_ms.is_some()).max_by_key(|s| s.end_ms);
+    while let Some(span) = cur {
+        if chain.iter().any(|s: &&Span| s.name == span.name) {
+            break;
+        }
+        chain.push(span);
+        cur = span.after.iter().filter_map(|a| find(a)).max_by_key(|s| s.end_ms);
+    }
+    chain
+}
+
+fn state_note(span: &Span) -> String {
+    match span.state.as_str() {
+        "ready" | "started" | "loaded" => String::new(),
+        other => format!(" ({})", other.replace('_', " ")),
+    }
+}
+
+fn secs(ms: u64) -> String {
+    format!("{}.{:03}s", ms / 1000, ms % 1000)
+}
+
+/// One bar per boot step on a shared time axis.
+fn render_svg(timing: &BootTiming) -> String {
+    const LABEL_W: u64 = 260;
+    const CHART_W: u64 = 720;
+    const ROW_H: u64 = 22;
+    const TOP: u64 = 40;
+
+    let end = timing
+        .spans
+        .iter()
+        .filter_map(|s| s.end_ms)
+        .chain(timing.ready_ms)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let x = |ms: u64| LABEL_W + ms * CHART_W / end;
+    let height = TOP + ROW_H * timing.spans.len() as u64 + 20;
+    let width = LABEL_W + CHART_W + 20;
+
+    let mut out = String::new();
+    let _ = writeln!(out, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" font-family="monospace" font-size="12">"#);
+    let _ = writeln!(out, r#"<rect width="100%" height="100%" fill="white"/>"#);
+    let title = match timing.ready_ms {
+        Some(ms) => format!("CircleOSD boot, {} (started {})", secs(ms), timing.started_at),
+        None => format!("CircleOSD boot, still running (started {})", timing.started_at),
+    };
+    let _ = writeln!(out, r#"<text x="10" y="18" font-size="14">{}</text>"#, escape(&title));
+
+    // grid line roughly every tenth of the chart, on a round number of ms
+    let step = [10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000, 30000, 60000]
+        .into_iter()
+        .find(|s| end / s <= 10)
+        .unwrap_or(end);
+    for t in (0..=end).step_by(step as usize) {
+        let _ = writeln!(
+            out,
+            r##"<line x1="{0}" y1="{1}" x2="{0}" y2="{2}" stroke="#ddd"/><text x="{0}" y="{3}" fill="#888">{4}</text>"##,
+            x(t),
+            TOP - 8,
+            height - 20,
+            TOP - 12,
+            secs(t)
+        );
+    }
+
+    for (i, span) in timing.spans.iter().enumerate() {
+        let y = TOP + ROW_H * i as u64;
+        let colour = match span.state.as_str() {
+            "failed" => "#d9534f",
+            "skipped" | "running" => "#bbbbbb",
+            _ if span.kind == "component" => "#5b9bd5",
+            _ => "#70ad47",
+        };
+        let bar_end = span.end_ms.unwrap_or(end);
+        let w = x(bar_end).saturating_sub(x(span.start_ms)).max(2);
+        let _ = writeln!(
+            out,
+            r#"<text x="10" y="{}">{}</text><rect x="{}" y="{}" width="{}" height="{}" fill="{}"><title>{}: {}ms, {}</title></rect>"#,
+            y + 15,
+            escape(&span.name),
+            x(span.start_ms),
+            y + 4,
+            w,
+            ROW_H - 6,
+            colour,
+            escape(&span.name),
+            span.took().unwrap_or_default(),
+            escape(&span.state)
+        );
+    }
+    if let Some(ms) = timing.ready_ms {
+        let _ = writeln!(
+            out,
+            r##"<line x1="{0}" y1="{1}" x2="{0}" y2="{2}" stroke="#d9534f" stroke-dasharray="4"/>"##,
+            x(ms),
+            TOP - 8,
+            height - 20
+        );
+    }
+    out.push_str("</svg>\n");
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -0,0 +1,27 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use crate::client;
+use crate::config::CliConfig;
+
+/// Print events from core-daemon as they happen (until interrupted).
+pub async fn run(topics: Vec<String>, since: Option<u64>, as_json: bool) -> Result<()> {
+    let cfg = CliConfig::load_or_default();
+    let req = json!({ "action": "subscribe", "topics": topics, "since": since });
+    client::stream(&cfg, req, |ev| {
+        if as_json {
+            println!("{}", ev);
+        } else if let Some(missed) = ev.get("lagged") {
+            println!("(missed {} events)", missed);
+        } else {
+            let field = |k: &str| ev.get(k).cloned().unwrap_or(Value::Null);
+            println!(
+                "#{} {} {} {}",
+                field("seq"),
+                field("ts").as_str().unwrap_or(""),
+                field("topic").as_str().unwrap_or(""),
+                field("data")
+            );
+        }
+    })
+    .await
+}
@@ -0,0 +1,48 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use crate::client;
+use crate::config::CliConfig;
+
+/// Filters for `circlectl logs`, passed straight to `query_logs`.
+pub struct LogFilter {
+    pub component: Option<String>,
+    pub level: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub grep: Option<String>,
+    pub limit: usize,
+    pub json: bool,
+}
+
+pub async fn run(filter: LogFilter) -> Result<()> {
+    let cfg = CliConfig::load_or_default();
+    let req = json!({
+        "action": "query_logs",
+        "component": filter.component,
+        "level": filter.level,
+        "since": filter.since,
+        "until": filter.until,
+        "text": filter.grep,
+        "limit": filter.limit,
+    });
+    let resp = client::call(&cfg, req).await?;
+    if resp.get("ok").and_then(Value::as_bool) != Some(true) {
+        println!("{}", serde_json::to_string_pretty(&resp)?);
+        return Ok(());
+    }
+
+    let records = resp.get("data").and_then(Value::as_array).cloned().unwrap_or_default();
+    for r in records {
+        if filter.json {
+            println!("{}", r);
+            continue;
+        }
+        let field = |k: &str| r.get(k).and_then(Value::as_str).unwrap_or("").to_string();
+        let extra = match r.get("fields") {
+            Some(f) => format!(" {}", f),
+            None => String::new(),
+        };
+        println!("{} {:<5} {}: {}{}", field("ts"), field("level"), field("component"), field("msg"), extra);
+    }
+    Ok(())
+}
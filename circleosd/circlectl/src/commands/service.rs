@@ -1,31 +1,120 @@
 use anyhow::Result;
-use serde_json::json;
+use serde_json::{json, Value};
 use crate::client;
 use crate::config::CliConfig;
 
-pub async fn run(action: &str, name: Option<String>) -> Result<()> {
+pub async fn run(action: &str, name: Option<String>, replicas: Option<u32>, follow: bool, lines: usize, reverse: bool, dry_run: bool) -> Result<()> {
     let cfg = CliConfig::load_or_default();
     match action {
         "list" => {
-            let req = json!({"action":"list"}).to_string();
-            let resp = client::send_unix_request(&cfg.registry_socket, &req).await?;
+            let resp = client::call(&cfg, json!({"action":"list_services"})).await?;
             println!("{}", serde_json::to_string_pretty(&resp)?);
         }
         "start" => {
             let svc = name.ok_or_else(|| anyhow::anyhow!("service name required"))?;
-            let req = json!({"action":"start","name":svc}).to_string();
-            let resp = client::send_unix_request(&cfg.registry_socket, &req).await?;
+            let resp = client::call(&cfg, json!({"action":"start_service","name":svc})).await?;
             println!("{}", serde_json::to_string_pretty(&resp)?);
         }
         "stop" => {
             let svc = name.ok_or_else(|| anyhow::anyhow!("service name required"))?;
-            let req = json!({"action":"stop","name":svc}).to_string();
-            let resp = client::send_unix_request(&cfg.registry_socket, &req).await?;
+            let resp = client::call(&cfg, json!({"action":"stop_service","name":svc})).await?;
             println!("{}", serde_json::to_string_pretty(&resp)?);
         }
+        "scale" => {
+            let svc = name.ok_or_else(|| anyhow::anyhow!("template name required, e.g. worker@"))?;
+            let n = replicas.ok_or_else(|| anyhow::anyhow!("number of replicas required"))?;
+            let resp = client::call(&cfg, json!({"action":"scale_service","name":svc,"replicas":n})).await?;
+            println!("{}", serde_json::to_string_pretty(&resp)?);
+        }
+        "reset-failed" => {
+            let svc = name.ok_or_else(|| anyhow::anyhow!("service name required"))?;
+            let resp = client::call(&cfg, json!({"action":"reset_failed_service","name":svc})).await?;
+            println!("{}", serde_json::to_string_pretty(&resp)?);
+        }
+        "logs" => {
+            let svc = name.ok_or_else(|| anyhow::anyhow!("service name required"))?;
+            let req = json!({"action":"service_logs","name":svc,"lines":lines,"follow":follow});
+            if follow {
+                client::stream(&cfg, req, |line| print_line(&line)).await?;
+            } else {
+                let resp = client::call(&cfg, req).await?;
+                if resp.get("ok").and_then(Value::as_bool) != Some(true) {
+                    anyhow::bail!("{}", resp.get("message").and_then(Value::as_str).unwrap_or("service_logs failed"));
+                }
+                for line in resp.get("data").and_then(Value::as_array).into_iter().flatten() {
+                    print_line(line);
+                }
+            }
+        }
+        "timers" => {
+            let resp = client::call(&cfg, json!({"action":"list_timers"})).await?;
+            if resp.get("ok").and_then(Value::as_bool) != Some(true) {
+                anyhow::bail!("{}", resp.get("message").and_then(Value::as_str).unwrap_or("list_timers failed"));
+            }
+            print_timers(resp.get("data").and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default());
+        }
+        "apply" => {
+            let resp = client::call(&cfg, json!({"action":"apply_services","dry_run":dry_run})).await?;
+            println!("{}", serde_json::to_string_pretty(&resp)?);
+        }
+        "deps" => {
+            let svc = name.ok_or_else(|| anyhow::anyhow!("service name required"))?;
+            let resp = client::call(&cfg, json!({"action":"service_deps","name":svc,"reverse":reverse})).await?;
+            if resp.get("ok").and_then(Value::as_bool) != Some(true) {
+                anyhow::bail!("{}", resp.get("message").and_then(Value::as_str).unwrap_or("service_deps failed"));
+            }
+            if let Some(tree) = resp.get("data") {
+                print_tree(tree, "", "");
+            }
+        }
         _ => {
             println!("unknown service action: {}", action);
         }
     }
     Ok(())
 }
+
+/// `<ts> <stream>: <line>`, as a service wrote it.
+fn print_line(line: &Value) {
+    if let Some(missed) = line.get("lagged") {
+        println!("(missed {} lines)", missed);
+        return;
+    }
+    let field = |k: &str| line.get(k).and_then(Value::as_str).unwrap_or("");
+    println!("{} {}: {}", field("ts"), field("stream"), field("line"));
+}
+
+/// `list_timers` as a table, soonest first.
+fn print_timers(timers: &[Value]) {
+    println!("{:<22} {:>8}  {:<22} {:<20} ON", "NEXT", "LEFT", "LAST", "SERVICE");
+    for timer in timers {
+        let field = |k: &str| timer.get(k).and_then(Value::as_str).unwrap_or("-");
+        let left = timer.get("next_in_secs").and_then(Value::as_i64).map(fmt_secs).unwrap_or_else(|| "-".into());
+        let on: Vec<&str> = timer.get("on").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str).collect();
+        println!("{:<22} {:>8}  {:<22} {:<20} {}", field("next"), left, field("last"), field("name"), on.join(", "));
+    }
+}
+
+/// 90061 -> "1d1h", 125 -> "2m5s".
+fn fmt_secs(secs: i64) -> String {
+    let (d, h, m, s) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    match (d, h, m) {
+        (0, 0, 0) => format!("{}s", s),
+        (0, 0, _) => format!("{}m{}s", m, s),
+        (0, _, _) => format!("{}h{}m", h, m),
+        _ => format!("{}d{}h", d, h),
+    }
+}
+
+/// One node of a `deps` tree and, indented below it, its children.
+fn print_tree(node: &Value, lead: &str, indent: &str) {
+    let field = |k: &str| node.get(k).and_then(Value::as_str).unwrap_or("");
+    let relation = node.get("relation").and_then(Value::as_str).map(|r| format!("{} ", r)).unwrap_or_default();
+    println!("{}{}{} ({})", lead, relation, field("name"), field("state"));
+    let children = node.get("deps").and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default();
+    for (i, child) in children.iter().enumerate() {
+        let last = i + 1 == children.len();
+        let (lead, more) = if last { ("└─ ", "   ") } else { ("├─ ", "│  ") };
+        print_tree(child, &format!("{}{}", indent, lead), &format!("{}{}", indent, more));
+    }
+}
@@ -1,13 +1,72 @@
 use anyhow::Result;
+use serde_json::{json, Value};
+use crate::client;
+use crate::config::CliConfig;
+use std::path::PathBuf;
 
-pub async fn run(action: &str) -> Result<()> {
+pub async fn run(action: &str, target: Option<String>, svg: Option<PathBuf>) -> Result<()> {
     match action {
         "status" => {
             println!("circlectl system status: (stub) All systems nominal");
         }
+        "reload" => {
+            let cfg = CliConfig::load_or_default();
+            let resp = client::call(&cfg, json!({"action":"reload_config"})).await?;
+            let data = reply_data(resp, "reload failed")?;
+            print_report(&data, &[("applied", "applied"), ("needs restart", "restart_required"), ("failed", "errors")]);
+        }
+        "targets" => {
+            let cfg = CliConfig::load_or_default();
+            let resp = client::call(&cfg, json!({"action":"list_targets"})).await?;
+            let data = reply_data(resp, "list_targets failed")?;
+            let active = data.get("active").and_then(Value::as_str);
+            let default = data.get("default").and_then(Value::as_str);
+            let targets = data.get("targets").and_then(Value::as_object).cloned().unwrap_or_default();
+            for (name, t) in &targets {
+                let mark = match (Some(name.as_str()) == active, Some(name.as_str()) == default) {
+                    (true, true) => "* (default)",
+                    (true, false) => "*",
+                    (false, true) => "  (default)",
+                    (false, false) => "",
+                };
+                println!("{:<14} {:<12} {}", name, mark, t.get("description").and_then(Value::as_str).unwrap_or_default());
+                for key in ["services", "plugins"] {
+                    let items: Vec<&str> = t.get(key).and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str).collect();
+                    if !items.is_empty() {
+                        println!("    {:<10} {}", key, items.join(", "));
+                    }
+                }
+            }
+        }
+        "analyze" => super::analyze::run(svg.as_deref()).await?,
+        "isolate" => {
+            let target = target.ok_or_else(|| anyhow::anyhow!("target name required"))?;
+            let cfg = CliConfig::load_or_default();
+            let resp = client::call(&cfg, json!({"action":"isolate_target","target":target})).await?;
+            let data = reply_data(resp, "isolate failed")?;
+            print_report(&data, &[("done", "applied"), ("failed", "errors")]);
+        }
         _ => {
             println!("unknown system action: {}", action);
         }
     }
     Ok(())
 }
+
+/// The reply's data, or its message as the error when the call was refused.
+fn reply_data(resp: Value, fallback: &str) -> Result<Value> {
+    if resp.get("ok").and_then(Value::as_bool) != Some(true) {
+        anyhow::bail!("{}", resp.get("message").and_then(Value::as_str).unwrap_or(fallback));
+    }
+    Ok(resp.get("data").cloned().unwrap_or_default())
+}
+
+/// One line per report entry, under the heading of its list.
+fn print_report(data: &Value, sections: &[(&str, &str)]) {
+    for (heading, key) in sections {
+        let items = data.get(*key).and_then(Value::as_array).cloned().unwrap_or_default();
+        for item in items {
+            println!("{:<14} {}", heading, item.as_str().unwrap_or_default());
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::Result;
 use serde_json::json;
 use crate::client;
-use crate::config::CliConfig;
+use crate::config::{self, CliConfig};
 
 pub async fn run(action: &str, username: Option<String>) -> Result<()> {
     let cfg = CliConfig::load_or_default();
@@ -10,17 +10,25 @@ pub async fn run(action: &str, username: Option<String>) -> Result<()> {
             let name = username.ok_or_else(|| anyhow::anyhow!("username required"))?;
             // prompt for password (simple)
             let password = rpassword::prompt_password("Password: ")?;
-            let req = json!({"action":"create_user","username":name,"password":password}).to_string();
-            let resp = client::send_unix_request(&cfg.auth_socket, &req).await?;
+            let resp = client::call(&cfg, json!({"action":"create_user","username":name,"password":password})).await?;
             println!("{}", serde_json::to_string_pretty(&resp)?);
         }
         "login" => {
             let name = username.ok_or_else(|| anyhow::anyhow!("username required"))?;
             let password = rpassword::prompt_password("Password: ")?;
-            let req = json!({"action":"login","username":name,"password":password}).to_string();
-            let resp = client::send_unix_request(&cfg.auth_socket, &req).await?;
+            let resp = client::call(&cfg, json!({"action":"auth","username":name,"password":password})).await?;
+            if let Some(token) = resp.pointer("/data/token").and_then(|t| t.as_str()) {
+                let path = config::save_token(token)?;
+                println!("session saved to {}", path.display());
+            }
             println!("{}", serde_json::to_string_pretty(&resp)?);
         }
+        "logout" => {
+            if let Some(path) = config::session_file().filter(|p| p.exists()) {
+                std::fs::remove_file(&path)?;
+                println!("removed {}", path.display());
+            }
+        }
         _ => {
             println!("unknown user action: {}", action);
         }
@@ -3,17 +3,18 @@ use std::path::PathBuf;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CliConfig {
-    pub plugin_socket: String,
-    pub registry_socket: String,
-    pub auth_socket: String,
+    /// core-daemon RPC socket; it forwards requests to the individual services.
+    pub socket: String,
+    /// Session token from `circlectl user login`, sent with every request.
+    #[serde(skip)]
+    pub token: Option<String>,
 }
 
 impl Default for CliConfig {
     fn default() -> Self {
         Self {
-            plugin_socket: "/tmp/plugin-manager.sock".to_string(),
-            registry_socket: "/tmp/service-registry.sock".to_string(),
-            auth_socket: "/tmp/auth-service.sock".to_string(),
+            socket: "/tmp/circleosd.sock".to_string(),
+            token: None,
         }
     }
 }
@@ -21,6 +22,30 @@ impl Default for CliConfig {
 impl CliConfig {
     pub fn load_or_default() -> Self {
         // attempt to read ~/.circleosd/config.toml (not implemented — default used)
-        Self::default()
+        let token = std::env::var("CIRCLEOSD_TOKEN").ok()
+            .or_else(|| std::fs::read_to_string(session_file()?).ok())
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty());
+        Self { token, ..Self::default() }
     }
 }
+
+/// ~/.circleosd/session, where `user login` keeps the token.
+pub fn session_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".circleosd").join("session"))
+}
+
+/// Store a session token readable only by the current user.
+pub fn save_token(token: &str) -> anyhow::Result<PathBuf> {
+    let path = session_file().ok_or_else(|| anyhow::anyhow!("HOME is not set"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, token)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(path)
+}
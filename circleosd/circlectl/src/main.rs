@@ -16,9 +16,67 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     User { action: String, username: Option<String> },
-    Service { action: String, name: Option<String> },
+    Service {
+        action: String,
+        name: Option<String>,
+        /// With `scale`: how many instances of the template to run
+        replicas: Option<u32>,
+        /// With `logs`: keep printing new output as it arrives
+        #[arg(short, long)]
+        follow: bool,
+        /// With `logs`: how many recent lines to show
+        #[arg(short = 'n', long, default_value_t = 100)]
+        lines: usize,
+        /// With `deps`: show what depends on the service instead
+        #[arg(short, long)]
+        reverse: bool,
+        /// With `apply`: only show what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
     Plugin { action: String, path_or_id: Option<String> },
-    System { action: String },
+    System {
+        action: String,
+        target: Option<String>,
+        /// With `analyze`: also write the boot timeline as SVG to this file
+        #[arg(long)]
+        svg: Option<std::path::PathBuf>,
+    },
+    /// Search the core-daemon log
+    Logs {
+        /// Only records from this component (boot, supervisor, authz, ...)
+        #[arg(short, long)]
+        component: Option<String>,
+        /// Minimum level: debug, info, warn or error
+        #[arg(short, long)]
+        level: Option<String>,
+        /// RFC 3339 timestamp, e.g. 2025-01-31T08:00:00Z
+        #[arg(long)]
+        since: Option<String>,
+        #[arg(long)]
+        until: Option<String>,
+        /// Case-insensitive text to look for
+        #[arg(short, long)]
+        grep: Option<String>,
+        /// Show at most this many of the newest records
+        #[arg(short = 'n', long, default_value_t = 100)]
+        limit: usize,
+        /// Print raw JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+    /// Follow core-daemon events (service, plugin, user, component, ...)
+    Events {
+        /// Topic filters such as service.* or user.login (default: all)
+        #[arg(short, long)]
+        topic: Vec<String>,
+        /// Replay retained events after this sequence number first
+        #[arg(long)]
+        since: Option<u64>,
+        /// Print raw JSON lines
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 #[tokio::main]
@@ -27,9 +85,15 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
     match cli.command {
         Commands::User { action, username } => commands::user::run(&action, username).await?,
-        Commands::Service { action, name } => commands::service::run(&action, name).await?,
+        Commands::Service { action, name, replicas, follow, lines, reverse, dry_run } => {
+            commands::service::run(&action, name, replicas, follow, lines, reverse, dry_run).await?
+        }
         Commands::Plugin { action, path_or_id } => commands::plugin::run(&action, path_or_id).await?,
-        Commands::System { action } => commands::system::run(&action).await?,
+        Commands::System { action, target, svg } => commands::system::run(&action, target, svg).await?,
+        Commands::Logs { component, level, since, until, grep, limit, json } => {
+            commands::logs::run(commands::logs::LogFilter { component, level, since, until, grep, limit, json }).await?
+        }
+        Commands::Events { topic, since, json } => commands::events::run(topic, since, json).await?,
     }
     Ok(())
 }
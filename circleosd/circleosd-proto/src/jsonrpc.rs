@@ -0,0 +1,224 @@
+//! JSON-RPC 2.0 framing for the newline-delimited socket protocol.
+//!
+//! Each line is inspected on its own: arrays and objects carrying a
+//! `"jsonrpc"` member are handled as JSON-RPC 2.0 (ids, batches,
+//! notifications), everything else as the legacy `{"action": ...}` envelope.
+//! A JSON-RPC call `{"method": "m", "params": {...}}` is dispatched exactly
+//! like the legacy `{"action": "m", ...}`.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::future::Future;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// Generic application failure (`ok: false`) from a handler.
+pub const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self { code, message: message.into(), data: None }
+    }
+}
+
+/// A service's legacy `{ok, message, data}` reply, convertible to JSON-RPC.
+pub trait RpcReply: Serialize {
+    /// Legacy reply for a line that could not be decoded into a request.
+    fn invalid(message: String) -> Self;
+
+    /// The JSON-RPC `result` on success, or the `error` object on failure.
+    fn into_jsonrpc(self) -> Result<Value, RpcError>;
+}
+
+/// Default mapping: `data` (or else `message`) becomes the result, and a
+/// failed reply becomes a server error carrying its message and data.
+pub fn split_reply(ok: bool, message: Option<String>, data: Option<Value>, code: i64) -> Result<Value, RpcError> {
+    if ok {
+        Ok(data.or_else(|| message.map(Value::String)).unwrap_or(Value::Null))
+    } else {
+        Err(RpcError { code, message: message.unwrap_or_else(|| "request failed".into()), data })
+    }
+}
+
+/// Whether a parsed line is JSON-RPC 2.0 (a batch, or an object with a
+/// top-level `jsonrpc` member) rather than the legacy envelope.
+pub fn is_jsonrpc(value: &Value) -> bool {
+    match value {
+        Value::Array(_) => true,
+        Value::Object(obj) => obj.contains_key("jsonrpc"),
+        _ => false,
+    }
+}
+
+/// Handle one input line and return the line to write back, if any
+/// (notifications and all-notification batches produce no output).
+pub async fn handle_line<T, R, F, Fut>(line: &str, methods: &[&str], handle: F) -> Option<String>
+where
+    T: DeserializeOwned,
+    R: RpcReply,
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = R>,
+{
+    let value: Value = match serde_json::from_str(line) {
+        Ok(v) => v,
+        // Not JSON at all: answer in whichever dialect the sender seems to speak.
+        Err(e) if line.contains("\"jsonrpc\"") => {
+            return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, format!("parse error: {}", e))).to_string());
+        }
+        Err(e) => return Some(encode(&R::invalid(format!("invalid request: {}", e)))),
+    };
+
+    match value {
+        Value::Array(items) => {
+            if items.is_empty() {
+                return Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "empty batch")).to_string());
+            }
+            let mut out = Vec::new();
+            for item in items {
+                if let Some(resp) = handle_call(item, methods, &handle).await {
+                    out.push(resp);
+                }
+            }
+            if out.is_empty() { None } else { Some(Value::Array(out).to_string()) }
+        }
+        Value::Object(_) if is_jsonrpc(&value) => {
+            handle_call(value, methods, &handle).await.map(|v| v.to_string())
+        }
+        legacy => {
+            let reply = match serde_json::from_value::<T>(legacy) {
+                Ok(req) => handle(req).await,
+                Err(e) => R::invalid(format!("invalid request: {}", e)),
+            };
+            Some(encode(&reply))
+        }
+    }
+}
+
+/// Handle a single JSON-RPC request object; `None` for notifications.
+async fn handle_call<T, R, F, Fut>(call: Value, methods: &[&str], handle: &F) -> Option<Value>
+where
+    T: DeserializeOwned,
+    R: RpcReply,
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = R>,
+{
+    let Value::Object(mut obj) = call else {
+        return Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "request must be an object")));
+    };
+
+    let id = obj.remove("id");
+    if let Some(id) = &id {
+        if !(id.is_string() || id.is_number() || id.is_null()) {
+            return Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "id must be a string, number or null")));
+        }
+    }
+    // A malformed request is answered even without an id (with id null); only
+    // a well-formed notification goes unanswered, per the spec.
+    let invalid = |message: &str| Some(error_response(id.clone().unwrap_or(Value::Null), RpcError::new(INVALID_REQUEST, message)));
+    let reply = |err: RpcError| id.clone().map(|id| error_response(id, err));
+
+    if obj.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return invalid("jsonrpc must be \"2.0\"");
+    }
+    let method = match obj.remove("method") {
+        Some(Value::String(m)) => m,
+        _ => return invalid("method must be a string"),
+    };
+    if !methods.contains(&method.as_str()) {
+        return reply(RpcError::new(METHOD_NOT_FOUND, format!("method not found: {}", method)));
+    }
+    let mut params = match obj.remove("params") {
+        None => Map::new(),
+        Some(Value::Object(p)) => p,
+        Some(_) => return reply(RpcError::new(INVALID_PARAMS, "params must be an object (by-name)")),
+    };
+
+    params.insert("action".into(), Value::String(method));
+    let req = match serde_json::from_value::<T>(Value::Object(params)) {
+        Ok(req) => req,
+        Err(e) => return reply(RpcError::new(INVALID_PARAMS, format!("invalid params: {}", e))),
+    };
+
+    let result = handle(req).await.into_jsonrpc();
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(err) => error_response(id, err),
+    })
+}
+
+fn error_response(id: Value, err: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": err })
+}
+
+fn encode<R: Serialize>(reply: &R) -> String {
+    serde_json::to_string(reply).unwrap_or_else(|e| json!({ "ok": false, "message": e.to_string() }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct Ping {}
+
+    #[derive(Serialize)]
+    struct Reply {
+        ok: bool,
+        message: Option<String>,
+    }
+
+    impl RpcReply for Reply {
+        fn invalid(message: String) -> Self {
+            Reply { ok: false, message: Some(message) }
+        }
+
+        fn into_jsonrpc(self) -> Result<Value, RpcError> {
+            split_reply(self.ok, self.message, None, SERVER_ERROR)
+        }
+    }
+
+    async fn run(line: &str) -> Option<Value> {
+        let out = handle_line(line, &["ping"], |_: Ping| async { Reply { ok: true, message: Some("pong".into()) } }).await;
+        out.map(|s| serde_json::from_str(&s).unwrap())
+    }
+
+    #[test]
+    fn dialect() {
+        assert!(is_jsonrpc(&json!({ "jsonrpc": "2.0", "method": "ping" })));
+        assert!(is_jsonrpc(&json!([{ "jsonrpc": "2.0", "method": "ping" }])));
+        // only a top-level member counts, not the word inside a value
+        assert!(!is_jsonrpc(&json!({ "action": "subscribe", "topic": "\"jsonrpc\"" })));
+        assert!(!is_jsonrpc(&json!({ "action": "ping", "params": { "jsonrpc": "2.0" } })));
+    }
+
+    #[tokio::test]
+    async fn notifications_are_not_answered() {
+        assert_eq!(run(r#"{"jsonrpc":"2.0","method":"ping"}"#).await, None);
+        assert_eq!(run(r#"[{"jsonrpc":"2.0","method":"ping"},{"jsonrpc":"2.0","method":"nope"}]"#).await, None);
+    }
+
+    #[tokio::test]
+    async fn invalid_batch_elements_get_an_error_with_id_null() {
+        let out = run(r#"[1,{"jsonrpc":"1.0","method":"ping"},{"jsonrpc":"2.0"},{"jsonrpc":"2.0","method":"ping","id":7}]"#).await.unwrap();
+        let items = out.as_array().unwrap();
+        assert_eq!(items.len(), 4);
+        for item in &items[..3] {
+            assert_eq!(item["id"], Value::Null);
+            assert_eq!(item["error"]["code"], INVALID_REQUEST);
+        }
+        assert_eq!(items[3], json!({ "jsonrpc": "2.0", "id": 7, "result": "pong" }));
+    }
+}
@@ -0,0 +1,6 @@
+//! What every CircleOSD daemon speaks on its socket: the JSON-RPC 2.0
+//! framing around the newline-delimited `{"action": ...}` protocol, and the
+//! SO_PEERCRED check of who is connecting.
+
+pub mod jsonrpc;
+pub mod peercred;
@@ -0,0 +1,153 @@
+//! Caller identification for the CircleOSD sockets.
+//!
+//! Every accepted connection is identified with SO_PEERCRED and checked
+//! against an access list: root is always let in, plus the listed users
+//! and groups. core-daemon builds its list from its `[access]` table or
+//! these variables, and also admits the user it runs as:
+//!
+//! - `CIRCLEOSD_ALLOWED_UIDS`: comma-separated user names or uids
+//! - `CIRCLEOSD_ALLOWED_GROUPS`: comma-separated group names or gids
+//! - `CIRCLEOSD_SOCKET_GROUP`: group that owns the socket file (mode 0660)
+//!
+//! The components behind it use [`AccessList::for_component`]: root and
+//! the uid core-daemon passes in `CIRCLEOSD_CORE_UID` when it spawns them.
+//! Their own uid is not enough, since the services they start may share it.
+
+use serde::Serialize;
+use std::fmt;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use tokio::net::UnixStream;
+
+/// Credentials of the process on the other end of a connection.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Peer {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+impl Peer {
+    pub fn of(stream: &UnixStream) -> io::Result<Self> {
+        let cred = stream.peer_cred()?;
+        Ok(Self { uid: cred.uid(), gid: cred.gid(), pid: cred.pid() })
+    }
+
+    /// Primary group plus the supplementary groups listed in /proc, when
+    /// the peer's pid is known and still running.
+    fn groups(&self) -> Vec<u32> {
+        let mut groups = vec![self.gid];
+        if let Some(pid) = self.pid {
+            if let Ok(status) = std::fs::read_to_string(format!("/proc/{}/status", pid)) {
+                if let Some(line) = status.lines().find_map(|l| l.strip_prefix("Groups:")) {
+                    groups.extend(line.split_whitespace().filter_map(|g| g.parse::<u32>().ok()));
+                }
+            }
+        }
+        groups
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "uid={} gid={}", self.uid, self.gid)?;
+        match self.pid {
+            Some(pid) => write!(f, " pid={}", pid),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Who may connect to a socket.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AccessList {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+    /// Group given read/write access to the socket file.
+    pub socket_group: Option<u32>,
+}
+
+impl AccessList {
+    /// Override `self` with whichever of the `CIRCLEOSD_*` variables
+    /// described above are set. Errors name the offending variable.
+    pub fn with_env(mut self) -> Result<Self, String> {
+        let list = |key: &str, v: String, resolve: fn(&str) -> Option<u32>| -> Result<Vec<u32>, String> {
+            v.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| resolve(s).ok_or_else(|| format!("{}: unknown user or group `{}`", key, s)))
+                .collect()
+        };
+        if let Ok(v) = std::env::var("CIRCLEOSD_ALLOWED_UIDS") {
+            self.uids = list("CIRCLEOSD_ALLOWED_UIDS", v, resolve_user)?;
+        }
+        if let Ok(v) = std::env::var("CIRCLEOSD_ALLOWED_GROUPS") {
+            self.gids = list("CIRCLEOSD_ALLOWED_GROUPS", v, resolve_group)?;
+        }
+        if let Ok(g) = std::env::var("CIRCLEOSD_SOCKET_GROUP") {
+            self.socket_group = match g.trim() {
+                "" => None,
+                g => Some(resolve_group(g).ok_or_else(|| format!("CIRCLEOSD_SOCKET_GROUP: unknown group `{}`", g))?),
+            };
+        }
+        Ok(self)
+    }
+
+    /// Root and core-daemon's uid from `CIRCLEOSD_CORE_UID`; just root
+    /// when the component was not started by core-daemon.
+    pub fn for_component() -> Result<Self, String> {
+        let uids = match std::env::var("CIRCLEOSD_CORE_UID") {
+            Ok(v) => vec![v.trim().parse().map_err(|_| format!("CIRCLEOSD_CORE_UID: `{}` is not a uid", v))?],
+            Err(_) => Vec::new(),
+        };
+        Ok(Self { uids, ..Self::default() })
+    }
+
+    pub fn allows(&self, peer: &Peer) -> bool {
+        if peer.uid == 0 || self.uids.contains(&peer.uid) {
+            return true;
+        }
+        !self.gids.is_empty() && peer.groups().iter().any(|g| self.gids.contains(g))
+    }
+
+    /// Restrict the socket file to its owner (0600), or to its owner and
+    /// `socket_group` (0660).
+    pub fn apply_to_socket(&self, path: &Path) -> io::Result<()> {
+        let mode = match self.socket_group {
+            Some(gid) => {
+                std::os::unix::fs::chown(path, None, Some(gid))?;
+                0o660
+            }
+            None => 0o600,
+        };
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+    }
+}
+
+/// Effective uid of this process.
+pub fn own_uid() -> Option<u32> {
+    std::fs::metadata("/proc/self").ok().map(|m| m.uid())
+}
+
+/// A user name from /etc/passwd, or a numeric uid.
+pub fn resolve_user(name: &str) -> Option<u32> {
+    name.parse().ok().or_else(|| lookup("/etc/passwd", name))
+}
+
+/// A group name from /etc/group, or a numeric gid.
+pub fn resolve_group(name: &str) -> Option<u32> {
+    name.parse().ok().or_else(|| lookup("/etc/group", name))
+}
+
+/// Third field of the `name:x:id:...` line for `name`.
+fn lookup(db: &str, name: &str) -> Option<u32> {
+    let text = std::fs::read_to_string(db).ok()?;
+    text.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next()? != name {
+            return None;
+        }
+        fields.nth(1)?.parse().ok()
+    })
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+/// Every permission an RPC action can require.
+pub const PERMISSIONS: &[&str] = &[
+    "user.create",
+    "service.register",
+    "service.unregister",
+    "service.list",
+    "service.status",
+    "service.start",
+    "service.stop",
+    "service.logs",
+    "service.reset",
+    "plugin.load",
+    "plugin.unload",
+    "plugin.list",
+    "log.read",
+    "event.subscribe",
+    "event.publish",
+    "config.reload",
+    "target.list",
+    "target.isolate",
+    "boot.read",
+];
+
+/// Maps users to roles and roles to permission patterns.
+///
+/// A pattern is an exact permission (`service.start`), a group
+/// (`service.*`) or `*` for everything.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Policy {
+    pub roles: HashMap<String, Vec<String>>,
+    pub users: HashMap<String, Vec<String>>,
+    /// Role applied to authenticated users not listed in `users`.
+    pub default_role: Option<String>,
+    /// Permissions granted to callers without a session token.
+    pub anonymous: Vec<String>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        let roles = HashMap::from([
+            ("admin".to_string(), vec!["*".to_string()]),
+            (
+                "user".to_string(),
+                vec!["service.list".to_string(), "service.status".to_string(), "plugin.list".to_string()],
+            ),
+        ]);
+        Self { roles, users: HashMap::new(), default_role: Some("user".to_string()), anonymous: Vec::new() }
+    }
+}
+
+impl Policy {
+    pub fn allows_anonymous(&self, perm: &str) -> bool {
+        self.anonymous.iter().any(|p| matches(p, perm))
+    }
+
+    pub fn allows(&self, user: &str, perm: &str) -> bool {
+        self.roles_of(user)
+            .filter_map(|role| self.roles.get(role))
+            .flatten()
+            .any(|p| matches(p, perm))
+    }
+
+    fn roles_of<'a>(&'a self, user: &str) -> Box<dyn Iterator<Item = &'a String> + 'a> {
+        match self.users.get(user) {
+            Some(roles) => Box::new(roles.iter()),
+            None => Box::new(self.default_role.iter()),
+        }
+    }
+}
+
+/// Whether `pattern` names at least one known permission.
+pub fn is_valid_pattern(pattern: &str) -> bool {
+    PERMISSIONS.iter().any(|perm| matches(pattern, perm))
+}
+
+fn matches(pattern: &str, perm: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some("") => true,
+        Some(prefix) if prefix.ends_with('.') => perm.starts_with(prefix),
+        _ => pattern == perm,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(users: &[(&str, &[&str])]) -> Policy {
+        let mut policy = Policy::default();
+        policy.roles.insert("operator".to_string(), vec!["service.*".to_string(), "plugin.list".to_string()]);
+        for (user, roles) in users {
+            policy.users.insert(user.to_string(), roles.iter().map(|r| r.to_string()).collect());
+        }
+        policy
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(matches("*", "plugin.load"));
+        assert!(matches("service.*", "service.start"));
+        assert!(!matches("service.*", "plugin.list"));
+        // a group only matches whole segments
+        assert!(!matches("serv*", "service.start"));
+        assert!(!matches("service*", "service.start"));
+        assert!(matches("service.start", "service.start"));
+        assert!(!matches("service.start", "service.stop"));
+
+        assert!(is_valid_pattern("*"));
+        assert!(is_valid_pattern("plugin.*"));
+        assert!(is_valid_pattern("user.create"));
+        assert!(!is_valid_pattern("user.delete"));
+        assert!(!is_valid_pattern("users.*"));
+    }
+
+    #[test]
+    fn listed_users_get_only_their_roles() {
+        let policy = policy(&[("alice", &["admin"]), ("bob", &["operator"]), ("carol", &[])]);
+        assert!(policy.allows("alice", "user.create"));
+        assert!(policy.allows("bob", "service.stop"));
+        assert!(policy.allows("bob", "plugin.list"));
+        assert!(!policy.allows("bob", "plugin.load"));
+        // listing a user replaces the default role rather than adding to it
+        assert!(!policy.allows("carol", "service.list"));
+    }
+
+    #[test]
+    fn default_role() {
+        let mut policy = policy(&[]);
+        assert!(policy.allows("dave", "service.list"));
+        assert!(!policy.allows("dave", "service.start"));
+
+        policy.default_role = None;
+        assert!(!policy.allows("dave", "service.list"));
+
+        // a default role that does not exist grants nothing
+        policy.default_role = Some("missing".to_string());
+        assert!(!policy.allows("dave", "service.list"));
+    }
+
+    #[test]
+    fn anonymous() {
+        let mut policy = Policy::default();
+        assert!(PERMISSIONS.iter().all(|perm| !policy.allows_anonymous(perm)));
+
+        policy.anonymous = vec!["service.list".to_string(), "plugin.*".to_string()];
+        assert!(policy.allows_anonymous("service.list"));
+        assert!(policy.allows_anonymous("plugin.unload"));
+        assert!(!policy.allows_anonymous("service.start"));
+    }
+}
@@ -0,0 +1,407 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::process::{Child, Command};
+use tokio::sync::{oneshot, watch, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::events::EventBus;
+use crate::logging::{self, Level};
+use circleosd_proto::peercred;
+use crate::timing::Timeline;
+
+/// A CircleOSD component core-daemon knows how to boot.
+pub struct Component {
+    pub name: &'static str,
+    /// Components that must be ready before this one is started.
+    pub requires: &'static [&'static str],
+}
+
+/// Boot table, listed in dependency order.
+pub const COMPONENTS: &[Component] = &[
+    Component { name: "service-registry", requires: &[] },
+    Component { name: "auth-service", requires: &["service-registry"] },
+    Component { name: "plugin-manager", requires: &["service-registry"] },
+];
+
+impl Component {
+    /// RPC socket the component binds once it is up: `<name>.sock` in the
+    /// run directory, which is handed to it as CIRCLEOSD_RUN_DIR.
+    pub fn socket(&self, run_dir: &Path) -> PathBuf {
+        run_dir.join(format!("{}.sock", self.name))
+    }
+}
+
+pub fn component(name: &str) -> Option<&'static Component> {
+    COMPONENTS.iter().find(|c| c.name == name)
+}
+
+/// How long a single ping may take before the attempt counts as failed.
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+const READY_POLL: Duration = Duration::from_millis(100);
+/// Wait before restarting a component that exited; doubles per restart up
+/// to RESTART_BACKOFF_MAX, and starts over after a run of RESTART_STABLE.
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(30);
+const RESTART_STABLE: Duration = Duration::from_secs(30);
+/// How long a component has to exit after SIGTERM before it is killed.
+/// service-registry spends it stopping its services.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Result of booting one component.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Outcome {
+    /// Spawned by us and answered a ping.
+    Ready { pid: Option<u32>, millis: u128 },
+    /// Something was already answering on the socket; left untouched.
+    AlreadyRunning,
+    Failed { reason: String },
+    Skipped { reason: String },
+}
+
+impl Outcome {
+    pub fn is_up(&self) -> bool {
+        matches!(self, Outcome::Ready { .. } | Outcome::AlreadyRunning)
+    }
+
+    /// The `state` tag it is serialized with.
+    pub fn state(&self) -> &'static str {
+        match self {
+            Outcome::Ready { .. } => "ready",
+            Outcome::AlreadyRunning => "already_running",
+            Outcome::Failed { .. } => "failed",
+            Outcome::Skipped { .. } => "skipped",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BootReport {
+    pub components: Vec<(String, Outcome)>,
+}
+
+impl BootReport {
+    pub fn outcome(&self, name: &str) -> Option<&Outcome> {
+        self.components.iter().find(|(n, _)| n == name).map(|(_, o)| o)
+    }
+
+    /// Names of components that did not come up.
+    pub fn failed(&self) -> Vec<&str> {
+        self.components.iter().filter(|(_, o)| !o.is_up()).map(|(n, _)| n.as_str()).collect()
+    }
+}
+
+/// A child process spawned during boot and the task watching it.
+struct Supervised {
+    name: &'static str,
+    stop: Option<oneshot::Sender<()>>,
+    watcher: JoinHandle<()>,
+}
+
+/// Spawns the CircleOSD components as children of core-daemon and keeps
+/// track of them until they exit or are stopped.
+pub struct Supervisor {
+    bin_dir: PathBuf,
+    run_dir: PathBuf,
+    ready_timeout: Duration,
+    /// core-daemon's own socket, passed to children for publishing events.
+    core_socket: String,
+    /// Where service-registry keeps the output of the services it runs.
+    service_log_dir: PathBuf,
+    /// Where service-registry remembers when each timer last fired.
+    timer_state: PathBuf,
+    events: Arc<EventBus>,
+    /// Start and ready times of each component, for boot_timing.
+    timeline: Arc<Timeline>,
+    children: Mutex<Vec<Supervised>>,
+}
+
+impl Supervisor {
+    pub fn new(cfg: &Config, events: Arc<EventBus>, timeline: Arc<Timeline>) -> Self {
+        Self {
+            bin_dir: cfg.bin_dir.clone(),
+            run_dir: cfg.run_dir(),
+            ready_timeout: cfg.boot_timeout,
+            core_socket: cfg.socket_path.clone(),
+            service_log_dir: cfg.log_dir.join("services"),
+            timer_state: cfg.var_dir.join("timers.json"),
+            events,
+            timeline,
+            children: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Start the requested components in dependency order, waiting for each
+    /// one to answer a ping before moving on to the next.
+    pub async fn boot(&self, services: &[String]) -> BootReport {
+        let mut report = BootReport::default();
+
+        for comp in COMPONENTS.iter().filter(|c| services.iter().any(|s| s == c.name)) {
+            let blocked = comp.requires.iter().find(|dep| {
+                report.outcome(dep).map(|o| !o.is_up()).unwrap_or(false)
+            });
+
+            let span = self.timeline.begin(comp.name.to_string(), "component", comp.requires);
+            let outcome = match blocked {
+                Some(dep) => Outcome::Skipped { reason: format!("dependency {} is not ready", dep) },
+                None => {
+                    logging::log(Level::Info, "boot", format!("starting {}", comp.name), json!({ "service": comp.name }));
+                    self.start(comp).await
+                }
+            };
+
+            let (level, topic, msg) = match &outcome {
+                Outcome::Ready { pid, millis } => {
+                    (Level::Info, "component.ready", format!("{} ready in {}ms (pid {})", comp.name, millis, fmt_pid(*pid)))
+                }
+                Outcome::AlreadyRunning => {
+                    (Level::Info, "component.ready", format!("{} already running on {}", comp.name, comp.socket(&self.run_dir).display()))
+                }
+                Outcome::Failed { reason } => (Level::Error, "component.failed", format!("{} failed: {}", comp.name, reason)),
+                Outcome::Skipped { reason } => {
+                    (Level::Warn, "component.skipped", format!("{} skipped: {}", comp.name, reason))
+                }
+            };
+            self.timeline.end(span, outcome.state());
+            let fields = json!({ "service": comp.name, "outcome": outcome });
+            logging::log(level, "boot", msg, fields.clone());
+            self.events.publish(topic, fields);
+            report.components.push((comp.name.to_string(), outcome));
+        }
+
+        report
+    }
+
+    async fn start(&self, comp: &'static Component) -> Outcome {
+        let socket = comp.socket(&self.run_dir);
+        if ping(&socket).await.is_ok() {
+            return Outcome::AlreadyRunning;
+        }
+
+        let bin = self.bin_dir.join(comp.name);
+        if !bin.is_file() {
+            return Outcome::Failed { reason: format!("binary not found at {}", bin.display()) };
+        }
+
+        let started = Instant::now();
+        let mut env: Vec<(&'static str, OsString)> = vec![
+            ("CIRCLEOSD_SOCKET", self.core_socket.clone().into()),
+            ("CIRCLEOSD_PUBLISH_TOKEN", self.events.publisher_token().into()),
+            ("CIRCLEOSD_RUN_DIR", self.run_dir.clone().into()),
+            ("CIRCLEOSD_SERVICE_LOG_DIR", self.service_log_dir.clone().into()),
+            ("CIRCLEOSD_TIMER_STATE", self.timer_state.clone().into()),
+        ];
+        // the components answer root and this uid only; other callers go
+        // through core-daemon's access list
+        if let Some(uid) = peercred::own_uid() {
+            env.push(("CIRCLEOSD_CORE_UID", uid.to_string().into()));
+        }
+        let launch = Launch { bin, env };
+        let child = match launch.spawn() {
+            Ok(c) => c,
+            Err(e) => return Outcome::Failed { reason: format!("failed to spawn {}: {}", launch.bin.display(), e) },
+        };
+        let pid = child.id();
+        info!("spawned {} (pid {})", comp.name, fmt_pid(pid));
+
+        // The watcher owns the child: it reports exits, restarts the process
+        // once it has been ready, and kills it when asked to stop.
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let (exit_tx, exit_rx) = watch::channel::<Option<ExitStatus>>(None);
+        let restart = Arc::new(AtomicBool::new(false));
+        let name = comp.name;
+        let watcher = tokio::spawn(watch_component(name, child, launch, restart.clone(), stop_rx, exit_tx, self.events.clone()));
+
+        self.children.lock().await.push(Supervised { name, stop: Some(stop_tx), watcher });
+
+        let deadline = started + self.ready_timeout;
+        loop {
+            let exited = *exit_rx.borrow();
+            if let Some(status) = exited {
+                self.stop(name).await;
+                return Outcome::Failed {
+                    reason: format!("exited with {} before becoming ready", status),
+                };
+            }
+            if ping(&socket).await.is_ok() {
+                restart.store(true, Ordering::SeqCst);
+                return Outcome::Ready { pid, millis: started.elapsed().as_millis() };
+            }
+            if Instant::now() >= deadline {
+                self.stop(name).await;
+                return Outcome::Failed {
+                    reason: format!("no ping reply on {} within {:?}", socket.display(), self.ready_timeout),
+                };
+            }
+            tokio::time::sleep(READY_POLL).await;
+        }
+    }
+
+    /// Stop one supervised component and wait for it to exit.
+    pub async fn stop(&self, name: &str) {
+        let entry = {
+            let mut children = self.children.lock().await;
+            let idx = children.iter().position(|c| c.name == name);
+            idx.map(|i| children.remove(i))
+        };
+        if let Some(mut sup) = entry {
+            if let Some(tx) = sup.stop.take() {
+                let _ = tx.send(());
+            }
+            if let Err(e) = sup.watcher.await {
+                warn!("watcher for {} failed: {}", sup.name, e);
+            }
+        }
+    }
+
+    /// Stop every supervised component, dependents first.
+    pub async fn stop_all(&self) {
+        let names: Vec<&'static str> = self.children.lock().await.iter().rev().map(|c| c.name).collect();
+        for name in names {
+            self.stop(name).await;
+        }
+    }
+}
+
+/// How to spawn a component, kept so the watcher can spawn it again.
+struct Launch {
+    bin: PathBuf,
+    env: Vec<(&'static str, OsString)>,
+}
+
+impl Launch {
+    fn spawn(&self) -> std::io::Result<Child> {
+        Command::new(&self.bin)
+            .envs(self.env.iter().map(|(k, v)| (k, v)))
+            .kill_on_drop(true)
+            .spawn()
+    }
+}
+
+/// Own a component's process until it is stopped: report each exit, and
+/// once `restart` is set (the component has been ready) spawn it again
+/// after a backoff, publishing component.restarted.
+async fn watch_component(
+    name: &'static str,
+    mut child: Child,
+    launch: Launch,
+    restart: Arc<AtomicBool>,
+    mut stop_rx: oneshot::Receiver<()>,
+    exit_tx: watch::Sender<Option<ExitStatus>>,
+    events: Arc<EventBus>,
+) {
+    let mut backoff = RESTART_BACKOFF;
+    let mut restarts = 0u32;
+    let mut started = Instant::now();
+    loop {
+        let status = tokio::select! {
+            status = child.wait() => status.ok(),
+            _ = &mut stop_rx => {
+                let status = terminate(name, &mut child).await;
+                let _ = exit_tx.send(status);
+                stopped(name, status, &events);
+                return;
+            }
+        };
+        let _ = exit_tx.send(status);
+        let code = status.and_then(|s| s.code());
+        logging::log(
+            Level::Warn,
+            "supervisor",
+            format!("{} exited ({})", name, fmt_status(status)),
+            json!({ "service": name, "code": code }),
+        );
+        events.publish("component.exited", json!({ "service": name, "code": code }));
+        if !restart.load(Ordering::SeqCst) {
+            return;
+        }
+
+        if started.elapsed() >= RESTART_STABLE {
+            backoff = RESTART_BACKOFF;
+        }
+        child = loop {
+            info!("restarting {} in {:?}", name, backoff);
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = &mut stop_rx => {
+                    stopped(name, status, &events);
+                    return;
+                }
+            }
+            backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+            match launch.spawn() {
+                Ok(child) => break child,
+                Err(e) => logging::error("supervisor", format!("failed to restart {}: {}", name, e)),
+            }
+        };
+        restarts += 1;
+        started = Instant::now();
+        let pid = child.id();
+        logging::log(
+            Level::Warn,
+            "supervisor",
+            format!("{} restarted (pid {}, restart {})", name, fmt_pid(pid), restarts),
+            json!({ "service": name, "pid": pid, "restarts": restarts }),
+        );
+        events.publish("component.restarted", json!({ "service": name, "pid": pid, "restarts": restarts, "code": code }));
+    }
+}
+
+/// SIGTERM `child`, then SIGKILL it if it outlives STOP_TIMEOUT.
+async fn terminate(name: &str, child: &mut Child) -> Option<ExitStatus> {
+    if let Some(pid) = child.id() {
+        // SAFETY: kill(2) has no memory-safety preconditions.
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+    }
+    match tokio::time::timeout(STOP_TIMEOUT, child.wait()).await {
+        Ok(status) => status.ok(),
+        Err(_) => {
+            warn!("{} did not exit within {:?} of SIGTERM; killing it", name, STOP_TIMEOUT);
+            let _ = child.start_kill();
+            child.wait().await.ok()
+        }
+    }
+}
+
+fn stopped(name: &str, status: Option<ExitStatus>, events: &EventBus) {
+    logging::log(Level::Info, "supervisor", format!("{} stopped ({})", name, fmt_status(status)), json!({ "service": name }));
+    events.publish("component.stopped", json!({ "service": name }));
+}
+
+/// Send `{"action":"ping"}` to a component socket and wait for an `ok` reply.
+pub async fn ping(socket: &Path) -> Result<()> {
+    tokio::time::timeout(PING_TIMEOUT, async {
+        let stream = UnixStream::connect(socket).await?;
+        let (r, mut w) = stream.into_split();
+        w.write_all(b"{\"action\":\"ping\"}\n").await?;
+        let mut lines = BufReader::new(r).lines();
+        let line = lines.next_line().await?.ok_or_else(|| anyhow::anyhow!("connection closed"))?;
+        let v: serde_json::Value = serde_json::from_str(&line)?;
+        if v.get("ok").and_then(|ok| ok.as_bool()) == Some(true) {
+            Ok(())
+        } else {
+            anyhow::bail!("unexpected ping reply: {}", line)
+        }
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("ping timed out"))?
+}
+
+fn fmt_pid(pid: Option<u32>) -> String {
+    pid.map(|p| p.to_string()).unwrap_or_else(|| "?".into())
+}
+
+fn fmt_status(status: Option<ExitStatus>) -> String {
+    status.map(|s| s.to_string()).unwrap_or_else(|| "unknown status".into())
+}
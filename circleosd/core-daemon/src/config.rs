@@ -1,24 +1,746 @@
-use std::path::PathBuf;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
 
-/// Simple in-memory config. Extend to read from /etc/circleosd/circleosd.conf
+use crate::authz::{self, Policy};
+use crate::logging::Rotation;
+use circleosd_proto::peercred::{self, AccessList};
+
+/// Config file read when neither `--config` nor `CIRCLEOSD_CONFIG` is given.
+pub const DEFAULT_CONFIG_PATH: &str = "etc/circleosd.conf";
+
+/// Components core-daemon knows how to boot, in dependency order.
+pub const KNOWN_SERVICES: &[&str] = &["service-registry", "auth-service", "plugin-manager"];
+
+const LOG_LEVELS: &[&str] = &["trace", "debug", "info", "warn", "error"];
+
+/// What to do when a boot component never becomes ready.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnBootFailure {
+    /// Stop everything that was started and exit with an error.
+    Abort,
+    /// Keep running with whatever came up.
+    Degraded,
+}
+
+impl std::str::FromStr for OnBootFailure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "abort" => Ok(OnBootFailure::Abort),
+            "degraded" => Ok(OnBootFailure::Degraded),
+            other => Err(format!("`{}` is not one of abort, degraded", other)),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("config file {path} not found")]
+    NotFound { path: PathBuf },
+
+    #[error("failed to read {path}")]
+    Io { path: PathBuf, source: std::io::Error },
+
+    #[error("failed to parse {path}")]
+    Parse { path: PathBuf, source: toml::de::Error },
+
+    #[error("invalid value for `{key}`: {reason}")]
+    Invalid { key: String, reason: String },
+}
+
+impl ConfigError {
+    fn invalid(key: &str, reason: impl Into<String>) -> Self {
+        ConfigError::Invalid { key: key.to_string(), reason: reason.into() }
+    }
+}
+
+/// Command-line flags. Each one overrides the file and environment layers.
+#[derive(Debug, Default, Parser)]
+#[command(name = "core-daemon")]
+#[command(about = "CircleOSD core daemon")]
+pub struct CliArgs {
+    /// Path to circleosd.conf (default: etc/circleosd.conf)
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// RPC socket path
+    #[arg(long)]
+    pub socket: Option<String>,
+
+    /// trace, debug, info, warn or error
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Directory for circleosd.log
+    #[arg(long)]
+    pub log_dir: Option<PathBuf>,
+
+    /// Runtime data directory (sockets, sessions)
+    #[arg(long)]
+    pub var_dir: Option<PathBuf>,
+
+    /// Directory containing the service binaries
+    #[arg(long)]
+    pub bin_dir: Option<PathBuf>,
+
+    /// Comma-separated list of services to boot
+    #[arg(long, value_delimiter = ',')]
+    pub boot_services: Option<Vec<String>>,
+
+    /// Seconds to wait for each service to answer a ping
+    #[arg(long)]
+    pub boot_timeout: Option<u64>,
+
+    /// abort or degraded
+    #[arg(long)]
+    pub on_boot_failure: Option<String>,
+
+    /// Seconds to let in-flight requests finish on shutdown
+    #[arg(long)]
+    pub drain_timeout: Option<u64>,
+
+    /// Target to boot into instead of boot.target (e.g. rescue)
+    #[arg(long)]
+    pub target: Option<String>,
+}
+
+/// On-disk layout of circleosd.conf. Other components may add their own
+/// tables (`[auth]`, `[plugins]`), so only the sections we own are strict.
+#[derive(Debug, Default, Deserialize)]
+struct FileConfig {
+    #[serde(default)]
+    core: CoreSection,
+    #[serde(default)]
+    boot: BootSection,
+    #[serde(default)]
+    gateway: GatewaySection,
+    #[serde(default)]
+    authz: AuthzSection,
+    #[serde(default)]
+    log: LogSection,
+    #[serde(default)]
+    access: AccessSection,
+    /// Service definitions registered with service-registry, keyed by name.
+    #[serde(default)]
+    services: BTreeMap<String, toml::Table>,
+    #[serde(default)]
+    targets: BTreeMap<String, TargetSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CoreSection {
+    socket: Option<String>,
+    log_level: Option<String>,
+    log_dir: Option<PathBuf>,
+    var_dir: Option<PathBuf>,
+    bin_dir: Option<PathBuf>,
+    drain_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BootSection {
+    services: Option<Vec<String>>,
+    /// Plugins loaded through plugin-manager once it is up.
+    plugins: Option<Vec<String>>,
+    timeout_secs: Option<u64>,
+    on_failure: Option<String>,
+    /// Target isolated once boot finishes.
+    target: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GatewaySection {
+    /// Per-backend request timeout, keyed by service name.
+    #[serde(default)]
+    timeout_ms: HashMap<String, u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogSection {
+    max_size_mb: Option<u64>,
+    rotate_daily: Option<bool>,
+    keep: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuthzSection {
+    /// Replaces the built-in `admin` / `user` roles when present.
+    roles: Option<HashMap<String, Vec<String>>>,
+    #[serde(default)]
+    users: HashMap<String, Vec<String>>,
+    /// Empty string disables the default role.
+    default_role: Option<String>,
+    anonymous: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccessSection {
+    /// User names or uids allowed to connect besides root and our own uid.
+    #[serde(default)]
+    allowed_users: Vec<String>,
+    /// Members of these groups may connect too.
+    #[serde(default)]
+    allowed_groups: Vec<String>,
+    /// Group owning core-daemon's socket, which is then made mode 0660.
+    socket_group: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TargetSection {
+    description: Option<String>,
+    #[serde(default)]
+    services: Vec<String>,
+    #[serde(default)]
+    plugins: Vec<String>,
+}
+
+/// A named set of services and plugins the system can be switched to as a whole.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Target {
+    pub description: Option<String>,
+    /// Registered services started by the target; all others are stopped.
+    pub services: Vec<String>,
+    /// Plugin paths loaded on top of `boot.plugins`.
+    pub plugins: Vec<String>,
+}
+
+/// Effective daemon configuration: defaults, then circleosd.conf, then
+/// `CIRCLEOSD_*` environment variables, then command-line flags.
 #[derive(Clone, Debug)]
 pub struct Config {
     pub socket_path: String,
     pub log_level: String,
+    pub log_dir: PathBuf,
+    pub log_rotation: Rotation,
+    pub var_dir: PathBuf,
+    pub bin_dir: PathBuf,
+    pub boot_services: Vec<String>,
+    pub boot_timeout: Duration,
+    pub on_boot_failure: OnBootFailure,
+    /// How long shutdown waits for open requests before dropping them.
+    pub drain_timeout: Duration,
+    pub backend_timeouts: HashMap<String, Duration>,
+    pub authz: Policy,
+    /// Which local users may connect to core-daemon.
+    pub access: AccessList,
+    /// Plugin paths loaded after boot.
+    pub preload_plugins: Vec<String>,
+    /// `[services.<name>]` specs (without the name) to register at boot.
+    pub services: BTreeMap<String, Map<String, Value>>,
+    /// `[targets.<name>]` definitions.
+    pub targets: BTreeMap<String, Target>,
+    /// Target isolated at boot; without one the services that were running
+    /// when core-daemon stopped are started again.
+    pub default_target: Option<String>,
 }
 
 impl Config {
     pub fn load_default() -> Self {
-        // In a real system, parse a toml file from /etc/circleosd/circleosd.conf
         Self {
             socket_path: "/tmp/circleosd.sock".to_string(),
             log_level: "info".to_string(),
+            log_dir: PathBuf::from("var/log"),
+            log_rotation: Rotation::default(),
+            var_dir: PathBuf::from("var"),
+            bin_dir: default_bin_dir(),
+            boot_services: KNOWN_SERVICES.iter().map(|s| s.to_string()).collect(),
+            boot_timeout: Duration::from_secs(10),
+            on_boot_failure: OnBootFailure::Degraded,
+            drain_timeout: Duration::from_secs(5),
+            backend_timeouts: HashMap::new(),
+            authz: Policy::default(),
+            access: AccessList::default(),
+            preload_plugins: Vec::new(),
+            services: BTreeMap::new(),
+            targets: BTreeMap::new(),
+            default_target: None,
+        }
+    }
+
+    /// Read a config file on top of the defaults.
+    pub fn from_path(p: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = p.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => ConfigError::NotFound { path: path.to_path_buf() },
+            _ => ConfigError::Io { path: path.to_path_buf(), source: e },
+        })?;
+        let file: FileConfig = toml::from_str(&text)
+            .map_err(|e| ConfigError::Parse { path: path.to_path_buf(), source: e })?;
+
+        let mut cfg = Self::load_default();
+        if let Some(v) = file.core.socket { cfg.set_socket("core.socket", v)?; }
+        if let Some(v) = file.core.log_level { cfg.set_log_level("core.log_level", v)?; }
+        if let Some(v) = file.core.log_dir { cfg.set_dir("core.log_dir", v, |c| &mut c.log_dir)?; }
+        if let Some(v) = file.core.var_dir { cfg.set_dir("core.var_dir", v, |c| &mut c.var_dir)?; }
+        if let Some(v) = file.core.bin_dir { cfg.set_dir("core.bin_dir", v, |c| &mut c.bin_dir)?; }
+        if let Some(v) = file.core.drain_timeout_secs { cfg.set_drain_timeout("core.drain_timeout_secs", v)?; }
+        if let Some(v) = file.boot.services { cfg.set_boot_services("boot.services", v)?; }
+        if let Some(v) = file.boot.timeout_secs { cfg.set_boot_timeout("boot.timeout_secs", v)?; }
+        if let Some(v) = file.boot.on_failure { cfg.set_on_boot_failure("boot.on_failure", &v)?; }
+        if let Some(v) = file.boot.plugins { cfg.set_preload_plugins("boot.plugins", v)?; }
+        for (name, ms) in file.gateway.timeout_ms {
+            cfg.set_backend_timeout(&format!("gateway.timeout_ms.{}", name), name, ms)?;
+        }
+        cfg.set_log_rotation(file.log)?;
+        cfg.set_authz(file.authz)?;
+        cfg.set_access(file.access)?;
+        for (name, table) in file.services {
+            cfg.add_service(name, table)?;
+        }
+        for (name, section) in file.targets {
+            cfg.add_target(name, section)?;
+        }
+        if let Some(v) = file.boot.target { cfg.set_default_target("boot.target", v)?; }
+        Ok(cfg)
+    }
+
+    /// Build the full layered config for this process.
+    pub fn load(args: &CliArgs) -> Result<Self, ConfigError> {
+        let explicit = args.config.clone()
+            .or_else(|| std::env::var_os("CIRCLEOSD_CONFIG").map(PathBuf::from));
+
+        let mut cfg = match explicit {
+            Some(path) => Self::from_path(path)?,
+            None => match Self::from_path(DEFAULT_CONFIG_PATH) {
+                Err(ConfigError::NotFound { .. }) => Self::load_default(),
+                other => other?,
+            },
+        };
+        cfg.apply_env()?;
+        cfg.apply_args(args)?;
+        Ok(cfg)
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(v) = env_var("CIRCLEOSD_SOCKET")? { self.set_socket("CIRCLEOSD_SOCKET", v)?; }
+        if let Some(v) = env_var("CIRCLEOSD_LOG_LEVEL")? { self.set_log_level("CIRCLEOSD_LOG_LEVEL", v)?; }
+        if let Some(v) = env_var("CIRCLEOSD_LOG_DIR")? { self.set_dir("CIRCLEOSD_LOG_DIR", v.into(), |c| &mut c.log_dir)?; }
+        if let Some(v) = env_var("CIRCLEOSD_VAR_DIR")? { self.set_dir("CIRCLEOSD_VAR_DIR", v.into(), |c| &mut c.var_dir)?; }
+        if let Some(v) = env_var("CIRCLEOSD_BOOT_SERVICES")? {
+            let list = v.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect();
+            self.set_boot_services("CIRCLEOSD_BOOT_SERVICES", list)?;
+        }
+        if let Some(v) = env_var("CIRCLEOSD_BIN_DIR")? { self.set_dir("CIRCLEOSD_BIN_DIR", v.into(), |c| &mut c.bin_dir)?; }
+        if let Some(v) = env_var("CIRCLEOSD_BOOT_TIMEOUT")? {
+            let secs = v.trim().parse()
+                .map_err(|_| ConfigError::invalid("CIRCLEOSD_BOOT_TIMEOUT", format!("`{}` is not a number of seconds", v)))?;
+            self.set_boot_timeout("CIRCLEOSD_BOOT_TIMEOUT", secs)?;
+        }
+        if let Some(v) = env_var("CIRCLEOSD_ON_BOOT_FAILURE")? { self.set_on_boot_failure("CIRCLEOSD_ON_BOOT_FAILURE", &v)?; }
+        if let Some(v) = env_var("CIRCLEOSD_TARGET")? { self.set_default_target("CIRCLEOSD_TARGET", v)?; }
+        if let Some(v) = env_var("CIRCLEOSD_DRAIN_TIMEOUT")? {
+            let secs = v.trim().parse()
+                .map_err(|_| ConfigError::invalid("CIRCLEOSD_DRAIN_TIMEOUT", format!("`{}` is not a number of seconds", v)))?;
+            self.set_drain_timeout("CIRCLEOSD_DRAIN_TIMEOUT", secs)?;
+        }
+        self.access = self.access.clone().with_env().map_err(|e| match e.split_once(": ") {
+            Some((key, reason)) => ConfigError::invalid(key, reason),
+            None => ConfigError::invalid("CIRCLEOSD_ALLOWED_UIDS", e),
+        })?;
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &CliArgs) -> Result<(), ConfigError> {
+        if let Some(v) = &args.socket { self.set_socket("--socket", v.clone())?; }
+        if let Some(v) = &args.log_level { self.set_log_level("--log-level", v.clone())?; }
+        if let Some(v) = &args.log_dir { self.set_dir("--log-dir", v.clone(), |c| &mut c.log_dir)?; }
+        if let Some(v) = &args.var_dir { self.set_dir("--var-dir", v.clone(), |c| &mut c.var_dir)?; }
+        if let Some(v) = &args.bin_dir { self.set_dir("--bin-dir", v.clone(), |c| &mut c.bin_dir)?; }
+        if let Some(v) = &args.boot_services { self.set_boot_services("--boot-services", v.clone())?; }
+        if let Some(v) = args.boot_timeout { self.set_boot_timeout("--boot-timeout", v)?; }
+        if let Some(v) = &args.on_boot_failure { self.set_on_boot_failure("--on-boot-failure", v)?; }
+        if let Some(v) = args.drain_timeout { self.set_drain_timeout("--drain-timeout", v)?; }
+        if let Some(v) = &args.target { self.set_default_target("--target", v.clone())?; }
+        Ok(())
+    }
+
+    fn set_socket(&mut self, key: &str, v: String) -> Result<(), ConfigError> {
+        if v.trim().is_empty() {
+            return Err(ConfigError::invalid(key, "socket path must not be empty"));
+        }
+        self.socket_path = v;
+        Ok(())
+    }
+
+    fn set_log_level(&mut self, key: &str, v: String) -> Result<(), ConfigError> {
+        let lvl = v.trim().to_ascii_lowercase();
+        if !LOG_LEVELS.contains(&lvl.as_str()) {
+            return Err(ConfigError::invalid(key, format!("`{}` is not one of {}", v, LOG_LEVELS.join(", "))));
+        }
+        self.log_level = lvl;
+        Ok(())
+    }
+
+    fn set_dir(&mut self, key: &str, v: PathBuf, field: fn(&mut Self) -> &mut PathBuf) -> Result<(), ConfigError> {
+        if v.as_os_str().is_empty() {
+            return Err(ConfigError::invalid(key, "directory must not be empty"));
+        }
+        if v.is_file() {
+            return Err(ConfigError::invalid(key, format!("{} is a file, not a directory", v.display())));
+        }
+        *field(self) = v;
+        Ok(())
+    }
+
+    fn set_boot_services(&mut self, key: &str, v: Vec<String>) -> Result<(), ConfigError> {
+        let mut seen: Vec<&str> = Vec::new();
+        for name in &v {
+            if !KNOWN_SERVICES.contains(&name.as_str()) {
+                return Err(ConfigError::invalid(
+                    key,
+                    format!("unknown service `{}` (expected one of {})", name, KNOWN_SERVICES.join(", ")),
+                ));
+            }
+            if seen.contains(&name.as_str()) {
+                return Err(ConfigError::invalid(key, format!("service `{}` listed twice", name)));
+            }
+            seen.push(name);
+        }
+        self.boot_services = v;
+        Ok(())
+    }
+
+    fn set_boot_timeout(&mut self, key: &str, secs: u64) -> Result<(), ConfigError> {
+        if secs == 0 {
+            return Err(ConfigError::invalid(key, "timeout must be at least 1 second"));
         }
+        self.boot_timeout = Duration::from_secs(secs);
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn from_path(_p: PathBuf) -> Self {
-        // TODO: implement reading the file
-        Self::load_default()
+    fn set_on_boot_failure(&mut self, key: &str, v: &str) -> Result<(), ConfigError> {
+        self.on_boot_failure = v.parse().map_err(|reason: String| ConfigError::invalid(key, reason))?;
+        Ok(())
+    }
+
+    fn set_drain_timeout(&mut self, key: &str, secs: u64) -> Result<(), ConfigError> {
+        if secs > 300 {
+            return Err(ConfigError::invalid(key, "drain timeout must be at most 300 seconds"));
+        }
+        self.drain_timeout = Duration::from_secs(secs);
+        Ok(())
+    }
+
+    fn set_preload_plugins(&mut self, key: &str, v: Vec<String>) -> Result<(), ConfigError> {
+        for (i, path) in v.iter().enumerate() {
+            if path.trim().is_empty() {
+                return Err(ConfigError::invalid(key, "plugin path must not be empty"));
+            }
+            if v[..i].contains(path) {
+                return Err(ConfigError::invalid(key, format!("plugin `{}` listed twice", path)));
+            }
+        }
+        self.preload_plugins = v;
+        Ok(())
+    }
+
+    /// Only `cmd` is checked here; service-registry validates the rest of
+    /// the spec when it is registered.
+    fn add_service(&mut self, name: String, table: toml::Table) -> Result<(), ConfigError> {
+        let key = format!("services.{}", name);
+        if name.trim().is_empty() || name.contains(char::is_whitespace) {
+            return Err(ConfigError::invalid(&key, "service names must be non-empty and without spaces"));
+        }
+        if table.contains_key("name") {
+            return Err(ConfigError::invalid(&format!("{}.name", key), "the name is taken from the table header"));
+        }
+        let spec = match serde_json::to_value(table) {
+            Ok(Value::Object(spec)) => spec,
+            Ok(_) => return Err(ConfigError::invalid(&key, "expected a table")),
+            Err(e) => return Err(ConfigError::invalid(&key, e.to_string())),
+        };
+        let cmd_ok = spec
+            .get("cmd")
+            .and_then(Value::as_array)
+            .is_some_and(|cmd| !cmd.is_empty() && cmd.iter().all(Value::is_string));
+        if !cmd_ok {
+            return Err(ConfigError::invalid(&format!("{}.cmd", key), "expected a non-empty list of strings"));
+        }
+        self.services.insert(name, spec);
+        Ok(())
+    }
+
+    /// Service names are not checked against what is registered: services
+    /// registered at runtime can be listed too.
+    fn add_target(&mut self, name: String, section: TargetSection) -> Result<(), ConfigError> {
+        let key = format!("targets.{}", name);
+        if name.trim().is_empty() || name.contains(char::is_whitespace) {
+            return Err(ConfigError::invalid(&key, "target names must be non-empty and without spaces"));
+        }
+        for (field, list) in [("services", &section.services), ("plugins", &section.plugins)] {
+            for (i, item) in list.iter().enumerate() {
+                if item.trim().is_empty() {
+                    return Err(ConfigError::invalid(&format!("{}.{}", key, field), "entries must not be empty"));
+                }
+                if list[..i].contains(item) {
+                    return Err(ConfigError::invalid(&format!("{}.{}", key, field), format!("`{}` listed twice", item)));
+                }
+            }
+        }
+        let target = Target { description: section.description, services: section.services, plugins: section.plugins };
+        self.targets.insert(name, target);
+        Ok(())
+    }
+
+    fn set_default_target(&mut self, key: &str, v: String) -> Result<(), ConfigError> {
+        let name = v.trim();
+        if name.is_empty() {
+            self.default_target = None;
+            return Ok(());
+        }
+        if !self.targets.contains_key(name) {
+            return Err(ConfigError::invalid(key, format!("unknown target `{}`", name)));
+        }
+        self.default_target = Some(name.to_string());
+        Ok(())
+    }
+
+    fn set_backend_timeout(&mut self, key: &str, name: String, ms: u64) -> Result<(), ConfigError> {
+        if !KNOWN_SERVICES.contains(&name.as_str()) {
+            return Err(ConfigError::invalid(key, format!("unknown service `{}`", name)));
+        }
+        if ms == 0 {
+            return Err(ConfigError::invalid(key, "timeout must be greater than zero"));
+        }
+        self.backend_timeouts.insert(name, Duration::from_millis(ms));
+        Ok(())
+    }
+
+    fn set_log_rotation(&mut self, section: LogSection) -> Result<(), ConfigError> {
+        if let Some(mb) = section.max_size_mb {
+            self.log_rotation.max_bytes = mb.checked_mul(1024 * 1024)
+                .ok_or_else(|| ConfigError::invalid("log.max_size_mb", "size is too large"))?;
+        }
+        if let Some(daily) = section.rotate_daily {
+            self.log_rotation.daily = daily;
+        }
+        if let Some(keep) = section.keep {
+            if keep > 100 {
+                return Err(ConfigError::invalid("log.keep", "at most 100 rotated files can be kept"));
+            }
+            self.log_rotation.keep = keep;
+        }
+        Ok(())
+    }
+
+    fn set_authz(&mut self, section: AuthzSection) -> Result<(), ConfigError> {
+        let mut policy = Policy::default();
+        if let Some(roles) = section.roles {
+            policy.roles = roles;
+        }
+        for (role, perms) in &policy.roles {
+            check_patterns(&format!("authz.roles.{}", role), perms)?;
+        }
+        if let Some(anonymous) = section.anonymous {
+            check_patterns("authz.anonymous", &anonymous)?;
+            policy.anonymous = anonymous;
+        }
+        if let Some(role) = section.default_role {
+            policy.default_role = if role.is_empty() { None } else { Some(role) };
+        }
+        if let Some(role) = &policy.default_role {
+            if !policy.roles.contains_key(role) {
+                return Err(ConfigError::invalid("authz.default_role", format!("unknown role `{}`", role)));
+            }
+        }
+        for (user, roles) in &section.users {
+            if let Some(role) = roles.iter().find(|r| !policy.roles.contains_key(*r)) {
+                return Err(ConfigError::invalid(&format!("authz.users.{}", user), format!("unknown role `{}`", role)));
+            }
+        }
+        policy.users = section.users;
+        self.authz = policy;
+        Ok(())
+    }
+
+    fn set_access(&mut self, section: AccessSection) -> Result<(), ConfigError> {
+        let resolve = |key: &str, names: &[String], lookup: fn(&str) -> Option<u32>| -> Result<Vec<u32>, ConfigError> {
+            names
+                .iter()
+                .map(|n| lookup(n.trim()).ok_or_else(|| ConfigError::invalid(key, format!("unknown name `{}`", n))))
+                .collect()
+        };
+        self.access.uids = resolve("access.allowed_users", &section.allowed_users, peercred::resolve_user)?;
+        self.access.gids = resolve("access.allowed_groups", &section.allowed_groups, peercred::resolve_group)?;
+        self.access.socket_group = match section.socket_group.as_deref().map(str::trim) {
+            None | Some("") => None,
+            Some(g) => Some(
+                peercred::resolve_group(g)
+                    .ok_or_else(|| ConfigError::invalid("access.socket_group", format!("unknown group `{}`", g)))?,
+            ),
+        };
+        Ok(())
+    }
+
+    /// Request timeout for a backend; hashing and wasm compilation get more headroom.
+    pub fn backend_timeout(&self, name: &str) -> Duration {
+        self.backend_timeouts.get(name).copied().unwrap_or(match name {
+            "auth-service" => Duration::from_secs(5),
+            "plugin-manager" => Duration::from_secs(10),
+            // `start` waits for notify-type services to report ready
+            "service-registry" => Duration::from_secs(15),
+            _ => Duration::from_secs(2),
+        })
+    }
+
+    pub fn log_file(&self) -> PathBuf {
+        self.log_dir.join("circleosd.log")
+    }
+
+    pub fn run_dir(&self) -> PathBuf {
+        self.var_dir.join("run")
+    }
+
+    /// core-daemon's record of registered services, kept across restarts.
+    pub fn services_file(&self) -> PathBuf {
+        self.var_dir.join("services.json")
+    }
+
+    pub fn sessions_dir(&self) -> PathBuf {
+        self.var_dir.join("sessions")
+    }
+
+    pub fn tracing_level(&self) -> tracing::Level {
+        self.log_level.parse().unwrap_or(tracing::Level::INFO)
+    }
+}
+
+/// Service binaries are normally installed next to core-daemon itself.
+fn default_bin_dir() -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+        .unwrap_or_else(|| PathBuf::from("build/release"))
+}
+
+fn check_patterns(key: &str, patterns: &[String]) -> Result<(), ConfigError> {
+    match patterns.iter().find(|p| !authz::is_valid_pattern(p)) {
+        Some(p) => Err(ConfigError::invalid(key, format!("unknown permission `{}`", p))),
+        None => Ok(()),
+    }
+}
+
+/// Read an environment variable, treating unset as `None` and non-UTF-8 as an error.
+fn env_var(key: &str) -> Result<Option<String>, ConfigError> {
+    match std::env::var(key) {
+        Ok(v) => Ok(Some(v)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(std::env::VarError::NotUnicode(_)) => Err(ConfigError::invalid(key, "value is not valid UTF-8")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_conf(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("circleosd-config-{}-{}.conf", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn invalid_key(err: ConfigError) -> String {
+        match err {
+            ConfigError::Invalid { key, .. } => key,
+            other => panic!("expected an invalid value, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn file_overrides_defaults() {
+        let path = write_conf("file", "[core]\nlog_level = \"DEBUG\"\nvar_dir = \"/srv/circleosd\"\n\n[boot]\nservices = [\"service-registry\"]\n");
+        let cfg = Config::from_path(&path).unwrap();
+        assert_eq!(cfg.log_level, "debug");
+        assert_eq!(cfg.var_dir, PathBuf::from("/srv/circleosd"));
+        assert_eq!(cfg.boot_services, ["service-registry"]);
+        // untouched keys keep their defaults
+        assert_eq!(cfg.socket_path, Config::load_default().socket_path);
+        assert_eq!(cfg.run_dir(), PathBuf::from("/srv/circleosd/run"));
+    }
+
+    #[test]
+    fn unknown_keys() {
+        // tables of other components are left alone
+        let path = write_conf("other", "[auth]\nhash = \"argon2\"\n");
+        assert!(Config::from_path(&path).is_ok());
+        // but ours are strict
+        let path = write_conf("strict", "[core]\nlog_levle = \"debug\"\n");
+        assert!(matches!(Config::from_path(&path), Err(ConfigError::Parse { .. })));
+        let path = write_conf("strict-boot", "[boot]\nservice = []\n");
+        assert!(matches!(Config::from_path(&path), Err(ConfigError::Parse { .. })));
+    }
+
+    #[test]
+    fn invalid_values() {
+        for (text, key) in [
+            ("[core]\nlog_level = \"loud\"\n", "core.log_level"),
+            ("[core]\nsocket = \" \"\n", "core.socket"),
+            ("[core]\nlog_dir = \"\"\n", "core.log_dir"),
+            ("[boot]\nservices = [\"web\"]\n", "boot.services"),
+            ("[boot]\nservices = [\"auth-service\", \"auth-service\"]\n", "boot.services"),
+            ("[authz]\nanonymous = [\"service.restart\"]\n", "authz.anonymous"),
+            ("[authz]\ndefault_role = \"guest\"\n", "authz.default_role"),
+            ("[authz.users]\nalice = [\"root\"]\n", "authz.users.alice"),
+        ] {
+            let path = write_conf("invalid", text);
+            assert_eq!(invalid_key(Config::from_path(&path).unwrap_err()), key, "{}", text);
+        }
+    }
+
+    #[test]
+    fn missing_file() {
+        let path = std::env::temp_dir().join("circleosd-config-does-not-exist.conf");
+        assert!(matches!(Config::from_path(&path), Err(ConfigError::NotFound { .. })));
+        let args = CliArgs { config: Some(path), ..Default::default() };
+        assert!(matches!(Config::load(&args), Err(ConfigError::NotFound { .. })));
+    }
+
+    // The only test that touches the environment, so the others never see it.
+    #[test]
+    fn env_overrides_file_and_args_override_env() {
+        let path = write_conf("layers", "[core]\nlog_level = \"debug\"\nsocket = \"/run/file.sock\"\nlog_dir = \"/var/log/file\"\n");
+        std::env::set_var("CIRCLEOSD_LOG_LEVEL", "warn");
+        std::env::set_var("CIRCLEOSD_LOG_DIR", "/var/log/env");
+        std::env::set_var("CIRCLEOSD_BOOT_SERVICES", "service-registry, auth-service");
+        std::env::remove_var("CIRCLEOSD_SOCKET");
+
+        let args = CliArgs { config: Some(path.clone()), ..Default::default() };
+        let cfg = Config::load(&args).unwrap();
+        assert_eq!(cfg.socket_path, "/run/file.sock");
+        assert_eq!(cfg.log_level, "warn");
+        assert_eq!(cfg.log_dir, PathBuf::from("/var/log/env"));
+        assert_eq!(cfg.boot_services, ["service-registry", "auth-service"]);
+
+        let args = CliArgs {
+            config: Some(path.clone()),
+            log_level: Some("error".into()),
+            boot_services: Some(vec!["plugin-manager".into()]),
+            ..Default::default()
+        };
+        let cfg = Config::load(&args).unwrap();
+        assert_eq!(cfg.log_level, "error");
+        assert_eq!(cfg.log_dir, PathBuf::from("/var/log/env"));
+        assert_eq!(cfg.boot_services, ["plugin-manager"]);
+
+        // a bad value is reported under the variable that set it
+        std::env::set_var("CIRCLEOSD_LOG_LEVEL", "chatty");
+        assert_eq!(invalid_key(Config::load(&args).unwrap_err()), "CIRCLEOSD_LOG_LEVEL");
+
+        for key in ["CIRCLEOSD_LOG_LEVEL", "CIRCLEOSD_LOG_DIR", "CIRCLEOSD_BOOT_SERVICES"] {
+            std::env::remove_var(key);
+        }
     }
 }
@@ -0,0 +1,204 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// Events kept for replay to late subscribers.
+const HISTORY: usize = 1024;
+/// Events buffered per subscriber before it starts missing some.
+const CHANNEL: usize = 256;
+
+/// Something that happened in CircleOSD.
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    /// Increases by one per event for the lifetime of core-daemon.
+    pub seq: u64,
+    pub ts: DateTime<Utc>,
+    /// Dotted name such as `service.started` or `user.login`.
+    pub topic: String,
+    pub data: Value,
+}
+
+/// In-process publish/subscribe hub with a bounded replay history.
+pub struct EventBus {
+    next_seq: AtomicU64,
+    tx: broadcast::Sender<Event>,
+    history: Mutex<VecDeque<Event>>,
+    /// Handed to the services core-daemon spawns so they can publish.
+    publisher_token: String,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(CHANNEL);
+        Self {
+            next_seq: AtomicU64::new(1),
+            tx,
+            history: Mutex::new(VecDeque::with_capacity(HISTORY)),
+            publisher_token: random_token(),
+        }
+    }
+
+    pub fn publisher_token(&self) -> &str {
+        &self.publisher_token
+    }
+
+    pub fn publish(&self, topic: &str, data: Value) -> u64 {
+        // Sequence numbers are assigned under the history lock so that the
+        // history and the live stream see events in the same order.
+        let mut history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        let event = Event { seq, ts: Utc::now(), topic: topic.to_string(), data };
+        if history.len() == HISTORY {
+            history.pop_front();
+        }
+        history.push_back(event.clone());
+        // No receivers is not an error.
+        let _ = self.tx.send(event);
+        seq
+    }
+
+    /// Subscribe to events matching `topics`, first replaying retained
+    /// events with a sequence number greater than `since`.
+    pub fn subscribe(&self, topics: Vec<String>, since: Option<u64>) -> Subscription {
+        let history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        let rx = self.tx.subscribe();
+        let oldest = history.front().map(|e| e.seq);
+        let replay: VecDeque<Event> = match since {
+            Some(since) => history
+                .iter()
+                .filter(|e| e.seq > since && topics.iter().any(|t| topic_matches(t, &e.topic)))
+                .cloned()
+                .collect(),
+            None => VecDeque::new(),
+        };
+        Subscription {
+            topics,
+            replay,
+            rx,
+            next_seq: self.next_seq.load(Ordering::SeqCst),
+            oldest,
+        }
+    }
+}
+
+/// A live feed of events for one subscriber.
+pub struct Subscription {
+    topics: Vec<String>,
+    replay: VecDeque<Event>,
+    rx: broadcast::Receiver<Event>,
+    /// Sequence number the next live event will carry.
+    pub next_seq: u64,
+    /// Oldest event still available for replay, if any.
+    pub oldest: Option<u64>,
+}
+
+pub enum Next {
+    Event(Event),
+    /// The subscriber fell behind and this many events were dropped.
+    Lagged(u64),
+    Closed,
+}
+
+impl Subscription {
+    pub async fn next(&mut self) -> Next {
+        if let Some(event) = self.replay.pop_front() {
+            return Next::Event(event);
+        }
+        loop {
+            match self.rx.recv().await {
+                Ok(event) if self.topics.iter().any(|t| topic_matches(t, &event.topic)) => return Next::Event(event),
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(n)) => return Next::Lagged(n),
+                Err(broadcast::error::RecvError::Closed) => return Next::Closed,
+            }
+        }
+    }
+}
+
+/// `*` matches everything, `service.*` a whole group, anything else itself.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    match filter.strip_suffix('*') {
+        Some("") => true,
+        Some(prefix) if prefix.ends_with('.') => topic.starts_with(prefix),
+        _ => filter == topic,
+    }
+}
+
+fn random_token() -> String {
+    let mut buf = [0u8; 16];
+    if let Err(e) = std::fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut buf)) {
+        // Fall back to something unguessable enough for a local socket.
+        tracing::warn!("failed to read /dev/urandom: {}", e);
+        let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default() as u128;
+        buf = (nanos ^ ((std::process::id() as u128) << 64)).to_le_bytes();
+    }
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn topics(filters: &[&str]) -> Vec<String> {
+        filters.iter().map(|t| t.to_string()).collect()
+    }
+
+    async fn next_topic(sub: &mut Subscription) -> (u64, String) {
+        match sub.next().await {
+            Next::Event(e) => (e.seq, e.topic),
+            Next::Lagged(n) => panic!("lagged by {}", n),
+            Next::Closed => panic!("bus closed"),
+        }
+    }
+
+    #[test]
+    fn topic_filters() {
+        assert!(topic_matches("*", "service.started"));
+        assert!(topic_matches("service.*", "service.started"));
+        assert!(!topic_matches("service.*", "system.ready"));
+        // a group only matches whole segments
+        assert!(!topic_matches("serv*", "service.started"));
+        assert!(!topic_matches("service*", "service.started"));
+        assert!(topic_matches("user.login", "user.login"));
+        assert!(!topic_matches("user.login", "user.logout"));
+    }
+
+    #[tokio::test]
+    async fn replays_history_after_since() {
+        let bus = EventBus::new();
+        bus.publish("service.started", json!({ "name": "a" }));
+        bus.publish("user.login", json!({ "user": "alice" }));
+        bus.publish("service.stopped", json!({ "name": "a" }));
+
+        let mut sub = bus.subscribe(topics(&["service.*"]), Some(1));
+        assert_eq!(sub.oldest, Some(1));
+        assert_eq!(sub.next_seq, 4);
+        // only matching events after `since`, then the live stream
+        assert_eq!(next_topic(&mut sub).await, (3, "service.stopped".to_string()));
+        bus.publish("user.logout", json!({}));
+        bus.publish("service.restarted", json!({}));
+        assert_eq!(next_topic(&mut sub).await, (5, "service.restarted".to_string()));
+
+        // without `since`, nothing is replayed
+        let mut sub = bus.subscribe(topics(&["*"]), None);
+        bus.publish("system.shutdown", json!({}));
+        assert_eq!(next_topic(&mut sub).await, (6, "system.shutdown".to_string()));
+    }
+
+    #[tokio::test]
+    async fn history_is_bounded() {
+        let bus = EventBus::new();
+        for _ in 0..HISTORY + 10 {
+            bus.publish("service.started", json!({}));
+        }
+        let mut sub = bus.subscribe(topics(&["*"]), Some(0));
+        assert_eq!(sub.oldest, Some(11));
+        assert_eq!(next_topic(&mut sub).await.0, 11);
+    }
+}
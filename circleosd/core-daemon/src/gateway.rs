@@ -0,0 +1,177 @@
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::boot::{self, Component};
+use crate::config::Config;
+
+/// Idle connections kept open per backend for reuse.
+const MAX_IDLE: usize = 4;
+
+#[derive(Debug, Error)]
+pub enum GatewayError {
+    #[error("{backend} is unavailable: {reason}")]
+    Unavailable { backend: &'static str, reason: String },
+
+    #[error("{backend} did not answer within {timeout:?}")]
+    Timeout { backend: &'static str, timeout: Duration },
+
+    #[error("{backend} sent an invalid reply: {reason}")]
+    BadReply { backend: &'static str, reason: String },
+}
+
+/// One open line-protocol connection to a backend socket.
+struct Conn {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    w: OwnedWriteHalf,
+}
+
+/// Client for one microservice socket. Connections are pooled and reused
+/// across requests; every call is bounded by the backend's timeout.
+pub struct Backend {
+    name: &'static str,
+    socket: PathBuf,
+    /// Request timeout in milliseconds; changed by a config reload.
+    timeout_ms: AtomicU64,
+    idle: Mutex<Vec<Conn>>,
+}
+
+impl Backend {
+    pub fn new(comp: &'static Component, socket: PathBuf, timeout: Duration) -> Self {
+        Self {
+            name: comp.name,
+            socket,
+            timeout_ms: AtomicU64::new(timeout.as_millis() as u64),
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.timeout_ms.store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Send one JSON request and return the backend's JSON reply.
+    pub async fn call(&self, req: &Value) -> Result<Value, GatewayError> {
+        let line = req.to_string();
+        let timeout = Duration::from_millis(self.timeout_ms.load(Ordering::Relaxed));
+        match tokio::time::timeout(timeout, self.round_trip(&line)).await {
+            Ok(res) => res,
+            Err(_) => Err(GatewayError::Timeout { backend: self.name, timeout }),
+        }
+    }
+
+    async fn round_trip(&self, line: &str) -> Result<Value, GatewayError> {
+        let request = format!("{}\n", line);
+        // A pooled connection may have been closed by a backend restart. The
+        // request goes out again on a fresh connection only if the old one
+        // took none of it: once any of it was written the backend may have
+        // acted on it, and sending it twice could repeat a start or a
+        // create_user.
+        let pooled = self.idle.lock().await.pop();
+        if let Some(mut conn) = pooled {
+            match conn.w.write(request.as_bytes()).await {
+                Ok(n) => return self.exchange(conn, &request.as_bytes()[n..]).await,
+                Err(e) => debug!("pooled connection to {} is gone ({}); reconnecting", self.name, e),
+            }
+        }
+        let conn = self.connect().await?;
+        self.exchange(conn, request.as_bytes()).await
+    }
+
+    async fn connect(&self) -> Result<Conn, GatewayError> {
+        let stream = UnixStream::connect(&self.socket).await.map_err(|e| self.unavailable(e))?;
+        let (r, w) = stream.into_split();
+        Ok(Conn { lines: BufReader::new(r).lines(), w })
+    }
+
+    /// Write what is left of the request, read the reply and pool the
+    /// connection again.
+    async fn exchange(&self, mut conn: Conn, rest: &[u8]) -> Result<Value, GatewayError> {
+        conn.w.write_all(rest).await.map_err(|e| self.unavailable(e))?;
+        let reply = conn.lines.next_line().await
+            .map_err(|e| self.unavailable(e))?
+            .ok_or_else(|| self.unavailable("connection closed"))?;
+        let v = serde_json::from_str(&reply)
+            .map_err(|e| GatewayError::BadReply { backend: self.name, reason: e.to_string() })?;
+
+        let mut idle = self.idle.lock().await;
+        if idle.len() < MAX_IDLE {
+            idle.push(conn);
+        }
+        Ok(v)
+    }
+
+    /// Send a request that answers with a stream, on a connection of its
+    /// own. Returns the first reply and the lines that follow it.
+    pub async fn open_stream(&self, req: &Value) -> Result<(Value, BackendStream), GatewayError> {
+        let timeout = Duration::from_millis(self.timeout_ms.load(Ordering::Relaxed));
+        let opened = async {
+            let mut conn = self.connect().await?;
+            conn.w.write_all(format!("{}\n", req).as_bytes()).await.map_err(|e| self.unavailable(e))?;
+            let reply = conn.lines.next_line().await
+                .map_err(|e| self.unavailable(e))?
+                .ok_or_else(|| self.unavailable("connection closed"))?;
+            let v = serde_json::from_str(&reply)
+                .map_err(|e| GatewayError::BadReply { backend: self.name, reason: e.to_string() })?;
+            Ok((v, BackendStream { lines: conn.lines, _w: conn.w }))
+        };
+        match tokio::time::timeout(timeout, opened).await {
+            Ok(res) => res,
+            Err(_) => Err(GatewayError::Timeout { backend: self.name, timeout }),
+        }
+    }
+
+    fn unavailable(&self, reason: impl ToString) -> GatewayError {
+        GatewayError::Unavailable { backend: self.name, reason: reason.to_string() }
+    }
+}
+
+/// The rest of a backend's reply to a streaming request. Dropping it
+/// closes the connection, which ends the stream on the backend's side too.
+pub struct BackendStream {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    _w: OwnedWriteHalf,
+}
+
+impl BackendStream {
+    /// Next line; `None` once the backend closes the stream.
+    pub async fn next(&mut self) -> Option<Value> {
+        let line = self.lines.next_line().await.ok()??;
+        serde_json::from_str(&line).ok()
+    }
+}
+
+/// Clients for the three CircleOSD microservices.
+pub struct Gateway {
+    pub registry: Backend,
+    pub auth: Backend,
+    pub plugins: Backend,
+}
+
+impl Gateway {
+    pub fn new(cfg: &Config) -> Self {
+        let backend = |name: &str| {
+            let comp = boot::component(name).expect("backend missing from boot table");
+            Backend::new(comp, comp.socket(&cfg.run_dir()), cfg.backend_timeout(name))
+        };
+        Self {
+            registry: backend("service-registry"),
+            auth: backend("auth-service"),
+            plugins: backend("plugin-manager"),
+        }
+    }
+
+    /// Pick up reloaded `[gateway.timeout_ms]` values.
+    pub fn set_timeouts(&self, cfg: &Config) {
+        for backend in [&self.registry, &self.auth, &self.plugins] {
+            backend.set_timeout(cfg.backend_timeout(backend.name));
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use crate::config::Config;
+
+/// Upper bound on records returned by one query.
+const MAX_QUERY: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+/// One line of circleosd.log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub ts: DateTime<Utc>,
+    pub level: Level,
+    pub component: String,
+    pub msg: String,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub fields: Map<String, Value>,
+}
+
+/// When circleosd.log is rotated and how many old files are kept.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rotation {
+    /// Rotate once the file would grow past this size; 0 disables.
+    pub max_bytes: u64,
+    /// Rotate when the local date changes.
+    pub daily: bool,
+    /// Rotated files to keep (circleosd.log.1 is the newest).
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self { max_bytes: 10 * 1024 * 1024, daily: true, keep: 7 }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    rotation: Rotation,
+    min_level: Level,
+    file: Option<File>,
+    size: u64,
+    day: NaiveDate,
+}
+
+static LOG: OnceLock<Mutex<Writer>> = OnceLock::new();
+
+/// Start writing to the configured log file. Records emitted before this
+/// are only printed to stdout, whatever their level.
+pub fn init(cfg: &Config) {
+    let _ = LOG.set(Mutex::new(Writer {
+        path: cfg.log_file(),
+        rotation: cfg.log_rotation.clone(),
+        min_level: min_level(cfg),
+        file: None,
+        size: 0,
+        day: Local::now().date_naive(),
+    }));
+}
+
+/// Apply a reloaded level and rotation policy. The file location is fixed
+/// for the lifetime of the process.
+pub fn reconfigure(cfg: &Config) {
+    if let Some(writer) = LOG.get() {
+        let mut w = writer.lock().unwrap_or_else(|e| e.into_inner());
+        w.min_level = min_level(cfg);
+        w.rotation = cfg.log_rotation.clone();
+    }
+}
+
+fn min_level(cfg: &Config) -> Level {
+    match cfg.log_level.as_str() {
+        "trace" | "debug" => Level::Debug,
+        "warn" => Level::Warn,
+        "error" => Level::Error,
+        _ => Level::Info,
+    }
+}
+
+/// Write a record with structured fields (a JSON object, or `Value::Null` for none).
+pub fn log(level: Level, component: &str, msg: impl Into<String>, fields: Value) {
+    // The level filter applies to the console as well as the file.
+    let writer = LOG.get().map(|w| w.lock().unwrap_or_else(|e| e.into_inner()));
+    if writer.as_ref().is_some_and(|w| level < w.min_level) {
+        return;
+    }
+
+    let record = Record {
+        ts: Utc::now(),
+        level,
+        component: component.to_string(),
+        msg: msg.into(),
+        fields: match fields {
+            Value::Object(map) => map,
+            _ => Map::new(),
+        },
+    };
+
+    println!(
+        "[{}] {:<5} {}: {}",
+        record.ts.with_timezone(&Local).format("%H:%M:%S"),
+        level.as_str(),
+        record.component,
+        record.msg
+    );
+
+    if let Some(mut w) = writer {
+        if let Err(e) = w.write(&record) {
+            eprintln!("failed to write {}: {}", w.path.display(), e);
+        }
+    }
+}
+
+pub fn info(component: &str, msg: impl Into<String>) {
+    log(Level::Info, component, msg, Value::Null);
+}
+
+pub fn warn(component: &str, msg: impl Into<String>) {
+    log(Level::Warn, component, msg, Value::Null);
+}
+
+pub fn error(component: &str, msg: impl Into<String>) {
+    log(Level::Error, component, msg, Value::Null);
+}
+
+impl Writer {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let today = Local::now().date_naive();
+        let too_big = self.rotation.max_bytes > 0 && self.size > 0 && self.size + line.len() as u64 > self.rotation.max_bytes;
+        let new_day = self.rotation.daily && today != self.day;
+        if self.file.is_none() {
+            self.open()?;
+        } else if too_big || new_day {
+            self.rotate()?;
+        }
+
+        let file = self.file.as_mut().expect("log file opened above");
+        file.write_all(&line)?;
+        self.size += line.len() as u64;
+        self.day = today;
+        Ok(())
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let meta = file.metadata()?;
+        self.size = meta.len();
+        // An existing file from an earlier day is rotated on the first write.
+        if let Ok(modified) = meta.modified() {
+            self.day = DateTime::<Local>::from(modified).date_naive();
+        }
+        self.file = Some(file);
+        if self.size > 0 && self.rotation.daily && self.day != Local::now().date_naive() {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// circleosd.log -> .1 -> .2 ..., dropping anything past `keep`.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        let keep = self.rotation.keep;
+        for (n, old) in rotated_files(&self.path) {
+            if n >= keep {
+                fs::remove_file(old)?;
+            }
+        }
+        for n in (1..keep).rev() {
+            let from = numbered(&self.path, n);
+            if from.exists() {
+                fs::rename(from, numbered(&self.path, n + 1))?;
+            }
+        }
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.day = Local::now().date_naive();
+        self.file = Some(file);
+        Ok(())
+    }
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Existing rotated files as (number, path), oldest first.
+fn rotated_files(path: &Path) -> Vec<(usize, PathBuf)> {
+    let (Some(dir), Some(base)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) else {
+        return Vec::new();
+    };
+    let prefix = format!("{}.", base);
+    let mut files: Vec<(usize, PathBuf)> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|e| {
+            let name = e.file_name();
+            let n = name.to_str()?.strip_prefix(&prefix)?.parse().ok()?;
+            Some((n, e.path()))
+        })
+        .collect();
+    files.sort_by_key(|f| std::cmp::Reverse(f.0));
+    files
+}
+
+/// Filter for `query_logs`. Every criterion is optional.
+#[derive(Debug, Default)]
+pub struct Query {
+    pub component: Option<String>,
+    /// Minimum level.
+    pub level: Option<Level>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Case-insensitive substring of the message or fields.
+    pub text: Option<String>,
+    /// Return at most this many of the newest matches.
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    fn matches(&self, r: &Record) -> bool {
+        if self.component.as_ref().is_some_and(|c| *c != r.component) {
+            return false;
+        }
+        if self.level.is_some_and(|l| r.level < l) {
+            return false;
+        }
+        if self.since.is_some_and(|t| r.ts < t) || self.until.is_some_and(|t| r.ts > t) {
+            return false;
+        }
+        match &self.text {
+            Some(text) => {
+                let needle = text.to_lowercase();
+                r.msg.to_lowercase().contains(&needle)
+                    || Value::Object(r.fields.clone()).to_string().to_lowercase().contains(&needle)
+            }
+            None => true,
+        }
+    }
+}
+
+/// Search the current and rotated log files, returning matches oldest first.
+/// Lines that are not JSON records (e.g. from older versions) are skipped.
+pub fn query(q: &Query) -> io::Result<Vec<Record>> {
+    let Some(writer) = LOG.get() else { return Ok(Vec::new()) };
+    let path = writer.lock().unwrap_or_else(|e| e.into_inner()).path.clone();
+    search(&path, q)
+}
+
+fn search(path: &Path, q: &Query) -> io::Result<Vec<Record>> {
+    let limit = q.limit.unwrap_or(100).clamp(1, MAX_QUERY);
+    let mut files: Vec<PathBuf> = rotated_files(path).into_iter().map(|(_, p)| p).collect();
+    files.push(path.to_path_buf());
+
+    let mut out = VecDeque::with_capacity(limit);
+    for file in files {
+        let f = match File::open(&file) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for line in BufReader::new(f).lines() {
+            let line = match line {
+                Ok(line) => line,
+                // not UTF-8: skipped like any other line that is not a record
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                // the rest of this file is unreadable; keep what it gave
+                Err(_) => break,
+            };
+            let Ok(record) = serde_json::from_str::<Record>(&line) else { continue };
+            if q.matches(&record) {
+                if out.len() == limit {
+                    out.pop_front();
+                }
+                out.push_back(record);
+            }
+        }
+    }
+    Ok(out.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("circleosd-logging-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn writer(dir: &Path, rotation: Rotation) -> Writer {
+        Writer {
+            path: dir.join("circleosd.log"),
+            rotation,
+            min_level: Level::Debug,
+            file: None,
+            size: 0,
+            day: Local::now().date_naive(),
+        }
+    }
+
+    fn record(level: Level, component: &str, msg: &str, fields: Value) -> Record {
+        let Value::Object(fields) = fields else { panic!("fields must be an object") };
+        Record { ts: Utc::now(), level, component: component.to_string(), msg: msg.to_string(), fields }
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_the_newest() {
+        let dir = log_dir("size");
+        let mut w = writer(&dir, Rotation { max_bytes: 300, daily: false, keep: 2 });
+        for n in 0..20 {
+            w.write(&record(Level::Info, "boot", &format!("message {}", n), json!({}))).unwrap();
+        }
+        let kept: Vec<usize> = rotated_files(&w.path).into_iter().map(|(n, _)| n).collect();
+        assert_eq!(kept, [2, 1]);
+        for path in [w.path.clone(), numbered(&w.path, 1), numbered(&w.path, 2)] {
+            assert!(fs::metadata(&path).unwrap().len() <= 300, "{}", path.display());
+        }
+        // the newest record is in the live file, and .1 holds the ones just before it
+        let live = fs::read_to_string(&w.path).unwrap();
+        assert!(live.contains("message 19"));
+        let older = fs::read_to_string(numbered(&w.path, 1)).unwrap();
+        assert!(!older.contains("message 19"));
+    }
+
+    #[test]
+    fn rotates_on_a_new_day() {
+        let dir = log_dir("daily");
+        let mut w = writer(&dir, Rotation { max_bytes: 0, daily: true, keep: 3 });
+        w.write(&record(Level::Info, "boot", "yesterday", json!({}))).unwrap();
+        w.day = w.day.pred_opt().unwrap();
+        w.write(&record(Level::Info, "boot", "today", json!({}))).unwrap();
+        assert!(fs::read_to_string(numbered(&w.path, 1)).unwrap().contains("yesterday"));
+        let live = fs::read_to_string(&w.path).unwrap();
+        assert!(live.contains("today") && !live.contains("yesterday"));
+    }
+
+    #[test]
+    fn query_filters() {
+        let dir = log_dir("query");
+        let mut w = writer(&dir, Rotation { max_bytes: 400, daily: false, keep: 5 });
+        let start = Utc::now();
+        for n in 0..6 {
+            w.write(&record(Level::Info, "boot", &format!("booting step {}", n), json!({}))).unwrap();
+        }
+        w.write(&record(Level::Warn, "supervisor", "service-registry exited", json!({ "service": "service-registry" })))
+            .unwrap();
+        w.write(&record(Level::Error, "rpc", "Backend Timeout", json!({}))).unwrap();
+        assert!(!rotated_files(&w.path).is_empty(), "the records should span rotated files");
+
+        let msgs = |q: Query| -> Vec<String> { search(&w.path, &q).unwrap().into_iter().map(|r| r.msg).collect() };
+        assert_eq!(msgs(Query::default()).len(), 8);
+        assert_eq!(msgs(Query { component: Some("rpc".into()), ..Default::default() }), ["Backend Timeout"]);
+        assert_eq!(
+            msgs(Query { level: Some(Level::Warn), ..Default::default() }),
+            ["service-registry exited", "Backend Timeout"]
+        );
+        // text matches the message or the fields, ignoring case
+        assert_eq!(msgs(Query { text: Some("backend timeout".into()), ..Default::default() }), ["Backend Timeout"]);
+        assert_eq!(msgs(Query { text: Some("SERVICE-REGISTRY".into()), ..Default::default() }).len(), 1);
+        // the newest matches win, still returned oldest first
+        assert_eq!(
+            msgs(Query { component: Some("boot".into()), limit: Some(2), ..Default::default() }),
+            ["booting step 4", "booting step 5"]
+        );
+        assert_eq!(msgs(Query { since: Some(start), ..Default::default() }).len(), 8);
+        assert!(msgs(Query { until: Some(start), ..Default::default() }).is_empty());
+    }
+
+    #[test]
+    fn query_skips_lines_that_are_not_records() {
+        let dir = log_dir("garbage");
+        let mut w = writer(&dir, Rotation::default());
+        w.write(&record(Level::Info, "boot", "before", json!({}))).unwrap();
+        let file = w.file.as_mut().unwrap();
+        file.write_all(b"[08:00:01] [BOOT] plain text from an older version\n").unwrap();
+        file.write_all(b"\xff\xfe not utf-8\n").unwrap();
+        w.size = fs::metadata(&w.path).unwrap().len();
+        w.write(&record(Level::Info, "boot", "after", json!({}))).unwrap();
+
+        let found: Vec<String> = search(&w.path, &Query::default()).unwrap().into_iter().map(|r| r.msg).collect();
+        assert_eq!(found, ["before", "after"]);
+    }
+}
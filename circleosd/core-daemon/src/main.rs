@@ -1,127 +1,280 @@
 use anyhow::Result;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 use tokio::net::{UnixListener, UnixStream};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
 use tracing::{info, debug, error};
-use chrono::Local;
+use clap::Parser;
+use serde_json::{json, Value};
+use circleosd_proto::{jsonrpc, peercred};
 use std::fs;
+use std::time::Instant;
 
+mod authz;
+mod boot;
 mod service_registry;
-mod auth;
-mod plugin;
+mod gateway;
+mod logging;
+mod reload;
 mod rpc;
 mod config;
+mod events;
+mod shutdown;
+mod target;
+mod timing;
 
+use boot::Supervisor;
+use config::OnBootFailure;
+use events::EventBus;
+use gateway::Gateway;
+use logging::Level;
 use service_registry::Registry;
-use auth::AuthService;
-use plugin::PluginManager;
-use rpc::handle_rpc;
-
-const LOG_PATH: &str = "var/log/circleosd.log";
-
-fn log_to_file(msg: &str) {
-    let now = Local::now().format("%H:%M:%S");
-    let formatted = format!("[{now}] {msg}\n");
-    let _ = fs::create_dir_all("var/log");
-    let _ = fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(LOG_PATH)
-        .and_then(|mut f| std::io::Write::write_all(&mut f, formatted.as_bytes()));
-    println!("{formatted}");
-}
-
-async fn boot_step(label: &str, delay_ms: u64, ok: bool) {
-    log_to_file(&format!("[BOOT] {label}"));
-    tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
-    if ok {
-        log_to_file(&format!("[OK] {label}"));
-    } else {
-        log_to_file(&format!("[WARN] {label} (delayed)"));
-    }
-}
+use peercred::Peer;
+use rpc::{Connection, Context};
+use shutdown::Signals;
+use timing::Timeline;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    fs::create_dir_all("var/log")?;
-    fs::create_dir_all("var/run")?;
-    fs::create_dir_all("var/sessions")?;
+    // Load configuration: defaults < etc/circleosd.conf < CIRCLEOSD_* env < CLI flags
+    let args = config::CliArgs::parse();
+    let cfg = config::Config::load(&args)?;
+    let started = Instant::now();
+
+    tracing_subscriber::fmt().with_max_level(cfg.tracing_level()).init();
+    fs::create_dir_all(&cfg.log_dir)?;
+    logging::init(&cfg);
+    fs::create_dir_all(cfg.run_dir())?;
+    fs::create_dir_all(cfg.sessions_dir())?;
 
     println!("====================================================");
     println!("🌀  CircleOSD Core Daemon Boot Sequence v0.2.0");
     println!("====================================================");
 
-    boot_step("Powering on CPU cores...", 200, true).await;
-    boot_step("Loading kernel services...", 250, true).await;
-    boot_step("Initializing Service Registry...", 300, true).await;
-    boot_step("Starting Auth Service...", 350, true).await;
-    boot_step("Starting Plugin Manager...", 400, true).await;
-    boot_step("Preparing RPC socket...", 150, true).await;
+    logging::log(
+        Level::Info,
+        "core",
+        "configuration loaded",
+        json!({
+            "socket": cfg.socket_path,
+            "log_level": cfg.log_level,
+            "var_dir": cfg.var_dir,
+            "boot": cfg.boot_services,
+        }),
+    );
 
-    // Load configuration
-    let cfg = config::Config::load_default();
+    // Spawn the microservices in dependency order and wait for each to answer a ping.
+    // A shutdown signal during boot stops whatever has been started so far.
+    let mut signals = Signals::new()?;
+    // SIGHUP reloads the config; until the accept loop runs it just waits.
+    let mut hangup = signal(SignalKind::hangup())?;
+    let events = Arc::new(EventBus::new());
+    let timeline = Arc::new(Timeline::new(started));
+    let supervisor = Arc::new(Supervisor::new(&cfg, events.clone(), timeline.clone()));
+    let report = tokio::select! {
+        report = supervisor.boot(&cfg.boot_services) => report,
+        signal = signals.recv() => {
+            logging::info("shutdown", format!("{signal} received during boot"));
+            supervisor.stop_all().await;
+            stopped(signal, started);
+            return Ok(());
+        }
+    };
+    let failed = report.failed();
+    if !failed.is_empty() {
+        match cfg.on_boot_failure {
+            OnBootFailure::Abort => {
+                logging::error("boot", format!("boot aborted: {} not ready", failed.join(", ")));
+                supervisor.stop_all().await;
+                anyhow::bail!("boot failed: {} not ready", failed.join(", "));
+            }
+            OnBootFailure::Degraded => {
+                logging::warn("boot", format!("entering degraded mode: {} not ready", failed.join(", ")));
+            }
+        }
+    }
 
     // Shared components
-    let registry = Arc::new(Registry::new());
-    let auth = Arc::new(AuthService::new());
-    let plugin_mgr = Arc::new(PluginManager::new());
-
-    // Start background registry loop
-    {
-        let reg = registry.clone();
-        tokio::spawn(async move {
-            reg.run().await;
-        });
-    }
+    let ctx = Arc::new(Context {
+        registry: Registry::open(cfg.services_file()),
+        gateway: Gateway::new(&cfg),
+        events: events.clone(),
+        config: RwLock::new(cfg.clone()),
+        args,
+        preloaded: tokio::sync::Mutex::new(HashMap::new()),
+        target: RwLock::new(None),
+        timeline: timeline.clone(),
+    });
+    reload::apply_boot_definitions(&ctx).await;
 
     // Prepare RPC socket
     let sock_path = &cfg.socket_path;
     let _ = std::fs::remove_file(sock_path);
     let listener = UnixListener::bind(sock_path)?;
-    log_to_file(&format!("[SYSTEM] RPC socket ready on {sock_path}"));
+    cfg.access.apply_to_socket(sock_path.as_ref())?;
+    logging::info("core", format!("RPC socket ready on {sock_path}"));
     info!("Listening for RPC on {}", sock_path);
 
-    log_to_file("[SYSTEM] Boot sequence completed.");
+    let boot_ms = timeline.finish();
+    if failed.is_empty() {
+        logging::log(Level::Info, "boot", "boot sequence completed", json!({ "millis": boot_ms }));
+    } else {
+        logging::log(Level::Warn, "boot", "boot sequence completed (degraded)", json!({ "millis": boot_ms, "failed": failed }));
+    }
+    events.publish("system.ready", json!({ "millis": boot_ms, "degraded": !failed.is_empty() }));
     println!("====================================================");
     println!("🟢 System ready for user login");
     println!("Use `circlectl user login` to authenticate.");
-    println!("Logs → var/log/circleosd.log");
+    println!("Logs → {}", cfg.log_file().display());
     println!("====================================================");
 
-    // Accept RPC connections
-    loop {
-        let (stream, _) = listener.accept().await?;
-        let registry = registry.clone();
-        let auth = auth.clone();
-        let plugin_mgr = plugin_mgr.clone();
-
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(stream, registry, auth, plugin_mgr).await {
-                error!("connection handling failed: {:?}", e);
+    // Accept RPC connections until a shutdown signal arrives
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut conns = JoinSet::new();
+    let reason = loop {
+        tokio::select! {
+            signal = signals.recv() => break signal.to_string(),
+            _ = hangup.recv() => {
+                let ctx = ctx.clone();
+                tokio::spawn(async move {
+                    // The outcome is logged and published by reload itself.
+                    let _ = reload::reload(&ctx, "SIGHUP").await;
+                });
+            }
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => break format!("accept failed: {e}"),
+                };
+                let Some(peer) = admit(&stream, &ctx.config().access) else { continue };
+                let ctx = ctx.clone();
+                let stop_rx = stop_rx.clone();
+                conns.spawn(async move {
+                    if let Err(e) = handle_connection(stream, peer, ctx, stop_rx).await {
+                        error!("connection handling failed: {:?}", e);
+                    }
+                });
             }
-        });
+            Some(_) = conns.join_next(), if !conns.is_empty() => {}
+        }
+    };
+
+    // Ordered teardown: stop accepting, let open requests finish, unload
+    // plugins, stop the services dependents-first, then remove the socket.
+    drop(listener);
+    events.publish("system.shutdown", json!({ "reason": reason }));
+    let _ = stop_tx.send(true);
+    logging::info("shutdown", format!("{reason}; draining {} connection(s)", conns.len()));
+    let drain_timeout = ctx.config().drain_timeout;
+    let drained = tokio::time::timeout(drain_timeout, async {
+        while conns.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        logging::warn(
+            "shutdown",
+            format!("{} connection(s) still busy after {:?}; dropping them", conns.len(), drain_timeout),
+        );
+        conns.shutdown().await;
     }
+
+    shutdown::unload_plugins(&ctx.gateway).await;
+    supervisor.stop_all().await;
+    let _ = fs::remove_file(sock_path);
+    stopped(&reason, started);
+    Ok(())
 }
 
+/// Final record of a run.
+fn stopped(reason: &str, started: Instant) {
+    let uptime = started.elapsed().as_secs();
+    logging::log(
+        Level::Info,
+        "shutdown",
+        format!("core-daemon stopped ({reason}, up {uptime}s)"),
+        json!({ "reason": reason, "uptime_secs": uptime }),
+    );
+}
+
+/// Identify the caller and check it against the access list; the user
+/// core-daemon runs as is always let in. Rejected callers get one error
+/// line before the connection is closed.
+fn admit(stream: &UnixStream, access: &peercred::AccessList) -> Option<Peer> {
+    let peer = match Peer::of(stream) {
+        Ok(peer) => peer,
+        Err(e) => {
+            logging::warn("core", format!("rejecting connection: cannot read peer credentials: {e}"));
+            return None;
+        }
+    };
+    if Some(peer.uid) != peercred::own_uid() && !access.allows(&peer) {
+        logging::log(Level::Warn, "core", format!("rejected connection from {peer}"), json!({ "peer": peer }));
+        let reply = json!({ "ok": false, "message": "permission denied: uid not allowed", "error": "permission_denied" });
+        // Best effort: the peer may already be gone.
+        let _ = stream.try_write(format!("{reply}\n").as_bytes());
+        return None;
+    }
+    logging::log(Level::Debug, "core", format!("connection from {peer}"), json!({ "peer": peer }));
+    Some(peer)
+}
+
+/// Serve one client until it disconnects or shutdown begins. A request
+/// already being handled is finished before the connection is closed.
+/// After a successful `subscribe` (or a following `service_logs`) the connection
+/// only carries the stream.
 async fn handle_connection(
     stream: UnixStream,
-    registry: Arc<Registry>,
-    auth: Arc<AuthService>,
-    plugin_mgr: Arc<PluginManager>,
+    peer: Peer,
+    ctx: Arc<Context>,
+    mut stop: watch::Receiver<bool>,
 ) -> Result<()> {
     let (r, mut w) = stream.into_split();
     let mut reader = BufReader::new(r).lines();
+    let conn = Connection::new(peer);
 
-    while let Some(line) = reader.next_line().await? {
+    loop {
+        let line = tokio::select! {
+            line = reader.next_line() => match line? {
+                Some(line) => line,
+                None => break,
+            },
+            _ = stop.changed() => break,
+        };
         if line.trim().is_empty() {
             continue;
         }
-        debug!("got request: {}", line);
-        let resp = handle_rpc(&line, registry.clone(), auth.clone(), plugin_mgr.clone()).await;
-        let resp_text = serde_json::to_string(&resp)?;
-        w.write_all(resp_text.as_bytes()).await?;
-        w.write_all(b"\n").await?;
+        debug!("got request from {}: {}", conn.peer, line);
+        let resp = jsonrpc::handle_line(&line, rpc::METHODS, |call| rpc::handle_call(call, &ctx, &conn)).await;
+        if let Some(resp_text) = resp {
+            w.write_all(resp_text.as_bytes()).await?;
+            w.write_all(b"\n").await?;
+        }
+
+        let stream = conn.stream.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(mut stream) = stream {
+            // Messages go out in the dialect the streaming call used.
+            let as_jsonrpc = serde_json::from_str::<Value>(&line).is_ok_and(|v| jsonrpc::is_jsonrpc(&v));
+            loop {
+                let out = tokio::select! {
+                    next = stream.next(as_jsonrpc) => match next {
+                        Some(out) => out,
+                        None => break,
+                    },
+                    // Anything the client sends is ignored; EOF ends the stream.
+                    line = reader.next_line() => match line? {
+                        Some(_) => continue,
+                        None => break,
+                    },
+                    _ = stop.changed() => break,
+                };
+                w.write_all(out.to_string().as_bytes()).await?;
+                w.write_all(b"\n").await?;
+            }
+            break;
+        }
     }
 
     Ok(())
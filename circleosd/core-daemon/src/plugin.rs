@@ -1,19 +0,0 @@
-use anyhow::Result;
-
-/// Lightweight plugin manager skeleton.
-/// Real world: use `libloading` for native plugins or `wasmtime` for WASM sandboxing.
-#[derive(Clone)]
-pub struct PluginManager {}
-
-impl PluginManager {
-    pub fn new() -> Self { Self {} }
-
-    /// Load a plugin from path. Minimal behavior: log and return Ok.
-    pub async fn load_plugin(&self, path: &str) -> Result<()> {
-        // TODO: real loading and capability checks
-        tracing::info!("(plugin-manager) load_plugin called for path: {}", path);
-        // Simulate small async work
-        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
-        Ok(())
-    }
-}
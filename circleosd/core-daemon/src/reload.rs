@@ -0,0 +1,318 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::config::Config;
+use crate::gateway::Backend;
+use crate::logging::{self, Level};
+use crate::rpc::Context;
+use crate::service_registry::Service;
+use crate::target;
+
+/// Outcome of applying circleosd.conf to the running daemon.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /// Changes now in effect, e.g. `core.log_level` or `services.web: added`.
+    pub applied: Vec<String>,
+    /// Keys that changed but only take effect after core-daemon restarts.
+    pub restart_required: Vec<String>,
+    /// Service and plugin changes that were attempted and failed.
+    pub errors: Vec<String>,
+}
+
+/// Bring the backends in line once they are up: re-register the services
+/// persisted from the last run, register the configured ones, load the
+/// preload plugins, then isolate the default target or, without one, start
+/// whatever was running when core-daemon stopped.
+pub async fn apply_boot_definitions(ctx: &Context) {
+    let mut preloaded = ctx.preloaded.lock().await;
+    let (services, plugins, default_target) = {
+        let cfg = ctx.config();
+        (cfg.services.clone(), cfg.preload_plugins.clone(), cfg.default_target.clone())
+    };
+    let persisted = ctx.registry.list().await;
+    let mut report = Report::default();
+
+    // Configured services take their spec from the config instead.
+    for svc in persisted.iter().filter(|s| !services.contains_key(&s.name)) {
+        let mut spec = svc.options.clone();
+        spec.insert("cmd".into(), json!(svc.cmd));
+        match register(ctx, &svc.name, &spec, true).await {
+            Ok(()) => report.applied.push(format!("services.{}: restored", svc.name)),
+            Err(e) => report.errors.push(format!("services.{}: {}", svc.name, e)),
+        }
+    }
+    sync_services(ctx, &BTreeMap::new(), &services, true, &mut report).await;
+    sync_plugins(ctx, &mut preloaded, &plugins, &mut report).await;
+    if let Some(name) = default_target {
+        match target::isolate_locked(ctx, &mut preloaded, &name).await {
+            Ok(isolated) => {
+                report.applied.extend(isolated.applied);
+                report.errors.extend(isolated.errors);
+            }
+            Err(e) => report.errors.push(format!("boot.target: {}", e)),
+        }
+    } else {
+        for svc in persisted.iter().filter(|s| s.running) {
+            match start(ctx, &svc.name).await {
+                Ok(()) => report.applied.push(format!("services.{}: started", svc.name)),
+                Err(e) => report.errors.push(format!("services.{}: {}", svc.name, e)),
+            }
+        }
+    }
+
+    for change in &report.applied {
+        logging::info("boot", change.clone());
+    }
+    for err in &report.errors {
+        logging::warn("boot", err.clone());
+    }
+}
+
+/// Re-read the configuration, apply what can change at runtime and report
+/// the rest. Nothing is applied if the new configuration is invalid.
+pub async fn reload(ctx: &Context, trigger: &str) -> Result<Report> {
+    let mut preloaded = ctx.preloaded.lock().await;
+    let new = match Config::load(&ctx.args) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            let e = anyhow::Error::from(e);
+            logging::log(
+                Level::Error,
+                "config",
+                format!("reload ({trigger}) rejected: {e:#}"),
+                json!({ "trigger": trigger }),
+            );
+            return Err(e);
+        }
+    };
+    let old = ctx.config().clone();
+    let mut report = Report::default();
+
+    let restart_only = [
+        ("core.socket", old.socket_path != new.socket_path),
+        ("core.log_dir", old.log_dir != new.log_dir),
+        ("core.var_dir", old.var_dir != new.var_dir),
+        ("core.bin_dir", old.bin_dir != new.bin_dir),
+        ("boot.services", old.boot_services != new.boot_services),
+        ("boot.timeout_secs", old.boot_timeout != new.boot_timeout),
+        ("boot.on_failure", old.on_boot_failure != new.on_boot_failure),
+        ("boot.target", old.default_target != new.default_target),
+    ];
+    report.restart_required = restart_only.iter().filter(|(_, changed)| *changed).map(|(k, _)| k.to_string()).collect();
+
+    let live = [
+        ("core.log_level", old.log_level != new.log_level),
+        ("core.drain_timeout_secs", old.drain_timeout != new.drain_timeout),
+        ("log", old.log_rotation != new.log_rotation),
+        ("gateway.timeout_ms", old.backend_timeouts != new.backend_timeouts),
+        ("authz", old.authz != new.authz),
+        ("access", old.access != new.access),
+        ("targets", old.targets != new.targets),
+    ];
+    report.applied = live.iter().filter(|(_, changed)| *changed).map(|(k, _)| k.to_string()).collect();
+
+    // Keep the restart-only settings as they are, so the running config
+    // always describes what is actually in effect.
+    let running = Config {
+        socket_path: old.socket_path.clone(),
+        log_dir: old.log_dir.clone(),
+        var_dir: old.var_dir.clone(),
+        bin_dir: old.bin_dir.clone(),
+        boot_services: old.boot_services.clone(),
+        boot_timeout: old.boot_timeout,
+        on_boot_failure: old.on_boot_failure,
+        default_target: old.default_target.clone(),
+        ..new
+    };
+    logging::reconfigure(&running);
+    ctx.gateway.set_timeouts(&running);
+    if old.access != running.access {
+        if let Err(e) = running.access.apply_to_socket(running.socket_path.as_ref()) {
+            report.errors.push(format!("access: failed to update {}: {}", running.socket_path, e));
+        }
+    }
+    let services = running.services.clone();
+    let plugins = target::wanted_plugins(ctx, &running.preload_plugins);
+    *ctx.config.write().unwrap_or_else(|e| e.into_inner()) = running;
+
+    sync_services(ctx, &old.services, &services, false, &mut report).await;
+    sync_plugins(ctx, &mut preloaded, &plugins, &mut report).await;
+
+    let level = if report.errors.is_empty() { Level::Info } else { Level::Warn };
+    logging::log(
+        level,
+        "config",
+        format!(
+            "reloaded ({trigger}): {} applied, {} need a restart, {} failed",
+            report.applied.len(),
+            report.restart_required.len(),
+            report.errors.len()
+        ),
+        json!({ "trigger": trigger, "report": report }),
+    );
+    ctx.events.publish("config.reloaded", json!({ "trigger": trigger, "report": report }));
+    Ok(report)
+}
+
+/// Bring service-registry from the `old` definitions to the `new` ones. A
+/// changed definition is re-registered, and restarted if it was running.
+async fn sync_services(
+    ctx: &Context,
+    old: &BTreeMap<String, Map<String, Value>>,
+    new: &BTreeMap<String, Map<String, Value>>,
+    at_boot: bool,
+    report: &mut Report,
+) {
+    for name in old.keys().filter(|n| !new.contains_key(*n)) {
+        match unregister(ctx, name).await {
+            Ok(()) => report.applied.push(format!("services.{}: removed", name)),
+            Err(e) => report.errors.push(format!("services.{}: {}", name, e)),
+        }
+    }
+
+    for (name, spec) in new {
+        let (verb, was_running) = match old.get(name) {
+            Some(prev) if prev == spec => continue,
+            Some(_) => {
+                let was_running = ctx.registry.get(name).await.is_some_and(|s| s.running);
+                if let Err(e) = unregister(ctx, name).await {
+                    report.errors.push(format!("services.{}: {}", name, e));
+                    continue;
+                }
+                ("updated", was_running)
+            }
+            None => ("added", false),
+        };
+        let res = match register(ctx, name, spec, at_boot).await {
+            Ok(()) if was_running => start(ctx, name).await,
+            other => other,
+        };
+        match res {
+            Ok(()) if was_running => report.applied.push(format!("services.{}: {} and restarted", name, verb)),
+            Ok(()) => report.applied.push(format!("services.{}: {}", name, verb)),
+            Err(e) => report.errors.push(format!("services.{}: {}", name, e)),
+        }
+    }
+}
+
+/// Register `name` with service-registry and record it. With `existing_ok`,
+/// a service-registry adopted at boot that still has the service is fine.
+async fn register(ctx: &Context, name: &str, spec: &Map<String, Value>, existing_ok: bool) -> Result<(), String> {
+    let mut full = spec.clone();
+    full.insert("name".into(), json!(name));
+    let req = json!({ "action": "register", "spec": full });
+    let reply = ctx.gateway.registry.call(&req).await.map_err(|e| e.to_string())?;
+    let adopted = existing_ok && reply.pointer("/data/code").and_then(Value::as_str) == Some("exists");
+    if !(is_ok(&reply) || adopted) {
+        return Err(refused(&reply, "register"));
+    }
+    let mut options = spec.clone();
+    let cmd = options.remove("cmd").and_then(|c| serde_json::from_value(c).ok()).unwrap_or_default();
+    // Keep the recorded running state; it is what gets restored at boot.
+    let running = ctx.registry.get(name).await.is_some_and(|s| s.running);
+    ctx.registry
+        .register(Service { name: name.to_string(), cmd, running, options })
+        .await
+        .map_err(|e| format!("failed to record: {:#}", e))?;
+    ctx.events.publish("service.registered", json!({ "name": name }));
+    Ok(())
+}
+
+async fn unregister(ctx: &Context, name: &str) -> Result<(), String> {
+    call(&ctx.gateway.registry, json!({ "action": "unregister", "name": name }), "unregister").await?;
+    ctx.registry.unregister(name).await.map_err(|e| format!("failed to record: {:#}", e))?;
+    ctx.events.publish("service.unregistered", json!({ "name": name }));
+    Ok(())
+}
+
+pub async fn start(ctx: &Context, name: &str) -> Result<(), String> {
+    // recorded as a boot step when this happens during boot
+    let span = ctx.timeline.begin(format!("services.{}", name), "service", &["service-registry"]);
+    let res = call(&ctx.gateway.registry, json!({ "action": "start", "name": name }), "start").await;
+    ctx.timeline.end(span, if res.is_ok() { "started" } else { "failed" });
+    let reply = res?;
+    record_affected(ctx, name, reply.get("data"), true).await.map_err(|e| format!("failed to record: {:#}", e))
+}
+
+pub async fn stop(ctx: &Context, name: &str) -> Result<(), String> {
+    let reply = call(&ctx.gateway.registry, json!({ "action": "stop", "name": name }), "stop").await?;
+    record_affected(ctx, name, reply.get("data"), false).await.map_err(|e| format!("failed to record: {:#}", e))
+}
+
+/// Record `name` as running or not, along with the services service-registry
+/// says it started first (`data.started`) or stopped first (`data.stopped`)
+/// because of dependencies, and publish an event for each.
+pub async fn record_affected(ctx: &Context, name: &str, data: Option<&Value>, running: bool) -> anyhow::Result<()> {
+    let (key, event) = if running { ("started", "service.started") } else { ("stopped", "service.stopped") };
+    let mut names: Vec<&str> = data
+        .and_then(|d| d.get(key))
+        .and_then(Value::as_array)
+        .map(|list| list.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    // a template (`worker@`) has no process of its own to record
+    if !names.contains(&name) && !name.ends_with('@') {
+        names.push(name);
+    }
+    let mut res = Ok(());
+    for svc in names {
+        if let Err(e) = ctx.registry.set_running(svc, running).await {
+            res = Err(e);
+        }
+        ctx.events.publish(event, json!({ "name": svc }));
+    }
+    res
+}
+
+/// Load the `wanted` plugins not loaded yet and unload those no longer wanted.
+pub async fn sync_plugins(ctx: &Context, loaded: &mut HashMap<String, String>, wanted: &[String], report: &mut Report) {
+    let gone: Vec<String> = loaded.keys().filter(|p| !wanted.contains(p)).cloned().collect();
+    for path in gone {
+        let id = loaded[&path].clone();
+        match call(&ctx.gateway.plugins, json!({ "action": "unload", "id": id }), "unload").await {
+            Ok(_) => {
+                loaded.remove(&path);
+                ctx.events.publish("plugin.unloaded", json!({ "id": id }));
+                report.applied.push(format!("plugins: unloaded {}", path));
+            }
+            Err(e) => report.errors.push(format!("plugins: {}: {}", path, e)),
+        }
+    }
+
+    let new: Vec<&String> = wanted.iter().filter(|p| !loaded.contains_key(*p)).collect();
+    for path in new {
+        let req = json!({ "action": "load", "path": path, "manifest": null });
+        let span = ctx.timeline.begin(format!("plugins.{}", path), "plugin", &["plugin-manager"]);
+        let res = call(&ctx.gateway.plugins, req, "load").await;
+        ctx.timeline.end(span, if res.is_ok() { "loaded" } else { "failed" });
+        match res {
+            Ok(reply) => {
+                let id = reply.pointer("/data/id").and_then(Value::as_str).unwrap_or_default().to_string();
+                ctx.events.publish("plugin.loaded", json!({ "id": id, "path": path }));
+                loaded.insert(path.clone(), id);
+                report.applied.push(format!("plugins: loaded {}", path));
+            }
+            Err(e) => report.errors.push(format!("plugins: {}: {}", path, e)),
+        }
+    }
+}
+
+/// Call a backend and turn a refusal into its message.
+pub async fn call(backend: &Backend, req: Value, what: &str) -> Result<Value, String> {
+    let reply = backend.call(&req).await.map_err(|e| e.to_string())?;
+    if is_ok(&reply) {
+        Ok(reply)
+    } else {
+        Err(refused(&reply, what))
+    }
+}
+
+fn is_ok(reply: &Value) -> bool {
+    reply.get("ok").and_then(Value::as_bool) == Some(true)
+}
+
+fn refused(reply: &Value, what: &str) -> String {
+    let msg = reply.get("message").and_then(Value::as_str).unwrap_or("refused");
+    format!("{} failed: {}", what, msg)
+}
@@ -1,35 +1,295 @@
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
 
+use crate::config::{CliArgs, Config, Target};
+use crate::events::{EventBus, Next, Subscription};
+use crate::gateway::{Backend, BackendStream, Gateway, GatewayError};
+use circleosd_proto::jsonrpc::{self, RpcError, RpcReply};
+use crate::logging::{self, Level, Query};
+use circleosd_proto::peercred::Peer;
+use crate::reload;
 use crate::service_registry::{Registry, Service};
-use crate::auth::AuthService;
-use crate::plugin::PluginManager;
+use crate::target;
+use crate::timing::Timeline;
+
+/// State shared by every RPC connection.
+pub struct Context {
+    pub registry: Registry,
+    pub gateway: Gateway,
+    pub events: Arc<EventBus>,
+    /// Configuration in effect; a reload swaps in the parts it can apply live.
+    pub config: RwLock<Config>,
+    /// Flags core-daemon was started with, layered on top of every reload.
+    pub args: CliArgs,
+    /// Plugin-manager ids of the `boot.plugins` entries, by path. Held for
+    /// the whole of a reload so that two reloads never interleave.
+    pub preloaded: tokio::sync::Mutex<HashMap<String, String>>,
+    /// Target last isolated, as it was defined at the time.
+    pub target: RwLock<Option<(String, Target)>>,
+    /// How boot went, step by step.
+    pub timeline: Arc<Timeline>,
+}
+
+impl Context {
+    pub fn config(&self) -> RwLockReadGuard<'_, Config> {
+        self.config.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Per-connection state handed to every request on that connection.
+pub struct Connection {
+    /// Who is on the other end of the socket.
+    pub peer: Peer,
+    /// Where `subscribe` and a following `service_logs` leave what the
+    /// connection streams from then on.
+    pub stream: Mutex<Option<Stream>>,
+}
+
+/// What a connection carries once a streaming request succeeded.
+pub enum Stream {
+    Events(Subscription),
+    /// Lines relayed from a backend, e.g. a service's output.
+    Backend(BackendStream),
+}
+
+impl Stream {
+    /// Next message for the client, in the dialect the stream was opened
+    /// with; `None` once the stream is over.
+    pub async fn next(&mut self, as_jsonrpc: bool) -> Option<Value> {
+        match self {
+            Stream::Events(sub) => Some(match sub.next().await {
+                Next::Event(event) if as_jsonrpc => json!({ "jsonrpc": "2.0", "method": "event", "params": event }),
+                Next::Event(event) => json!(event),
+                Next::Lagged(n) if as_jsonrpc => json!({ "jsonrpc": "2.0", "method": "lagged", "params": { "missed": n } }),
+                Next::Lagged(n) => json!({ "lagged": n }),
+                Next::Closed => return None,
+            }),
+            Stream::Backend(lines) => {
+                let line = lines.next().await?;
+                Some(if as_jsonrpc { json!({ "jsonrpc": "2.0", "method": "log", "params": line }) } else { line })
+            }
+        }
+    }
+}
+
+impl Connection {
+    pub fn new(peer: Peer) -> Self {
+        Self { peer, stream: Mutex::new(None) }
+    }
+}
+
+/// One request line: the action plus the caller's session token, if any.
+#[derive(Debug, Deserialize)]
+pub struct Call {
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(flatten)]
+    pub request: RpcRequest,
+}
 
 /// Possible RPC requests (simple flattened protocol)
 #[derive(Debug, Deserialize)]
 #[serde(tag = "action")]
 pub enum RpcRequest {
+    #[serde(rename = "ping")]
+    Ping {},
+
     #[serde(rename = "create_user")]
     CreateUser { username: String, password: String },
 
     #[serde(rename = "auth")]
     Auth { username: String, password: String },
 
+    /// Any extra fields are passed through as part of the service spec.
     #[serde(rename = "register_service")]
-    RegisterService { name: String, cmd: Vec<String> },
+    RegisterService {
+        name: String,
+        cmd: Vec<String>,
+        #[serde(flatten)]
+        options: Map<String, Value>,
+    },
+
+    #[serde(rename = "unregister_service")]
+    UnregisterService { name: String },
 
     #[serde(rename = "list_services")]
     ListServices {},
 
+    #[serde(rename = "service_status")]
+    ServiceStatus { name: String },
+
     #[serde(rename = "start_service")]
     StartService { name: String },
 
     #[serde(rename = "stop_service")]
     StopService { name: String },
 
+    /// Let a service that crash-looped into the failed state start again.
+    #[serde(rename = "reset_failed_service")]
+    ResetFailedService { name: String },
+
+    /// Reconcile service-registry's declarative services with `services`, or
+    /// with its services file when omitted; `dry_run` only returns the plan.
+    #[serde(rename = "apply_services")]
+    ApplyServices {
+        services: Option<Vec<Value>>,
+        #[serde(default)]
+        dry_run: bool,
+    },
+
+    /// Run `replicas` numbered instances of a template service (`worker@`).
+    #[serde(rename = "scale_service")]
+    ScaleService { name: String, replicas: u32 },
+
+    /// Timer-triggered services with their next and last trigger.
+    #[serde(rename = "list_timers")]
+    ListTimers {},
+
+    /// Dependency tree of a service; with `reverse`, what depends on it.
+    #[serde(rename = "service_deps")]
+    ServiceDeps {
+        name: String,
+        #[serde(default)]
+        reverse: bool,
+    },
+
+    /// Recent stdout/stderr of a service; with `follow` the connection then
+    /// streams new lines as they are written.
+    #[serde(rename = "service_logs")]
+    ServiceLogs {
+        name: String,
+        lines: Option<usize>,
+        #[serde(default)]
+        follow: bool,
+    },
+
     #[serde(rename = "load_plugin")]
-    LoadPlugin { path: String },
+    LoadPlugin {
+        path: String,
+        #[serde(default)]
+        manifest: Option<String>,
+    },
+
+    #[serde(rename = "unload_plugin")]
+    UnloadPlugin { id: String },
+
+    #[serde(rename = "list_plugins")]
+    ListPlugins {},
+
+    /// Switch the connection to a stream of events whose topic matches one
+    /// of `topics` (default: all), replaying retained events after `since`.
+    #[serde(rename = "subscribe")]
+    Subscribe {
+        #[serde(default)]
+        topics: Vec<String>,
+        since: Option<u64>,
+    },
+
+    /// Used by the services core-daemon spawns to report their own events.
+    #[serde(rename = "publish")]
+    Publish {
+        topic: String,
+        #[serde(default)]
+        data: Value,
+    },
+
+    /// Search circleosd.log; `since` / `until` are RFC 3339 timestamps.
+    #[serde(rename = "query_logs")]
+    QueryLogs {
+        component: Option<String>,
+        level: Option<Level>,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        text: Option<String>,
+        limit: Option<usize>,
+    },
+
+    /// Re-read circleosd.conf, same as SIGHUP.
+    #[serde(rename = "reload_config")]
+    ReloadConfig {},
+
+    #[serde(rename = "list_targets")]
+    ListTargets {},
+
+    /// Start the target's services and stop all others.
+    #[serde(rename = "isolate_target")]
+    IsolateTarget { target: String },
+
+    /// Start and ready times of every boot step.
+    #[serde(rename = "boot_timing")]
+    BootTiming {},
+}
+
+impl RpcRequest {
+    /// Permission needed to run this action; `None` for actions open to anyone.
+    pub fn permission(&self) -> Option<&'static str> {
+        Some(match self {
+            RpcRequest::Ping {} | RpcRequest::Auth { .. } => return None,
+            RpcRequest::CreateUser { .. } => "user.create",
+            RpcRequest::RegisterService { .. } | RpcRequest::ApplyServices { .. } => "service.register",
+            RpcRequest::UnregisterService { .. } => "service.unregister",
+            RpcRequest::ListServices {} | RpcRequest::ListTimers {} => "service.list",
+            RpcRequest::ServiceStatus { .. } | RpcRequest::ServiceDeps { .. } => "service.status",
+            RpcRequest::StartService { .. } | RpcRequest::ScaleService { .. } => "service.start",
+            RpcRequest::StopService { .. } => "service.stop",
+            RpcRequest::ServiceLogs { .. } => "service.logs",
+            RpcRequest::ResetFailedService { .. } => "service.reset",
+            RpcRequest::LoadPlugin { .. } => "plugin.load",
+            RpcRequest::UnloadPlugin { .. } => "plugin.unload",
+            RpcRequest::ListPlugins {} => "plugin.list",
+            RpcRequest::QueryLogs { .. } => "log.read",
+            RpcRequest::Subscribe { .. } => "event.subscribe",
+            RpcRequest::Publish { .. } => "event.publish",
+            RpcRequest::ReloadConfig {} => "config.reload",
+            RpcRequest::ListTargets {} => "target.list",
+            RpcRequest::IsolateTarget { .. } => "target.isolate",
+            RpcRequest::BootTiming {} => "boot.read",
+        })
+    }
+}
+
+/// Action names, also accepted as JSON-RPC 2.0 method names.
+pub const METHODS: &[&str] = &[
+    "ping", "create_user", "auth",
+    "register_service", "unregister_service", "list_services", "service_status", "start_service", "stop_service",
+    "service_logs", "reset_failed_service", "apply_services", "service_deps", "scale_service", "list_timers",
+    "load_plugin", "unload_plugin", "list_plugins",
+    "query_logs", "subscribe", "publish", "reload_config",
+    "list_targets", "isolate_target", "boot_timing",
+];
+
+/// Machine-readable failure reason, set when `ok` is false.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    /// The backend socket could not be reached (not running or crashed).
+    BackendUnavailable,
+    BackendTimeout,
+    /// The backend answered with something that is not a valid reply.
+    BackendError,
+    /// No session token, or auth-service did not recognise it.
+    Unauthenticated,
+    /// The caller's roles do not grant the action's permission.
+    PermissionDenied,
+}
+
+impl ErrorCode {
+    /// JSON-RPC error code, in the implementation-defined server range.
+    pub fn jsonrpc_code(self) -> i64 {
+        match self {
+            ErrorCode::InvalidRequest => jsonrpc::INVALID_PARAMS,
+            ErrorCode::BackendUnavailable => -32001,
+            ErrorCode::BackendTimeout => -32002,
+            ErrorCode::BackendError => -32003,
+            ErrorCode::Unauthenticated => -32010,
+            ErrorCode::PermissionDenied => -32011,
+        }
+    }
 }
 
 /// Generic RPC response
@@ -39,77 +299,304 @@ pub struct RpcResponse {
     pub message: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorCode>,
 }
 
 impl RpcResponse {
-    pub fn ok() -> Self { Self { ok: true, message: None, data: None } }
-    pub fn err(msg: &str) -> Self { Self { ok: false, message: Some(msg.to_string()), data: None } }
-}
-
-/// Handle a single JSON request text and return a response object.
-pub async fn handle_rpc(
-    req_text: &str,
-    registry: Arc<Registry>,
-    auth: Arc<AuthService>,
-    plugin_mgr: Arc<PluginManager>,
-) -> RpcResponse {
-    let parsed: Result<RpcRequest, _> = serde_json::from_str(req_text);
-    match parsed {
+    pub fn err(msg: &str) -> Self { Self { ok: false, message: Some(msg.to_string()), data: None, error: None } }
+    pub fn fail(code: ErrorCode, msg: &str) -> Self {
+        Self { ok: false, message: Some(msg.to_string()), data: None, error: Some(code) }
+    }
+
+    /// Wrap a backend's `{ok, message, data}` reply.
+    fn from_backend(v: Value) -> Self {
+        Self {
+            ok: v.get("ok").and_then(Value::as_bool).unwrap_or(false),
+            message: v.get("message").and_then(Value::as_str).map(str::to_string),
+            data: v.get("data").cloned(),
+            error: None,
+        }
+    }
+}
+
+impl RpcReply for RpcResponse {
+    fn invalid(message: String) -> Self {
+        RpcResponse::fail(ErrorCode::InvalidRequest, &message)
+    }
+
+    fn into_jsonrpc(self) -> Result<Value, RpcError> {
+        let code = self.error.map(ErrorCode::jsonrpc_code).unwrap_or(jsonrpc::SERVER_ERROR);
+        jsonrpc::split_reply(self.ok, self.message, self.data, code)
+    }
+}
+
+impl From<GatewayError> for RpcResponse {
+    fn from(e: GatewayError) -> Self {
+        let code = match e {
+            GatewayError::Unavailable { .. } => ErrorCode::BackendUnavailable,
+            GatewayError::Timeout { .. } => ErrorCode::BackendTimeout,
+            GatewayError::BadReply { .. } => ErrorCode::BackendError,
+        };
+        RpcResponse::fail(code, &e.to_string())
+    }
+}
+
+/// Forward a request to a backend and wrap its reply.
+async fn forward(backend: &Backend, req: Value) -> RpcResponse {
+    match backend.call(&req).await {
+        Ok(v) => RpcResponse::from_backend(v),
         Err(e) => {
-            return RpcResponse::err(&format!("invalid request: {}", e));
+            tracing::warn!("gateway: {}", e);
+            e.into()
         }
-        Ok(req) => {
-            match req {
-                RpcRequest::CreateUser { username, password } => {
-                    if let Err(e) = auth.create_user(&username, &password).await {
-                        return RpcResponse::err(&format!("create_user failed: {}", e));
-                    }
-                    RpcResponse { ok: true, message: Some("user created".into()), data: None }
-                }
+    }
+}
 
-                RpcRequest::Auth { username, password } => {
-                    match auth.verify_password(&username, &password).await {
-                        Ok(true) => RpcResponse { ok: true, message: Some("authenticated".into()), data: None },
-                        Ok(false) => RpcResponse::err("invalid username/password"),
-                        Err(e) => RpcResponse::err(&format!("auth error: {}", e)),
-                    }
-                }
+/// Check the caller's permission, then handle the request.
+pub async fn handle_call(call: Call, ctx: &Context, conn: &Connection) -> RpcResponse {
+    if let Some(perm) = call.request.permission() {
+        if let Err(denied) = authorize(ctx, &conn.peer, call.token.as_deref(), perm).await {
+            return denied;
+        }
+    }
+    handle_rpc(call.request, ctx, conn).await
+}
 
-                RpcRequest::RegisterService { name, cmd } => {
-                    let svc = Service { name: name.clone(), cmd, running: false };
-                    if let Err(e) = registry.register(svc).await {
-                        return RpcResponse::err(&format!("register_service failed: {}", e));
-                    }
-                    RpcResponse { ok: true, message: Some(format!("registered {}", name)), data: None }
-                }
+/// Resolve the session token through auth-service and look the user up in the policy.
+async fn authorize(ctx: &Context, peer: &Peer, token: Option<&str>, perm: &str) -> Result<(), RpcResponse> {
+    let deny = |code, who: &str, reason: &str| deny(ctx, peer, code, who, perm, reason);
+    let token = match token {
+        Some(t) => t,
+        None if ctx.config().authz.allows_anonymous(perm) => return Ok(()),
+        None => return Err(deny(ErrorCode::Unauthenticated, "anonymous", "session token required")),
+    };
+    // Services spawned by core-daemon hold a token that may only publish.
+    if token == ctx.events.publisher_token() {
+        return match perm {
+            "event.publish" => Ok(()),
+            _ => Err(deny(ErrorCode::PermissionDenied, "service", "the publisher token may only publish")),
+        };
+    }
 
-                RpcRequest::ListServices {} => {
-                    let services = registry.list().await;
-                    let data = serde_json::to_value(services).ok();
-                    RpcResponse { ok: true, message: None, data }
-                }
+    let reply = ctx.gateway.auth.call(&json!({ "action": "whoami", "token": token })).await?;
+    let user = match reply.pointer("/data/username").and_then(Value::as_str) {
+        Some(u) if reply.get("ok").and_then(Value::as_bool) == Some(true) => u,
+        _ => return Err(deny(ErrorCode::Unauthenticated, "?", "invalid or expired session token")),
+    };
 
-                RpcRequest::StartService { name } => {
-                    if let Err(e) = registry.start(&name).await {
-                        return RpcResponse::err(&format!("start_service failed: {}", e));
-                    }
-                    RpcResponse::ok()
-                }
+    let allowed = ctx.config().authz.allows(user, perm);
+    if allowed {
+        Ok(())
+    } else {
+        Err(deny(ErrorCode::PermissionDenied, user, &format!("{} may not {}", user, perm)))
+    }
+}
 
-                RpcRequest::StopService { name } => {
-                    if let Err(e) = registry.stop(&name).await {
-                        return RpcResponse::err(&format!("stop_service failed: {}", e));
-                    }
-                    RpcResponse::ok()
+fn deny(ctx: &Context, peer: &Peer, code: ErrorCode, who: &str, perm: &str, reason: &str) -> RpcResponse {
+    let fields = json!({ "user": who, "permission": perm, "code": code, "peer": peer });
+    logging::log(Level::Warn, "authz", format!("denied {} to {} ({}): {}", perm, who, peer, reason), fields.clone());
+    ctx.events.publish("authz.denied", fields);
+    RpcResponse::fail(code, &format!("permission denied: {}", reason))
+}
+
+/// Handle a single authorized request and return a response object.
+async fn handle_rpc(req: RpcRequest, ctx: &Context, conn: &Connection) -> RpcResponse {
+    let Context { registry, gateway, events, .. } = ctx;
+    match req {
+        RpcRequest::Ping {} => RpcResponse { ok: true, message: Some("pong".into()), data: None, error: None },
+
+        RpcRequest::CreateUser { username, password } => {
+            let req = json!({ "action": "create_user", "username": username, "password": password });
+            let resp = forward(&gateway.auth, req).await;
+            if resp.ok {
+                events.publish("user.created", json!({ "username": username }));
+            }
+            resp
+        }
+
+        RpcRequest::Auth { username, password } => {
+            let req = json!({ "action": "login", "username": username, "password": password });
+            let resp = forward(&gateway.auth, req).await;
+            if resp.ok {
+                events.publish("user.login", json!({ "username": username, "peer": conn.peer }));
+            } else if resp.error.is_none() {
+                events.publish("user.login_failed", json!({ "username": username, "peer": conn.peer }));
+            }
+            resp
+        }
+
+        RpcRequest::RegisterService { name, cmd, options } => {
+            let mut spec = options.clone();
+            spec.insert("name".into(), json!(name));
+            spec.insert("cmd".into(), json!(cmd));
+            let resp = forward(&gateway.registry, json!({ "action": "register", "spec": spec })).await;
+            if resp.ok {
+                events.publish("service.registered", json!({ "name": name }));
+                let svc = Service { name, cmd, running: false, options };
+                if let Err(e) = registry.register(svc).await {
+                    return RpcResponse::err(&format!("register_service failed: {}", e));
                 }
+            }
+            resp
+        }
+
+        RpcRequest::UnregisterService { name } => {
+            let resp = forward(&gateway.registry, json!({ "action": "unregister", "name": name })).await;
+            if resp.ok {
+                events.publish("service.unregistered", json!({ "name": name }));
+                record(registry.unregister(&name).await, resp)
+            } else {
+                resp
+            }
+        }
+
+        RpcRequest::ListServices {} => forward(&gateway.registry, json!({ "action": "list" })).await,
+
+        RpcRequest::ServiceStatus { name } => {
+            forward(&gateway.registry, json!({ "action": "status", "name": name })).await
+        }
 
-                RpcRequest::LoadPlugin { path } => {
-                    if let Err(e) = plugin_mgr.load_plugin(&path).await {
-                        return RpcResponse::err(&format!("load_plugin failed: {}", e));
+        RpcRequest::StartService { name } => {
+            let resp = forward(&gateway.registry, json!({ "action": "start", "name": name })).await;
+            if resp.ok {
+                record(reload::record_affected(ctx, &name, resp.data.as_ref(), true).await, resp)
+            } else {
+                resp
+            }
+        }
+
+        RpcRequest::StopService { name } => {
+            let resp = forward(&gateway.registry, json!({ "action": "stop", "name": name })).await;
+            if resp.ok {
+                record(reload::record_affected(ctx, &name, resp.data.as_ref(), false).await, resp)
+            } else {
+                resp
+            }
+        }
+
+        RpcRequest::ResetFailedService { name } => {
+            forward(&gateway.registry, json!({ "action": "reset_failed", "name": name })).await
+        }
+
+        RpcRequest::ApplyServices { services, dry_run } => {
+            forward(&gateway.registry, json!({ "action": "apply", "services": services, "dry_run": dry_run })).await
+        }
+
+        RpcRequest::ScaleService { name, replicas } => {
+            let resp = forward(&gateway.registry, json!({ "action": "scale", "name": name, "replicas": replicas })).await;
+            let data = resp.data.as_ref();
+            // partly done even when not ok, so recorded either way
+            let started = reload::record_affected(ctx, &name, data, true).await;
+            let stopped = reload::record_affected(ctx, &name, data, false).await;
+            record(started.and(stopped), resp)
+        }
+
+        RpcRequest::ListTimers {} => forward(&gateway.registry, json!({ "action": "list_timers" })).await,
+
+        RpcRequest::ServiceDeps { name, reverse } => {
+            forward(&gateway.registry, json!({ "action": "deps", "name": name, "reverse": reverse })).await
+        }
+
+        RpcRequest::ServiceLogs { name, lines, follow: false } => {
+            forward(&gateway.registry, json!({ "action": "logs", "name": name, "lines": lines })).await
+        }
+
+        RpcRequest::ServiceLogs { name, lines, follow: true } => {
+            let req = json!({ "action": "follow", "name": name, "lines": lines });
+            match gateway.registry.open_stream(&req).await {
+                Ok((reply, lines)) => {
+                    let resp = RpcResponse::from_backend(reply);
+                    if resp.ok {
+                        *conn.stream.lock().unwrap_or_else(|e| e.into_inner()) = Some(Stream::Backend(lines));
                     }
-                    RpcResponse::ok()
+                    resp
+                }
+                Err(e) => {
+                    tracing::warn!("gateway: {}", e);
+                    e.into()
                 }
             }
         }
+
+        RpcRequest::LoadPlugin { path, manifest } => {
+            let req = json!({ "action": "load", "path": path, "manifest": manifest });
+            let resp = forward(&gateway.plugins, req).await;
+            if resp.ok {
+                let id = resp.data.as_ref().and_then(|d| d.get("id")).cloned().unwrap_or(Value::Null);
+                events.publish("plugin.loaded", json!({ "id": id, "path": path }));
+            }
+            resp
+        }
+
+        RpcRequest::UnloadPlugin { id } => {
+            let resp = forward(&gateway.plugins, json!({ "action": "unload", "id": id })).await;
+            if resp.ok {
+                events.publish("plugin.unloaded", json!({ "id": id }));
+            }
+            resp
+        }
+
+        RpcRequest::ListPlugins {} => forward(&gateway.plugins, json!({ "action": "list" })).await,
+
+        RpcRequest::Subscribe { mut topics, since } => {
+            if topics.is_empty() {
+                topics.push("*".into());
+            }
+            let sub = events.subscribe(topics, since);
+            let data = json!({ "next_seq": sub.next_seq, "oldest": sub.oldest });
+            *conn.stream.lock().unwrap_or_else(|e| e.into_inner()) = Some(Stream::Events(sub));
+            RpcResponse { ok: true, message: Some("subscribed".into()), data: Some(data), error: None }
+        }
+
+        RpcRequest::Publish { topic, data } => {
+            if topic.is_empty() || topic.contains('*') {
+                return RpcResponse::fail(ErrorCode::InvalidRequest, "topic must be a plain dotted name");
+            }
+            let seq = events.publish(&topic, data);
+            RpcResponse { ok: true, message: None, data: Some(json!({ "seq": seq })), error: None }
+        }
+
+        RpcRequest::QueryLogs { component, level, since, until, text, limit } => {
+            let q = Query { component, level, since, until, text, limit };
+            match tokio::task::spawn_blocking(move || logging::query(&q)).await {
+                Ok(Ok(records)) => RpcResponse { ok: true, message: None, data: Some(json!(records)), error: None },
+                Ok(Err(e)) => RpcResponse::err(&format!("failed to read logs: {}", e)),
+                Err(e) => RpcResponse::err(&format!("log query task failed: {}", e)),
+            }
+        }
+
+        RpcRequest::ReloadConfig {} => match reload::reload(ctx, "reload_config").await {
+            Ok(report) => RpcResponse { ok: true, message: Some("reloaded".into()), data: Some(json!(report)), error: None },
+            Err(e) => RpcResponse::err(&format!("reload failed: {:#}", e)),
+        },
+
+        RpcRequest::ListTargets {} => {
+            let active = ctx.target.read().unwrap_or_else(|e| e.into_inner()).as_ref().map(|(name, _)| name.clone());
+            let cfg = ctx.config();
+            let data = json!({ "targets": cfg.targets, "default": cfg.default_target, "active": active });
+            RpcResponse { ok: true, message: None, data: Some(data), error: None }
+        }
+
+        RpcRequest::IsolateTarget { target } => match target::isolate(ctx, &target).await {
+            Ok(report) => {
+                let msg = if report.errors.is_empty() { "isolated" } else { "isolated with errors" };
+                RpcResponse { ok: true, message: Some(msg.into()), data: Some(json!(report)), error: None }
+            }
+            Err(e) => RpcResponse::fail(ErrorCode::InvalidRequest, &e),
+        },
+
+        RpcRequest::BootTiming {} => {
+            let data = json!(ctx.timeline.snapshot());
+            RpcResponse { ok: true, message: None, data: Some(data), error: None }
+        }
+    }
+}
+
+/// Keep the backend reply unless updating our own record failed.
+fn record(res: Result<()>, resp: RpcResponse) -> RpcResponse {
+    match res {
+        Ok(()) => resp,
+        Err(e) => RpcResponse::err(&format!("failed to record service state: {}", e)),
     }
 }
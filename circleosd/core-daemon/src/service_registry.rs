@@ -1,64 +1,122 @@
-use anyhow::Result;
-use std::collections::HashMap;
+use anyhow::{Context as _, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
 use tokio::sync::Mutex;
 use std::sync::Arc;
 use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
 
+use crate::logging;
+
+/// A service registered through core-daemon, as last seen by the gateway.
+/// The service-registry process owns the live state; this is core-daemon's record
+/// of what was registered and whether it was asked to run.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Service {
     pub name: String,
     pub cmd: Vec<String>,
     pub running: bool,
+    /// Remaining spec fields (restart policy, env, ...) passed through to service-registry.
+    #[serde(default, flatten)]
+    pub options: Map<String, Value>,
+}
+
+/// On-disk layout of the state file.
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    services: Vec<Service>,
 }
 
 pub struct Registry {
     services: Arc<Mutex<HashMap<String, Service>>>,
+    /// JSON file every change is written to.
+    path: PathBuf,
 }
 
 impl Registry {
-    pub fn new() -> Self {
-        Self {
-            services: Arc::new(Mutex::new(HashMap::new())),
-        }
+    /// Load the registry persisted at `path`. A missing file is an empty
+    /// registry; an unreadable one is moved aside so the next save does not
+    /// overwrite it.
+    pub fn open(path: PathBuf) -> Self {
+        let services = match load(&path) {
+            Ok(state) => state.services.into_iter().map(|s| (s.name.clone(), s)).collect(),
+            Err(e) => {
+                let aside = path.with_extension("json.corrupt");
+                logging::error(
+                    "core",
+                    format!("{:#}; moving it to {} and starting empty", e, aside.display()),
+                );
+                let _ = std::fs::rename(&path, aside);
+                HashMap::new()
+            }
+        };
+        Self { services: Arc::new(Mutex::new(services)), path }
     }
 
-    /// Background loop for health checks / restarts (very simple placeholder)
-    pub async fn run(self: Arc<Self>) {
-        loop {
-            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-            // Placeholder: check health, restart crashed services, etc.
-            // For now it's a no-op.
-        }
+    pub async fn register(&self, s: Service) -> Result<()> {
+        let mut map = self.services.lock().await;
+        map.insert(s.name.clone(), s);
+        self.save(&map).await
     }
 
-    pub async fn register(&self, s: Service) -> Result<()> {
-        self.services.lock().await.insert(s.name.clone(), s);
+    pub async fn unregister(&self, name: &str) -> Result<()> {
+        let mut map = self.services.lock().await;
+        if map.remove(name).is_some() {
+            self.save(&map).await?;
+        }
         Ok(())
     }
 
+    pub async fn get(&self, name: &str) -> Option<Service> {
+        self.services.lock().await.get(name).cloned()
+    }
+
     pub async fn list(&self) -> Vec<Service> {
         self.services.lock().await.values().cloned().collect()
     }
 
-    /// Start a service by name. Minimal - marks running = true.
-    pub async fn start(&self, name: &str) -> Result<()> {
+    /// Record whether a service was last started or stopped.
+    /// Services registered directly with service-registry are not tracked here.
+    pub async fn set_running(&self, name: &str, running: bool) -> Result<()> {
         let mut map = self.services.lock().await;
-        if let Some(s) = map.get_mut(name) {
-            if s.running {
-                return Ok(())
-            }
-            // TODO: spawn real process via Command; currently simulated
-            s.running = true;
+        match map.get_mut(name) {
+            Some(s) if s.running != running => s.running = running,
+            _ => return Ok(()),
         }
-        Ok(())
+        self.save(&map).await
     }
 
-    /// Stop a service by name. Minimal - marks running = false.
-    pub async fn stop(&self, name: &str) -> Result<()> {
-        let mut map = self.services.lock().await;
-        if let Some(s) = map.get_mut(name) {
-            s.running = false;
+    /// Write the whole registry to a temporary file and rename it over the
+    /// old one, so a crash never leaves a half-written file behind. Called
+    /// with the map locked, which also keeps saves in order.
+    async fn save(&self, map: &HashMap<String, Service>) -> Result<()> {
+        let path = &self.path;
+        let sorted: BTreeMap<&String, &Service> = map.iter().collect();
+        let state = State { services: sorted.into_values().cloned().collect() };
+        let json = serde_json::to_vec_pretty(&state)?;
+
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
         }
+        let tmp = path.with_extension("json.tmp");
+        let mut file = tokio::fs::File::create(&tmp)
+            .await
+            .with_context(|| format!("failed to create {}", tmp.display()))?;
+        file.write_all(&json).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, path)
+            .await
+            .with_context(|| format!("failed to replace {}", path.display()))?;
         Ok(())
     }
 }
+
+fn load(path: &Path) -> Result<State> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(State::default()),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    serde_json::from_str(&text).with_context(|| format!("failed to parse {}", path.display()))
+}
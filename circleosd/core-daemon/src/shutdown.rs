@@ -0,0 +1,57 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+use crate::gateway::Gateway;
+use crate::logging;
+
+/// SIGTERM / SIGINT listeners. Created before boot so that a signal
+/// arriving early is handled by us instead of killing the process and
+/// orphaning its children.
+pub struct Signals {
+    term: Signal,
+    int: Signal,
+}
+
+impl Signals {
+    pub fn new() -> Result<Self> {
+        Ok(Self { term: signal(SignalKind::terminate())?, int: signal(SignalKind::interrupt())? })
+    }
+
+    /// Wait for the next shutdown signal and return its name.
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.term.recv() => "SIGTERM",
+            _ = self.int.recv() => "SIGINT",
+        }
+    }
+}
+
+/// Ask plugin-manager to unload every plugin before it is stopped.
+pub async fn unload_plugins(gateway: &Gateway) {
+    let listed = match gateway.plugins.call(&json!({ "action": "list" })).await {
+        Ok(v) => v,
+        Err(e) => {
+            logging::warn("shutdown", format!("skipping plugin unload: {}", e));
+            return;
+        }
+    };
+    // plugin-manager lists plugins as [id, type] pairs.
+    let ids: Vec<String> = listed
+        .get("data")
+        .and_then(Value::as_array)
+        .map(|items| {
+            items.iter().filter_map(|p| p.get(0).and_then(Value::as_str).map(str::to_string)).collect()
+        })
+        .unwrap_or_default();
+
+    for id in ids {
+        match gateway.plugins.call(&json!({ "action": "unload", "id": id })).await {
+            Ok(v) if v.get("ok").and_then(Value::as_bool) == Some(true) => {
+                logging::info("shutdown", format!("unloaded plugin {}", id));
+            }
+            Ok(v) => logging::warn("shutdown", format!("unloading plugin {} failed: {}", id, v)),
+            Err(e) => logging::warn("shutdown", format!("unloading plugin {} failed: {}", id, e)),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use crate::logging::{self, Level};
+use crate::reload::{self, Report};
+use crate::rpc::Context;
+
+/// Switch the system to target `name`: start its services, stop every other
+/// service service-registry has running, and load boot.plugins plus the
+/// target's plugins, unloading the rest.
+pub async fn isolate(ctx: &Context, name: &str) -> Result<Report, String> {
+    let mut preloaded = ctx.preloaded.lock().await;
+    isolate_locked(ctx, &mut preloaded, name).await
+}
+
+/// `isolate` for callers that already hold `ctx.preloaded`.
+pub async fn isolate_locked(ctx: &Context, loaded: &mut HashMap<String, String>, name: &str) -> Result<Report, String> {
+    let (target, boot_plugins) = {
+        let cfg = ctx.config();
+        let target = cfg.targets.get(name).cloned().ok_or_else(|| format!("unknown target `{}`", name))?;
+        (target, cfg.preload_plugins.clone())
+    };
+    let mut report = Report::default();
+
+    match running(ctx).await {
+        Ok(up) => {
+            for svc in up.iter().filter(|s| !target.services.iter().any(|t| covers(t, s))) {
+                match reload::stop(ctx, svc).await {
+                    Ok(()) => report.applied.push(format!("services.{}: stopped", svc)),
+                    Err(e) => report.errors.push(format!("services.{}: {}", svc, e)),
+                }
+            }
+        }
+        Err(e) => report.errors.push(format!("services: {}", e)),
+    }
+    // Services already running are left alone. Read again, as a stop also
+    // stops the services requiring the stopped one.
+    match running(ctx).await {
+        Ok(up) => {
+            for svc in target.services.iter().filter(|t| !up.iter().any(|s| covers(t, s))) {
+                match reload::start(ctx, svc).await {
+                    Ok(()) => report.applied.push(format!("services.{}: started", svc)),
+                    Err(e) => report.errors.push(format!("services.{}: {}", svc, e)),
+                }
+            }
+        }
+        Err(e) => report.errors.push(format!("services: {}", e)),
+    }
+
+    let mut plugins = boot_plugins.clone();
+    plugins.extend(target.plugins.iter().filter(|p| !boot_plugins.contains(p)).cloned());
+    reload::sync_plugins(ctx, loaded, &plugins, &mut report).await;
+
+    let previous = ctx.target.write().unwrap_or_else(|e| e.into_inner()).replace((name.to_string(), target));
+    let previous = previous.map(|(name, _)| name);
+    let level = if report.errors.is_empty() { Level::Info } else { Level::Warn };
+    logging::log(
+        level,
+        "target",
+        format!("isolated {}: {} change(s), {} failed", name, report.applied.len(), report.errors.len()),
+        json!({ "target": name, "previous": previous, "report": report }),
+    );
+    ctx.events.publish("target.isolated", json!({ "target": name, "previous": previous, "report": report }));
+    Ok(report)
+}
+
+/// Services service-registry has running or listening for activation, as it
+/// sees them rather than as core-daemon last recorded them.
+async fn running(ctx: &Context) -> Result<Vec<String>, String> {
+    let reply = reload::call(&ctx.gateway.registry, json!({ "action": "list" }), "list").await?;
+    let list = reply.get("data").and_then(Value::as_array).cloned().unwrap_or_default();
+    Ok(list
+        .iter()
+        .filter(|s| matches!(s.get("state").and_then(Value::as_str), Some("running" | "listening")))
+        .filter_map(|s| s.get("name").and_then(Value::as_str).map(str::to_string))
+        .collect())
+}
+
+/// Whether target entry `wanted` covers service `name`: the same name, or a
+/// template (`worker@`) and one of its instances (`worker@2`).
+fn covers(wanted: &str, name: &str) -> bool {
+    wanted == name || (wanted.ends_with('@') && name.starts_with(wanted))
+}
+
+/// Plugins that should be loaded given `boot_plugins`: those plus the
+/// plugins of the isolated target, as it was defined when isolated.
+pub fn wanted_plugins(ctx: &Context, boot_plugins: &[String]) -> Vec<String> {
+    let mut plugins = boot_plugins.to_vec();
+    if let Some((_, target)) = ctx.target.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        plugins.extend(target.plugins.iter().filter(|p| !boot_plugins.contains(p)).cloned());
+    }
+    plugins
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates_cover_their_instances() {
+        assert!(covers("web", "web"));
+        assert!(covers("worker@", "worker@"));
+        assert!(covers("worker@", "worker@2"));
+        assert!(!covers("worker@", "worker"));
+        assert!(!covers("worker@", "other@1"));
+        assert!(!covers("web", "web2"));
+    }
+}
@@ -0,0 +1,87 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// One step of the boot sequence.
+#[derive(Clone, Debug, Serialize)]
+pub struct Span {
+    pub name: String,
+    /// `component`, `service` or `plugin`.
+    pub kind: &'static str,
+    /// Milliseconds since core-daemon started.
+    pub start_ms: u64,
+    pub end_ms: Option<u64>,
+    /// How the step ended: ready, already_running, failed, skipped, started, loaded.
+    pub state: String,
+    /// Steps this one waited for: its declared dependencies and, since boot
+    /// runs one step at a time, the step before it.
+    pub after: Vec<String>,
+}
+
+/// What `boot_timing` returns.
+#[derive(Clone, Debug, Serialize)]
+pub struct BootTiming {
+    pub started_at: DateTime<Utc>,
+    /// When system.ready was published; `None` while still booting.
+    pub ready_ms: Option<u64>,
+    pub spans: Vec<Span>,
+}
+
+/// Records the boot sequence as it happens. Once boot is finished no more
+/// spans are added, so starts requested later do not show up as boot steps.
+pub struct Timeline {
+    origin: Instant,
+    timing: Mutex<BootTiming>,
+}
+
+impl Timeline {
+    pub fn new(origin: Instant) -> Self {
+        let started_at = Utc::now() - chrono::Duration::from_std(origin.elapsed()).unwrap_or_default();
+        Self { origin, timing: Mutex::new(BootTiming { started_at, ready_ms: None, spans: Vec::new() }) }
+    }
+
+    /// Open a span; `None` once boot is over.
+    pub fn begin(&self, name: String, kind: &'static str, requires: &[&str]) -> Option<usize> {
+        let now = self.now_ms();
+        let mut timing = self.lock();
+        if timing.ready_ms.is_some() {
+            return None;
+        }
+        let mut after: Vec<String> = requires.iter().map(|r| r.to_string()).collect();
+        if let Some(prev) = timing.spans.last() {
+            if !after.contains(&prev.name) {
+                after.push(prev.name.clone());
+            }
+        }
+        timing.spans.push(Span { name, kind, start_ms: now, end_ms: None, state: "running".into(), after });
+        Some(timing.spans.len() - 1)
+    }
+
+    pub fn end(&self, span: Option<usize>, state: &str) {
+        let Some(idx) = span else { return };
+        let now = self.now_ms();
+        if let Some(span) = self.lock().spans.get_mut(idx) {
+            span.end_ms = Some(now);
+            span.state = state.to_string();
+        }
+    }
+
+    /// Boot is over; returns the time it took in milliseconds.
+    pub fn finish(&self) -> u64 {
+        let now = self.now_ms();
+        *self.lock().ready_ms.get_or_insert(now)
+    }
+
+    pub fn snapshot(&self) -> BootTiming {
+        self.lock().clone()
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.origin.elapsed().as_millis() as u64
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BootTiming> {
+        self.timing.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -4,9 +4,14 @@ use std::path::PathBuf;
 use tokio::net::{UnixListener, UnixStream};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use std::sync::Arc;
-use tracing::{info, error};
+use tracing::{info, warn, error};
 
+use circleosd_proto::jsonrpc::{self, RpcError, RpcReply};
 use crate::loader::PluginManager;
+use circleosd_proto::peercred::{AccessList, Peer};
+
+/// Action names, also accepted as JSON-RPC 2.0 method names.
+const METHODS: &[&str] = &["list", "load", "unload", "invoke", "ping"];
 
 #[derive(Debug, Deserialize)]
 #[serde(tag = "action")]
@@ -22,6 +27,9 @@ enum Request {
 
     #[serde(rename = "invoke")]
     Invoke { id: String, func: String, payload: String },
+
+    #[serde(rename = "ping")]
+    Ping {},
 }
 
 #[derive(Debug, Serialize)]
@@ -32,69 +40,89 @@ struct Response {
     data: Option<serde_json::Value>,
 }
 
-pub async fn serve(socket_path: PathBuf, manager: PluginManager) -> Result<()> {
+impl RpcReply for Response {
+    fn invalid(message: String) -> Self {
+        Response { ok: false, message: Some(message), data: None }
+    }
+
+    fn into_jsonrpc(self) -> Result<serde_json::Value, RpcError> {
+        jsonrpc::split_reply(self.ok, self.message, self.data, jsonrpc::SERVER_ERROR)
+    }
+}
+
+pub async fn serve(socket_path: PathBuf, manager: PluginManager, access: AccessList) -> Result<()> {
     // remove old socket
     let _ = std::fs::remove_file(&socket_path);
     let listener = UnixListener::bind(&socket_path)?;
     // set socket permission to owner only
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        let mut perms = std::fs::metadata(&socket_path)?.permissions();
-        perms.set_mode(0o600);
-        std::fs::set_permissions(&socket_path, perms)?;
-    }
+    access.apply_to_socket(&socket_path)?;
     info!("plugin-manager listening on {}", socket_path.display());
 
     loop {
         let (stream, _) = listener.accept().await?;
+        let peer = match Peer::of(&stream) {
+            Ok(peer) if access.allows(&peer) => peer,
+            Ok(peer) => {
+                warn!("rejected connection from {}", peer);
+                let _ = stream.try_write(b"{\"ok\":false,\"message\":\"permission denied: uid not allowed\"}\n");
+                continue;
+            }
+            Err(e) => {
+                warn!("rejected connection: cannot read peer credentials: {}", e);
+                continue;
+            }
+        };
         let manager = manager.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_client(stream, manager).await {
+            if let Err(e) = handle_client(stream, peer, manager).await {
                 error!("client error: {:?}", e);
             }
         });
     }
 }
 
-async fn handle_client(stream: UnixStream, manager: PluginManager) -> Result<()> {
+async fn handle_client(stream: UnixStream, peer: Peer, manager: PluginManager) -> Result<()> {
+    tracing::debug!("client connected: {}", peer);
     let (r, mut w) = stream.into_split();
     let mut reader = BufReader::new(r).lines();
 
     while let Some(line) = reader.next_line().await? {
         if line.trim().is_empty() { continue; }
-        tracing::debug!("plugin-api <- {}", line);
-        let req = serde_json::from_str::<Request>(&line);
-        let resp = match req {
-            Ok(Request::List {}) => {
-                let items = manager.list().await;
-                Response { ok: true, message: None, data: serde_json::to_value(items).ok() }
-            }
-            Ok(Request::Load { path, manifest }) => {
-                match manager.load_plugin(&path, manifest.as_deref()).await {
-                    Ok(id) => Response { ok: true, message: Some("loaded".into()), data: Some(serde_json::json!({ "id": id }))},
-                    Err(e) => Response { ok: false, message: Some(format!("load failed: {}", e)), data: None },
-                }
+        tracing::debug!("plugin-api <- [{}] {}", peer, line);
+        let out = jsonrpc::handle_line(&line, METHODS, |req| process(req, &manager)).await;
+        if let Some(out) = out {
+            w.write_all(out.as_bytes()).await?;
+            w.write_all(b"\n").await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn process(req: Request, manager: &PluginManager) -> Response {
+    match req {
+        Request::List {} => {
+            let items = manager.list().await;
+            Response { ok: true, message: None, data: serde_json::to_value(items).ok() }
+        }
+        Request::Load { path, manifest } => {
+            match manager.load_plugin(&path, manifest.as_deref()).await {
+                Ok(id) => Response { ok: true, message: Some("loaded".into()), data: Some(serde_json::json!({ "id": id }))},
+                Err(e) => Response { ok: false, message: Some(format!("load failed: {}", e)), data: None },
             }
-            Ok(Request::Unload { id }) => {
-                match manager.unload(&id).await {
-                    Ok(_) => Response { ok: true, message: Some("unloaded".into()), data: None },
-                    Err(e) => Response { ok: false, message: Some(format!("unload failed: {}", e)), data: None },
-                }
+        }
+        Request::Unload { id } => {
+            match manager.unload(&id).await {
+                Ok(_) => Response { ok: true, message: Some("unloaded".into()), data: None },
+                Err(e) => Response { ok: false, message: Some(format!("unload failed: {}", e)), data: None },
             }
-            Ok(Request::Invoke { id, func, payload }) => {
-                match manager.invoke_wasm(&id, &func, &payload).await {
-                    Ok(resp) => Response { ok: true, message: None, data: Some(serde_json::json!({ "resp": resp })) },
-                    Err(e) => Response { ok: false, message: Some(format!("invoke failed: {}", e)), data: None },
-                }
+        }
+        Request::Invoke { id, func, payload } => {
+            match manager.invoke_wasm(&id, &func, &payload).await {
+                Ok(resp) => Response { ok: true, message: None, data: Some(serde_json::json!({ "resp": resp })) },
+                Err(e) => Response { ok: false, message: Some(format!("invoke failed: {}", e)), data: None },
             }
-            Err(e) => Response { ok: false, message: Some(format!("invalid request: {}", e)), data: None },
-        };
-
-        let out = serde_json::to_string(&resp)?;
-        w.write_all(out.as_bytes()).await?;
-        w.write_all(b"\n").await?;
+        }
+        Request::Ping {} => Response { ok: true, message: Some("pong".into()), data: None },
     }
-
-    Ok(())
 }
@@ -1,5 +1,6 @@
 use anyhow::Result;
 use std::path::PathBuf;
+use circleosd_proto::peercred::AccessList;
 use tracing::info;
 
 mod loader;
@@ -13,10 +14,16 @@ async fn main() -> Result<()> {
     info!("plugin-manager starting...");
 
     // default socket path (RPC)
-    let socket_path = PathBuf::from("/tmp/plugin-manager.sock");
+    // core-daemon passes its run directory; /tmp when started by hand
+    let run_dir = std::env::var_os("CIRCLEOSD_RUN_DIR").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("/tmp"));
+    let socket_path = run_dir.join("plugin-manager.sock");
     // remove leftover socket if exists
     let _ = std::fs::remove_file(&socket_path);
 
+    // only root and core-daemon's uid; everyone else goes through
+    // core-daemon and its access list
+    let access = AccessList::for_component().map_err(|e| anyhow::anyhow!(e))?;
+
     // instantiate manager
     let manager = loader::PluginManager::new();
 
@@ -30,6 +37,6 @@ async fn main() -> Result<()> {
     });
 
     // start RPC server (simple newline JSON over unix socket)
-    api::serve(socket_path, manager).await?;
+    api::serve(socket_path, manager, access).await?;
     Ok(())
 }
@@ -0,0 +1,144 @@
+//! Socket activation: the registry binds a service's listen sockets itself and
+//! only spawns the service once a connection is waiting. The service receives
+//! the sockets as inherited fds starting at 3, announced with `LISTEN_FDS` and
+//! `LISTEN_PID` like sd_listen_fds(3) expects, and may exit whenever it is idle.
+
+use anyhow::{Context, Result};
+use std::fmt;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::task::JoinSet;
+
+/// Where a socket-activated service listens.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    /// `unix:/run/foo.sock`
+    Unix(PathBuf),
+    /// `tcp:127.0.0.1:8080`
+    Tcp(SocketAddr),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("'{}' has no socket path", s));
+            }
+            Ok(ListenAddr::Unix(PathBuf::from(path)))
+        } else if let Some(addr) = s.strip_prefix("tcp:") {
+            addr.parse().map(ListenAddr::Tcp).map_err(|_| format!("'{}' is not a host:port address", addr))
+        } else {
+            Err(format!("'{}' must start with unix: or tcp:", s))
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            ListenAddr::Tcp(addr) => write!(f, "tcp:{}", addr),
+        }
+    }
+}
+
+/// The bound sockets of one service, in the order they were declared.
+pub struct Listeners {
+    addrs: Vec<ListenAddr>,
+    fds: Vec<OwnedFd>,
+}
+
+impl Listeners {
+    /// Bind every address, replacing stale unix socket files. On failure the
+    /// sockets bound so far are closed and their files removed.
+    pub fn bind(addrs: &[ListenAddr]) -> Result<Self> {
+        let mut bound = Listeners { addrs: Vec::new(), fds: Vec::new() };
+        for addr in addrs {
+            let fd: std::io::Result<OwnedFd> = match addr {
+                ListenAddr::Unix(path) => {
+                    let _ = std::fs::remove_file(path);
+                    UnixListener::bind(path).map(Into::into)
+                }
+                ListenAddr::Tcp(sa) => TcpListener::bind(sa).map(Into::into),
+            };
+            match fd {
+                Ok(fd) => {
+                    bound.addrs.push(addr.clone());
+                    bound.fds.push(fd);
+                }
+                Err(e) => {
+                    bound.remove_files();
+                    return Err(e).with_context(|| format!("failed to bind {}", addr));
+                }
+            }
+        }
+        if bound.fds.is_empty() {
+            anyhow::bail!("no listen addresses");
+        }
+        Ok(bound)
+    }
+
+    /// The fds to hand to the service, in declaration order.
+    pub fn raw_fds(&self) -> Vec<RawFd> {
+        self.fds.iter().map(|fd| fd.as_raw_fd()).collect()
+    }
+
+    /// Wait until a connection is pending on any of the sockets. Nothing is
+    /// accepted; the connection is left for the service to pick up.
+    pub async fn wait_for_connection(&self) -> Result<()> {
+        let mut waits = JoinSet::new();
+        for fd in &self.fds {
+            // each waiter owns a duplicate, so an aborted waiter never touches
+            // an fd number that has since been closed and reused
+            let fd = fd.try_clone()?;
+            waits.spawn(async move {
+                let fd = AsyncFd::with_interest(fd, Interest::READABLE)?;
+                let _ready = fd.readable().await?;
+                Ok::<_, std::io::Error>(())
+            });
+        }
+        // the first socket to become readable wins; dropping the set stops the others
+        match waits.join_next().await {
+            Some(res) => Ok(res??),
+            None => anyhow::bail!("no sockets to wait on"),
+        }
+    }
+
+    /// Remove the unix socket files. The fds stay open until dropped.
+    pub fn remove_files(&self) {
+        for addr in &self.addrs {
+            if let ListenAddr::Unix(path) = addr {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_addr() {
+        assert_eq!("unix:/run/web.sock".parse(), Ok(ListenAddr::Unix(PathBuf::from("/run/web.sock"))));
+        assert_eq!("tcp:127.0.0.1:8080".parse(), Ok(ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 8080)))));
+        assert_eq!("tcp:[::1]:53".parse(), Ok(ListenAddr::Tcp(SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 53)))));
+        for bad in ["unix:", "tcp:localhost:80", "tcp:127.0.0.1", "tcp:127.0.0.1:70000", "/run/web.sock", "udp:0.0.0.0:53"] {
+            assert!(bad.parse::<ListenAddr>().is_err(), "{} should not parse", bad);
+        }
+    }
+
+    #[test]
+    fn listen_addr_round_trips() {
+        for s in ["unix:/run/web.sock", "tcp:0.0.0.0:80", "tcp:[::1]:8080"] {
+            assert_eq!(s.parse::<ListenAddr>().unwrap().to_string(), s);
+        }
+    }
+}
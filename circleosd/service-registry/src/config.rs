@@ -0,0 +1,63 @@
+//! Service definitions read from services.toml, or from every `*.toml` in a
+//! directory such as services.d/, each holding `[[service]]` tables.
+
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::service::ServiceSpec;
+
+/// Used when CIRCLEOSD_SERVICES is not set.
+const DEFAULT_PATH: &str = "etc/services.toml";
+
+/// One `[[service]]` table, or one entry of an `apply` request.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Entry {
+    #[serde(flatten)]
+    pub spec: ServiceSpec,
+    /// Start the service once it is added.
+    #[serde(default = "yes")]
+    pub autostart: bool,
+}
+
+fn yes() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+struct File {
+    #[serde(default)]
+    service: Vec<Entry>,
+}
+
+/// Where service definitions are read from, and whether it was asked for
+/// explicitly (a missing default is not an error).
+pub fn path() -> (PathBuf, bool) {
+    match std::env::var_os("CIRCLEOSD_SERVICES").filter(|p| !p.is_empty()) {
+        Some(p) => (PathBuf::from(p), true),
+        None => (PathBuf::from(DEFAULT_PATH), false),
+    }
+}
+
+/// Read the services at `path`, a file or a directory of `*.toml` files
+/// read in name order.
+pub fn load(path: &Path) -> Result<Vec<Entry>, String> {
+    let files = if path.is_dir() {
+        let dir = std::fs::read_dir(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut files: Vec<PathBuf> = dir
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|x| x == "toml") && p.is_file())
+            .collect();
+        files.sort();
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let mut entries = Vec::new();
+    for file in files {
+        let text = std::fs::read_to_string(&file).map_err(|e| format!("{}: {}", file.display(), e))?;
+        let parsed: File = toml::from_str(&text).map_err(|e| format!("{}: {}", file.display(), e))?;
+        entries.extend(parsed.service);
+    }
+    Ok(entries)
+}
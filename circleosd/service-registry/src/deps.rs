@@ -0,0 +1,233 @@
+//! Ordering between services, from the `requires`, `wants` and `after`
+//! lists of their specs:
+//!
+//! - `requires`: started first; if one fails to start, so does the service,
+//!   and stopping one stops the service as well.
+//! - `wants`: started first too, but a failure is only logged.
+//! - `after`: pulls nothing in; only orders the two when both are started.
+
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use tracing::warn;
+
+use crate::service::ServiceSpec;
+
+type Specs = HashMap<String, ServiceSpec>;
+
+/// Everything `spec` has to start after.
+fn ordered_after(spec: &ServiceSpec) -> impl Iterator<Item = &String> {
+    spec.requires.iter().chain(&spec.wants).chain(&spec.after)
+}
+
+/// Refuse `spec` if registering it next to `specs` would close a cycle.
+pub fn check_cycles(specs: &Specs, spec: &ServiceSpec) -> Result<(), String> {
+    let lookup = |name: &str| if name == spec.name { Some(spec) } else { specs.get(name) };
+    let mut path = vec![spec.name.clone()];
+    let mut seen = HashSet::new();
+    if leads_to(&lookup, &spec.name, &spec.name, &mut path, &mut seen) {
+        return Err(format!("dependency cycle: {}", path.join(" -> ")));
+    }
+    Ok(())
+}
+
+/// Depth-first search for `target` from `from`; `path` ends up holding the way there.
+fn leads_to<'a>(
+    lookup: &impl Fn(&str) -> Option<&'a ServiceSpec>,
+    from: &str,
+    target: &str,
+    path: &mut Vec<String>,
+    seen: &mut HashSet<String>,
+) -> bool {
+    let Some(spec) = lookup(from) else { return false };
+    for dep in ordered_after(spec) {
+        path.push(dep.clone());
+        if dep == target || (seen.insert(dep.clone()) && leads_to(lookup, dep, target, path, seen)) {
+            return true;
+        }
+        path.pop();
+    }
+    false
+}
+
+/// `name` and everything it requires or wants, transitively, with each
+/// service after the ones it is ordered after.
+pub fn start_order(specs: &Specs, name: &str) -> Result<Vec<String>, String> {
+    if !specs.contains_key(name) {
+        return Err("service not found".into());
+    }
+    let mut pulled = HashSet::new();
+    let mut todo = vec![name.to_string()];
+    while let Some(svc) = todo.pop() {
+        if !pulled.insert(svc.clone()) {
+            continue;
+        }
+        let spec = &specs[&svc];
+        for dep in &spec.requires {
+            if !specs.contains_key(dep) {
+                return Err(format!("{} requires {}, which is not registered", svc, dep));
+            }
+            todo.push(dep.clone());
+        }
+        for dep in &spec.wants {
+            if specs.contains_key(dep) {
+                todo.push(dep.clone());
+            } else {
+                warn!("{} wants {}, which is not registered", svc, dep);
+            }
+        }
+    }
+
+    let mut order = Vec::new();
+    let mut placed = HashSet::new();
+    place(specs, name, &pulled, &mut placed, &mut order);
+    Ok(order)
+}
+
+fn place(specs: &Specs, svc: &str, pulled: &HashSet<String>, placed: &mut HashSet<String>, order: &mut Vec<String>) {
+    if !placed.insert(svc.to_string()) {
+        return;
+    }
+    for dep in ordered_after(&specs[svc]) {
+        if pulled.contains(dep) {
+            place(specs, dep, pulled, placed, order);
+        }
+    }
+    order.push(svc.to_string());
+}
+
+/// Services that require `name`, directly or through others, in the order
+/// they have to be stopped: each before whatever it requires.
+pub fn dependents(specs: &Specs, name: &str) -> Vec<String> {
+    let mut order = Vec::new();
+    let mut seen = HashSet::from([name.to_string()]);
+    collect_dependents(specs, name, &mut seen, &mut order);
+    order
+}
+
+fn collect_dependents(specs: &Specs, name: &str, seen: &mut HashSet<String>, order: &mut Vec<String>) {
+    let mut direct: Vec<&String> = specs.values().filter(|s| s.requires.iter().any(|r| r == name)).map(|s| &s.name).collect();
+    direct.sort();
+    for svc in direct {
+        if seen.insert(svc.clone()) {
+            collect_dependents(specs, svc, seen, order);
+            order.push(svc.clone());
+        }
+    }
+}
+
+/// Dependency tree of `name` for `deps`: what it requires, wants and is
+/// ordered after, or with `reverse` which services name it in theirs.
+pub fn tree(specs: &Specs, name: &str, reverse: bool, state: &dyn Fn(&str) -> &'static str) -> Value {
+    let mut path = HashSet::new();
+    node(specs, name, reverse, state, &mut path)
+}
+
+fn node(specs: &Specs, name: &str, reverse: bool, state: &dyn Fn(&str) -> &'static str, path: &mut HashSet<String>) -> Value {
+    let mut edges: Vec<(&str, &String)> = Vec::new();
+    if reverse {
+        let mut names: Vec<&String> = specs.keys().collect();
+        names.sort();
+        for other in names {
+            let spec = &specs[other];
+            let has = |list: &[String]| list.iter().any(|n| n == name);
+            if has(&spec.requires) {
+                edges.push(("required_by", other));
+            } else if has(&spec.wants) {
+                edges.push(("wanted_by", other));
+            } else if has(&spec.after) {
+                edges.push(("before", other));
+            }
+        }
+    } else if let Some(spec) = specs.get(name) {
+        edges.extend(spec.requires.iter().map(|d| ("requires", d)));
+        edges.extend(spec.wants.iter().map(|d| ("wants", d)));
+        edges.extend(spec.after.iter().map(|d| ("after", d)));
+    }
+
+    path.insert(name.to_string());
+    let mut deps = Vec::new();
+    for (relation, dep) in edges {
+        // a cycle can't be registered, but don't loop forever on one
+        if path.contains(dep) {
+            continue;
+        }
+        let mut child = node(specs, dep, reverse, state, path);
+        child["relation"] = json!(relation);
+        deps.push(child);
+    }
+    path.remove(name);
+    json!({ "name": name, "state": state(name), "deps": deps })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(name: &str, requires: &[&str], wants: &[&str], after: &[&str]) -> ServiceSpec {
+        serde_json::from_value(json!({
+            "name": name,
+            "cmd": ["/bin/true"],
+            "requires": requires,
+            "wants": wants,
+            "after": after,
+        }))
+        .unwrap()
+    }
+
+    fn specs(list: Vec<ServiceSpec>) -> Specs {
+        list.into_iter().map(|s| (s.name.clone(), s)).collect()
+    }
+
+    #[test]
+    fn start_order_puts_dependencies_first() {
+        let specs = specs(vec![
+            spec("web", &["db"], &["cache"], &[]),
+            spec("db", &["disk"], &[], &[]),
+            spec("disk", &[], &[], &[]),
+            spec("cache", &[], &[], &["db"]),
+        ]);
+        let order = start_order(&specs, "web").unwrap();
+        let pos = |n: &str| order.iter().position(|s| s == n).unwrap();
+        assert_eq!(order.len(), 4);
+        assert_eq!(order.last().unwrap(), "web");
+        assert!(pos("disk") < pos("db"));
+        assert!(pos("db") < pos("cache"));
+    }
+
+    #[test]
+    fn start_order_after_pulls_nothing_in() {
+        let specs = specs(vec![spec("web", &[], &[], &["db"]), spec("db", &[], &[], &[])]);
+        assert_eq!(start_order(&specs, "web").unwrap(), ["web"]);
+    }
+
+    #[test]
+    fn start_order_missing_services() {
+        let specs = specs(vec![spec("web", &["db"], &[], &[]), spec("api", &[], &["gone"], &[])]);
+        assert_eq!(start_order(&specs, "nope").unwrap_err(), "service not found");
+        assert!(start_order(&specs, "web").unwrap_err().contains("requires db"));
+        // a missing `wants` is only logged
+        assert_eq!(start_order(&specs, "api").unwrap(), ["api"]);
+    }
+
+    #[test]
+    fn check_cycles_finds_the_loop() {
+        let specs = specs(vec![spec("b", &["c"], &[], &[]), spec("c", &[], &[], &["a"])]);
+        let err = check_cycles(&specs, &spec("a", &[], &["b"], &[])).unwrap_err();
+        assert_eq!(err, "dependency cycle: a -> b -> c -> a");
+        assert!(check_cycles(&specs, &spec("a", &[], &[], &[])).is_ok());
+        assert!(check_cycles(&specs, &spec("self", &["self"], &[], &[])).is_err());
+    }
+
+    #[test]
+    fn dependents_stop_before_what_they_require() {
+        let specs = specs(vec![
+            spec("db", &[], &[], &[]),
+            spec("api", &["db"], &[], &[]),
+            spec("web", &["api"], &[], &[]),
+            spec("worker", &["db"], &[], &[]),
+            spec("metrics", &[], &["db"], &["db"]),
+        ]);
+        assert_eq!(dependents(&specs, "db"), ["web", "api", "worker"]);
+        assert!(dependents(&specs, "web").is_empty());
+    }
+}
@@ -0,0 +1,31 @@
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tracing::debug;
+
+/// Report an event to core-daemon's event bus.
+///
+/// core-daemon passes its socket and a publish-only token in the
+/// environment of the services it spawns; when started any other way this
+/// is a no-op. Delivery is best-effort and never blocks the caller.
+pub fn publish(topic: &'static str, data: Value) {
+    let Ok(token) = std::env::var("CIRCLEOSD_PUBLISH_TOKEN") else { return };
+    let socket = std::env::var("CIRCLEOSD_SOCKET").unwrap_or_else(|_| "/tmp/circleosd.sock".to_string());
+    let line = json!({ "action": "publish", "token": token, "topic": topic, "data": data }).to_string();
+
+    tokio::spawn(async move {
+        let sent = async {
+            let stream = UnixStream::connect(&socket).await?;
+            let (r, mut w) = stream.into_split();
+            w.write_all(line.as_bytes()).await?;
+            w.write_all(b"\n").await?;
+            BufReader::new(r).lines().next_line().await
+        };
+        match tokio::time::timeout(std::time::Duration::from_secs(2), sent).await {
+            Ok(Ok(Some(reply))) => debug!("published {}: {}", topic, reply),
+            Ok(Ok(None)) => debug!("publishing {}: connection closed", topic),
+            Ok(Err(e)) => debug!("publishing {} failed: {}", topic, e),
+            Err(_) => debug!("publishing {} timed out", topic),
+        }
+    });
+}
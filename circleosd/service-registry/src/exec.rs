@@ -0,0 +1,323 @@
+//! The environment a service's process starts in: variables, working
+//! directory, user and groups, and umask. Everything is resolved when the
+//! service is registered or started, so a missing user or directory is
+//! reported to the caller instead of failing inside the forked child.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tokio::process::Command;
+
+use circleosd_proto::peercred;
+use crate::service::ServiceSpec;
+
+/// Who a service without `user` runs as when service-registry is root, so
+/// that no service gets root unless its spec asks for it.
+pub const DEFAULT_USER: &str = "nobody";
+
+#[derive(Debug, Clone, Default)]
+pub struct ExecContext {
+    env: HashMap<String, String>,
+    clear_env: bool,
+    working_dir: Option<PathBuf>,
+    credentials: Option<Credentials>,
+    umask: Option<libc::mode_t>,
+}
+
+/// Identity to switch to before exec. `None` fields are inherited.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub user: Option<Account>,
+    pub gid: Option<u32>,
+    /// Complete supplementary group list, replacing the inherited one.
+    pub groups: Option<Vec<u32>>,
+}
+
+/// A user as found in /etc/passwd (or just a uid).
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub name: String,
+    pub uid: u32,
+    pub gid: Option<u32>,
+    pub home: Option<String>,
+}
+
+impl ExecContext {
+    /// Check and resolve the exec settings of `spec`.
+    pub fn from_spec(spec: &ServiceSpec) -> Result<Self, String> {
+        let working_dir = match &spec.working_dir {
+            Some(dir) => match std::fs::metadata(dir) {
+                Ok(meta) if meta.is_dir() => Some(PathBuf::from(dir)),
+                Ok(_) => return Err(format!("working_dir {} is not a directory", dir)),
+                Err(e) => return Err(format!("working_dir {}: {}", dir, e)),
+            },
+            None => None,
+        };
+        let umask = match &spec.umask {
+            Some(mask) => match libc::mode_t::from_str_radix(mask, 8) {
+                Ok(m) if m <= 0o777 => Some(m),
+                _ => return Err(format!("umask `{}` is not an octal mode such as \"022\"", mask)),
+            },
+            None => None,
+        };
+        let user = match spec.user.as_deref() {
+            Some(user) => Some(user),
+            None if is_root() => Some(DEFAULT_USER),
+            None => None,
+        };
+        let credentials = if user.is_some() || spec.group.is_some() || !spec.supplementary_groups.is_empty() {
+            Some(Credentials::resolve(user, spec.group.as_deref(), &spec.supplementary_groups)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            env: spec.env.clone().unwrap_or_default(),
+            clear_env: spec.clear_env,
+            working_dir,
+            credentials,
+            umask,
+        })
+    }
+
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    /// Set up `command`. Comes before anything else adds variables, since
+    /// `clear_env` wipes what is already set.
+    pub fn apply(&self, command: &mut Command) {
+        if self.clear_env {
+            command.env_clear();
+        }
+        if let Some(account) = self.credentials.as_ref().and_then(|c| c.user.as_ref()) {
+            command.env("USER", &account.name).env("LOGNAME", &account.name);
+            if let Some(home) = &account.home {
+                command.env("HOME", home);
+            }
+        }
+        command.envs(&self.env);
+        if let Some(dir) = &self.working_dir {
+            command.current_dir(dir);
+        }
+
+        let (uid, gid, groups) = match &self.credentials {
+            Some(c) => (c.user.as_ref().map(|u| u.uid), c.gid, c.groups.clone()),
+            None => (None, None, None),
+        };
+        let umask = self.umask;
+        if uid.is_none() && gid.is_none() && groups.is_none() && umask.is_none() {
+            return;
+        }
+        // Safety: setgroups, setgid, setuid and umask are async-signal-safe and
+        // the group list was allocated before fork. Groups go first and the uid
+        // last, as dropping root makes the others fail.
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(groups) = &groups {
+                    if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(gid) = gid {
+                    if libc::setgid(gid) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(uid) = uid {
+                    if libc::setuid(uid) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(mask) = umask {
+                    libc::umask(mask);
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+impl Credentials {
+    fn resolve(user: Option<&str>, group: Option<&str>, supplementary: &[String]) -> Result<Self, String> {
+        let user = user.map(Account::lookup).transpose()?;
+        let gid = match group {
+            Some(g) => Some(peercred::resolve_group(g).ok_or_else(|| format!("group `{}` does not exist", g))?),
+            None => user.as_ref().and_then(|u| u.gid.or(Some(u.uid))),
+        };
+        let mut extra = Vec::new();
+        for g in supplementary {
+            extra.push(peercred::resolve_group(g).ok_or_else(|| format!("group `{}` does not exist", g))?);
+        }
+        // a new user gets their own groups, never service-registry's
+        let groups = match &user {
+            Some(account) => {
+                let mut groups = member_of(&account.name);
+                for g in extra {
+                    if !groups.contains(&g) {
+                        groups.push(g);
+                    }
+                }
+                Some(groups)
+            }
+            // no user, but still none of service-registry's groups
+            None => match gid {
+                Some(gid) => Some(std::iter::once(gid).chain(extra.into_iter().filter(|g| *g != gid)).collect()),
+                None if !extra.is_empty() => Some(extra),
+                None => None,
+            },
+        };
+
+        let creds = Self { user, gid, groups };
+        creds.check_privileges()?;
+        Ok(creds)
+    }
+
+    /// Switching to another uid or gid, or setting groups, needs root.
+    fn check_privileges(&self) -> Result<(), String> {
+        if is_root() {
+            return Ok(());
+        }
+        let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let changes_uid = self.user.as_ref().is_some_and(|u| u.uid != euid);
+        let changes_gid = self.gid.is_some_and(|g| g != egid);
+        if changes_uid || changes_gid || self.groups.is_some() {
+            return Err("running as another user or group needs service-registry to run as root".into());
+        }
+        Ok(())
+    }
+}
+
+impl Account {
+    /// A user name or uid from /etc/passwd; a uid with no entry is allowed.
+    fn lookup(name: &str) -> Result<Self, String> {
+        let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+        let found = passwd.lines().find_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            if fields.len() < 6 || (fields[0] != name && fields[2] != name) {
+                return None;
+            }
+            Some(Account {
+                name: fields[0].to_string(),
+                uid: fields[2].parse().ok()?,
+                gid: fields[3].parse().ok(),
+                home: Some(fields[5].to_string()).filter(|h| !h.is_empty()),
+            })
+        });
+        match found {
+            Some(account) => Ok(account),
+            None => match name.parse() {
+                Ok(uid) => Ok(Account { name: name.to_string(), uid, gid: None, home: None }),
+                Err(_) => Err(format!("user `{}` does not exist", name)),
+            },
+        }
+    }
+}
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Gids of the /etc/group entries listing `user` as a member.
+fn member_of(user: &str) -> Vec<u32> {
+    let groups = std::fs::read_to_string("/etc/group").unwrap_or_default();
+    groups
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            let members = fields.get(3)?;
+            if members.split(',').any(|m| m == user) {
+                fields.get(2)?.parse().ok()
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn spec(extra: serde_json::Value) -> ServiceSpec {
+        let mut spec = json!({ "name": "web", "cmd": ["/bin/true"] });
+        spec.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(spec).unwrap()
+    }
+
+    #[test]
+    fn from_spec_checks_working_dir_and_umask() {
+        let ctx = ExecContext::from_spec(&spec(json!({ "working_dir": "/", "umask": "027" }))).unwrap();
+        assert_eq!(ctx.working_dir, Some(PathBuf::from("/")));
+        assert_eq!(ctx.umask, Some(0o027));
+
+        assert!(ExecContext::from_spec(&spec(json!({ "working_dir": "/nonexistent/dir" }))).is_err());
+        assert!(ExecContext::from_spec(&spec(json!({ "working_dir": "/etc/passwd" })))
+            .unwrap_err()
+            .contains("not a directory"));
+        for bad in ["999", "0o22", "1000", ""] {
+            assert!(ExecContext::from_spec(&spec(json!({ "umask": bad }))).is_err(), "umask {} accepted", bad);
+        }
+    }
+
+    #[test]
+    fn from_spec_never_inherits_root() {
+        let ctx = ExecContext::from_spec(&spec(json!({}))).unwrap();
+        let user = ctx.credentials().and_then(|c| c.user.as_ref()).map(|u| u.name.as_str());
+        if is_root() {
+            assert_eq!(user, Some(DEFAULT_USER));
+        } else {
+            assert_eq!(user, None);
+        }
+        if is_root() {
+            let ctx = ExecContext::from_spec(&spec(json!({ "user": "root" }))).unwrap();
+            assert_eq!(ctx.credentials().unwrap().user.as_ref().unwrap().uid, 0);
+        }
+    }
+
+    #[test]
+    fn resolve_unknown_names() {
+        assert_eq!(
+            Credentials::resolve(Some("no-such-user-here"), None, &[]).unwrap_err(),
+            "user `no-such-user-here` does not exist"
+        );
+        assert_eq!(
+            Credentials::resolve(None, Some("no-such-group-here"), &[]).unwrap_err(),
+            "group `no-such-group-here` does not exist"
+        );
+        assert_eq!(
+            Credentials::resolve(None, None, &["no-such-group-here".into()]).unwrap_err(),
+            "group `no-such-group-here` does not exist"
+        );
+    }
+
+    #[test]
+    fn resolve_user_and_groups() {
+        if !is_root() {
+            return;
+        }
+        // a uid without a passwd entry is fine; its group is the same number
+        let creds = Credentials::resolve(Some("4242"), None, &["77".into()]).unwrap();
+        assert_eq!(creds.user.as_ref().map(|u| u.uid), Some(4242));
+        assert_eq!(creds.gid, Some(4242));
+        assert_eq!(creds.groups, Some(vec![77]));
+
+        let creds = Credentials::resolve(Some("root"), Some("5"), &[]).unwrap();
+        assert_eq!(creds.user.as_ref().map(|u| u.uid), Some(0));
+        assert_eq!(creds.gid, Some(5));
+    }
+
+    #[test]
+    fn resolve_group_without_user_replaces_groups() {
+        if !is_root() {
+            return;
+        }
+        let creds = Credentials::resolve(None, Some("5"), &["7".into(), "5".into()]).unwrap();
+        assert!(creds.user.is_none());
+        assert_eq!(creds.gid, Some(5));
+        assert_eq!(creds.groups, Some(vec![5, 7]));
+
+        let creds = Credentials::resolve(None, None, &["7".into()]).unwrap();
+        assert_eq!(creds.gid, None);
+        assert_eq!(creds.groups, Some(vec![7]));
+    }
+}
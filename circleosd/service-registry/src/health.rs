@@ -1,11 +1,301 @@
-use tracing::info;
-use crate::service::ServiceSpec;
-
-/// Health check utilities. Currently minimal: we can implement HTTP probe or command probe.
-/// For now we only provide a placeholder function that returns true if process exists.
-pub async fn check_process_alive(_spec: &ServiceSpec) -> bool {
-    // Placeholder: in future, use HTTP probe or custom command.
-    // Returning true indicates the process is "healthy" from the registry perspective.
-    // The supervisor will treat process liveness separately.
-    true
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::Weak;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::process::SupervisedProcess;
+
+/// `health_check` in a service spec: either just the probe,
+/// `"http://127.0.0.1:8080/health"`, or a table with the probe and its timing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "HealthCheckDef")]
+pub struct HealthCheck {
+    pub probe: String,
+    /// Seconds between probes.
+    pub interval_secs: u64,
+    /// Seconds a single probe may take.
+    pub timeout_secs: u64,
+    /// Consecutive failures after which the service is unhealthy and restarted.
+    pub failure_threshold: u32,
+    /// Seconds after each start during which failures are not counted.
+    pub start_period_secs: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HealthCheckDef {
+    Probe(String),
+    Table {
+        probe: String,
+        interval_secs: Option<u64>,
+        timeout_secs: Option<u64>,
+        failure_threshold: Option<u32>,
+        start_period_secs: Option<u64>,
+    },
+}
+
+impl From<HealthCheckDef> for HealthCheck {
+    fn from(def: HealthCheckDef) -> Self {
+        match def {
+            HealthCheckDef::Probe(probe) => HealthCheck {
+                probe,
+                interval_secs: 10,
+                timeout_secs: 3,
+                failure_threshold: 3,
+                start_period_secs: 0,
+            },
+            HealthCheckDef::Table { probe, interval_secs, timeout_secs, failure_threshold, start_period_secs } => {
+                HealthCheck {
+                    probe,
+                    interval_secs: interval_secs.unwrap_or(10),
+                    timeout_secs: timeout_secs.unwrap_or(3),
+                    failure_threshold: failure_threshold.unwrap_or(3),
+                    start_period_secs: start_period_secs.unwrap_or(0),
+                }
+            }
+        }
+    }
+}
+
+impl HealthCheck {
+    pub fn validate(&self) -> Result<(), String> {
+        self.probe.parse::<Probe>()?;
+        if self.interval_secs == 0 || self.timeout_secs == 0 || self.failure_threshold == 0 {
+            return Err("interval_secs, timeout_secs and failure_threshold must be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+/// How a service is probed.
+#[derive(Debug, Clone)]
+pub enum Probe {
+    /// `http://host:port/path`: GET must answer 2xx or 3xx.
+    Http { host: String, port: u16, path: String },
+    /// `tcp://host:port`: the port accepts a connection.
+    Tcp(String),
+    /// `unix:///path` (or `unix:/path`): the socket accepts a connection.
+    Unix(PathBuf),
+    /// `exec:cmd arg...`: the command exits with status 0.
+    Exec(Vec<String>),
+}
+
+impl FromStr for Probe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if let Some(rest) = s.strip_prefix("http://") {
+            let (authority, path) = match rest.find('/') {
+                Some(i) => (&rest[..i], &rest[i..]),
+                None => (rest, "/"),
+            };
+            let (host, port) = match authority.rsplit_once(':') {
+                Some((h, p)) => (h, p.parse().map_err(|_| format!("invalid port in '{}'", s))?),
+                None => (authority, 80),
+            };
+            if host.is_empty() {
+                return Err(format!("'{}' has no host", s));
+            }
+            Ok(Probe::Http { host: host.to_string(), port, path: path.to_string() })
+        } else if let Some(addr) = s.strip_prefix("tcp://") {
+            match addr.rsplit_once(':') {
+                Some((h, p)) if !h.is_empty() && p.parse::<u16>().is_ok() => Ok(Probe::Tcp(addr.to_string())),
+                _ => Err(format!("'{}' is not tcp://host:port", s)),
+            }
+        } else if let Some(path) = s.strip_prefix("unix://").or_else(|| s.strip_prefix("unix:")) {
+            if path.is_empty() {
+                return Err(format!("'{}' has no socket path", s));
+            }
+            Ok(Probe::Unix(PathBuf::from(path)))
+        } else if let Some(cmd) = s.strip_prefix("exec:") {
+            let argv: Vec<String> = cmd.split_whitespace().map(str::to_string).collect();
+            if argv.is_empty() {
+                return Err(format!("'{}' has no command", s));
+            }
+            Ok(Probe::Exec(argv))
+        } else {
+            Err(format!("'{}' must start with http://, tcp://, unix:// or exec:", s))
+        }
+    }
+}
+
+impl Probe {
+    /// Run the probe once; the error says why it failed.
+    pub async fn check(&self, timeout: Duration) -> Result<(), String> {
+        match tokio::time::timeout(timeout, self.check_inner()).await {
+            Ok(res) => res,
+            Err(_) => Err(format!("no answer within {}s", timeout.as_secs())),
+        }
+    }
+
+    async fn check_inner(&self) -> Result<(), String> {
+        match self {
+            Probe::Http { host, port, path } => {
+                let mut stream = TcpStream::connect((host.as_str(), *port)).await.map_err(|e| e.to_string())?;
+                let req = format!(
+                    "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: circleosd-service-registry\r\nConnection: close\r\n\r\n",
+                    path, host
+                );
+                stream.write_all(req.as_bytes()).await.map_err(|e| e.to_string())?;
+                // read the whole (bounded) reply so closing does not reset the connection
+                let mut reply = Vec::new();
+                stream.take(64 * 1024).read_to_end(&mut reply).await.map_err(|e| e.to_string())?;
+                let line = String::from_utf8_lossy(&reply[..reply.len().min(64)]);
+                let code: u16 = line
+                    .strip_prefix("HTTP/1.")
+                    .and_then(|l| l.get(2..5))
+                    .and_then(|c| c.parse().ok())
+                    .ok_or_else(|| "not an HTTP response".to_string())?;
+                if (200..400).contains(&code) {
+                    Ok(())
+                } else {
+                    Err(format!("HTTP {}", code))
+                }
+            }
+            Probe::Tcp(addr) => TcpStream::connect(addr.as_str()).await.map(drop).map_err(|e| e.to_string()),
+            Probe::Unix(path) => UnixStream::connect(path).await.map(drop).map_err(|e| e.to_string()),
+            Probe::Exec(argv) => {
+                let status = Command::new(&argv[0])
+                    .args(&argv[1..])
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .kill_on_drop(true)
+                    .status()
+                    .await
+                    .map_err(|e| format!("failed to run {}: {}", argv[0], e))?;
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(format!("exited with {}", status))
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    /// No probe has succeeded since the last start.
+    Starting,
+    Healthy,
+    /// failure_threshold probes in a row failed; the monitor restarts it.
+    Unhealthy,
+}
+
+/// Probe results for the current instance of a service.
+#[derive(Debug, Clone, Serialize)]
+pub struct Health {
+    pub status: HealthStatus,
+    /// Consecutive failed probes.
+    pub failures: u32,
+    pub last_error: Option<String>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self { status: HealthStatus::Starting, failures: 0, last_error: None }
+    }
+}
+
+/// Probe a service every interval for as long as it is supervised. Results
+/// go into the process's `health`; acting on them is up to the monitor.
+pub async fn watch(name: String, check: HealthCheck, process: Weak<parking_lot::Mutex<SupervisedProcess>>) {
+    let Ok(probe) = check.probe.parse::<Probe>() else { return };
+    let interval = Duration::from_secs(check.interval_secs);
+    let timeout = Duration::from_secs(check.timeout_secs);
+    let start_period = Duration::from_secs(check.start_period_secs);
+
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(process) = process.upgrade() else { return };
+        let since_start = {
+            let locked = process.lock();
+            if locked.child.is_none() {
+                continue;
+            }
+            locked.last_start.map(|t| t.elapsed()).unwrap_or_default()
+        };
+
+        let started = Instant::now();
+        let result = probe.check(timeout).await;
+        let mut locked = process.lock();
+        // the service was restarted while the probe ran; judge the new one next time
+        if locked.last_start.is_some_and(|t| t > started) {
+            continue;
+        }
+        let Some(health) = locked.health.as_mut() else { return };
+        match result {
+            Ok(()) => {
+                if health.status != HealthStatus::Healthy {
+                    info!("service {} is healthy", name);
+                }
+                *health = Health { status: HealthStatus::Healthy, failures: 0, last_error: None };
+            }
+            // failures while starting up are not held against it
+            Err(e) if since_start < start_period => {
+                health.last_error = Some(e);
+            }
+            Err(e) => {
+                health.failures += 1;
+                warn!("health check of {} failed ({}/{}): {}", name, health.failures, check.failure_threshold, e);
+                health.last_error = Some(e);
+                if health.failures >= check.failure_threshold {
+                    health.status = HealthStatus::Unhealthy;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn probe_http() {
+        match "http://127.0.0.1:8080/health?full=1".parse::<Probe>().unwrap() {
+            Probe::Http { host, port, path } => {
+                assert_eq!((host.as_str(), port, path.as_str()), ("127.0.0.1", 8080, "/health?full=1"));
+            }
+            other => panic!("{:?}", other),
+        }
+        match "http://localhost".parse::<Probe>().unwrap() {
+            Probe::Http { host, port, path } => assert_eq!((host.as_str(), port, path.as_str()), ("localhost", 80, "/")),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn probe_tcp_unix_exec() {
+        assert!(matches!("tcp://db:5432".parse(), Ok(Probe::Tcp(a)) if a == "db:5432"));
+        assert!(matches!("unix:///run/web.sock".parse(), Ok(Probe::Unix(p)) if p == Path::new("/run/web.sock")));
+        assert!(matches!("unix:/run/web.sock".parse(), Ok(Probe::Unix(p)) if p == Path::new("/run/web.sock")));
+        assert!(matches!("exec:pg_isready -q".parse(), Ok(Probe::Exec(argv)) if argv == ["pg_isready", "-q"]));
+    }
+
+    #[test]
+    fn probe_rejects() {
+        for bad in [
+            "http://:80/",
+            "http://host:http/",
+            "tcp://db",
+            "tcp://:5432",
+            "tcp://db:99999",
+            "unix://",
+            "exec:  ",
+            "https://example.com/",
+            "",
+        ] {
+            assert!(bad.parse::<Probe>().is_err(), "{} should not parse", bad);
+        }
+    }
 }
@@ -1,11 +1,23 @@
 use anyhow::Result;
 use std::path::PathBuf;
+use tokio::signal::unix::{signal, SignalKind};
+use circleosd_proto::peercred::AccessList;
 use tracing::info;
 
 mod registry;
 mod service;
 mod process;
 mod health;
+mod events;
+mod activation;
+mod notify;
+mod exec;
+mod output;
+mod deps;
+mod resources;
+mod config;
+mod template;
+mod timer;
 
 use registry::Registry;
 
@@ -15,10 +27,21 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     info!("service-registry starting...");
 
-    let socket_path = PathBuf::from("/tmp/service-registry.sock");
+    // core-daemon passes its run directory; /tmp when started by hand
+    let run_dir = std::env::var_os("CIRCLEOSD_RUN_DIR").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("/tmp"));
+    let socket_path = run_dir.join("service-registry.sock");
     // remove stale socket
     let _ = std::fs::remove_file(&socket_path);
 
+    // only root and core-daemon's uid; everyone else goes through
+    // core-daemon and its access list
+    let access = AccessList::for_component().map_err(|e| anyhow::anyhow!(e))?;
+
+    // before anything is spawned: the registry may move to a cgroup of its own
+    resources::init();
+    // `after boot` timers count from here
+    timer::boot();
+
     let registry = Registry::new();
 
     // spawn registry background monitor (restarts, health checks)
@@ -29,8 +52,25 @@ async fn main() -> Result<()> {
         });
     }
 
+    // services from services.toml; the socket is already up meanwhile, so a
+    // slow start does not keep core-daemon from seeing the registry
+    {
+        let reg = registry.clone();
+        tokio::spawn(async move { reg.load_services().await });
+    }
+
+    // services run in their own process groups, so a signal to ours does not
+    // reach them; stop them before exiting
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+
     // Start RPC listener
-    registry.serve(socket_path).await?;
+    tokio::select! {
+        res = registry.clone().serve(socket_path, access) => res?,
+        _ = term.recv() => info!("SIGTERM received, stopping services"),
+        _ = int.recv() => info!("SIGINT received, stopping services"),
+    }
+    registry.stop_all().await;
 
     Ok(())
 }
@@ -0,0 +1,122 @@
+//! Readiness notification for `type = "notify"` services, following the
+//! sd_notify(3) protocol: the service gets a datagram socket in NOTIFY_SOCKET
+//! and sends newline-separated `KEY=VALUE` lines such as `READY=1`,
+//! `STATUS=loading cache` or `STOPPING=1`. Unknown keys are ignored.
+
+use serde::Serialize;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixDatagram as StdUnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::UnixDatagram;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+/// Directory holding one notify socket per notify-type service, inside the
+/// run directory core-daemon passes on (/tmp when started by hand).
+fn notify_dir() -> PathBuf {
+    std::env::var_os("CIRCLEOSD_RUN_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/tmp"))
+        .join("service-registry.notify")
+}
+
+/// What a service has reported since it was last spawned.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub stopping: bool,
+    /// Last `STATUS=` text.
+    pub status: Option<String>,
+}
+
+/// The notify socket of one service and the task reading it. The socket
+/// outlives restarts of the service; the socket file is removed on drop,
+/// unless a newer Notifier of the service has taken its place.
+pub struct Notifier {
+    path: PathBuf,
+    /// Inode of the socket file, to tell it from a later one at `path`.
+    ino: u64,
+    state: Arc<watch::Sender<Readiness>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Notifier {
+    pub fn bind(service: &str) -> std::io::Result<Self> {
+        let dir = notify_dir();
+        std::fs::create_dir_all(&dir)?;
+        // services running as another user must reach their socket, but not
+        // list the others
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o711))?;
+        let path = dir.join(format!("{}.sock", service));
+        let _ = std::fs::remove_file(&path);
+        let std_sock = StdUnixDatagram::bind(&path)?;
+        // owner only; spawn_process hands it to the service's user
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        let ino = std::fs::metadata(&path)?.ino();
+        std_sock.set_nonblocking(true)?;
+        let sock = UnixDatagram::from_std(std_sock)?;
+
+        let state = Arc::new(watch::Sender::new(Readiness::default()));
+        let task = tokio::spawn(read_notifications(service.to_string(), sock, state.clone()));
+        Ok(Self { path, ino, state, task })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Forget what the previous instance reported; called on every spawn.
+    pub fn reset(&self) {
+        self.state.send_replace(Readiness::default());
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        self.state.borrow().clone()
+    }
+
+    /// A receiver to wait on without holding the process lock.
+    pub fn watch(&self) -> watch::Receiver<Readiness> {
+        self.state.subscribe()
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        self.task.abort();
+        if std::fs::metadata(&self.path).is_ok_and(|m| m.ino() == self.ino) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+async fn read_notifications(service: String, sock: UnixDatagram, state: Arc<watch::Sender<Readiness>>) {
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let n = match sock.recv(&mut buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                warn!("notify socket of {} failed: {}", service, e);
+                return;
+            }
+        };
+        let msg = String::from_utf8_lossy(&buf[..n]);
+        for line in msg.lines() {
+            match line.split_once('=') {
+                Some(("READY", "1")) => {
+                    info!("service {} reported ready", service);
+                    state.send_modify(|r| r.ready = true);
+                }
+                Some(("STOPPING", "1")) => {
+                    info!("service {} is stopping", service);
+                    state.send_modify(|r| r.stopping = true);
+                }
+                Some(("STATUS", text)) => {
+                    debug!("service {} status: {}", service, text);
+                    state.send_modify(|r| r.status = Some(text.to_string()));
+                }
+                _ => debug!("service {} sent unhandled notification {:?}", service, line),
+            }
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! Captured stdout/stderr of supervised services. Each service writes to
+//! its own `<name>.log` in the service log directory, rotated by size, and
+//! the most recent lines are kept in memory for `logs` and `follow`.
+
+use chrono::{SecondsFormat, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+/// Used when core-daemon does not pass CIRCLEOSD_SERVICE_LOG_DIR.
+const DEFAULT_LOG_DIR: &str = "var/log/services";
+/// A log file is rotated once it would grow past this size.
+const MAX_BYTES: u64 = 10 * 1024 * 1024;
+/// Rotated files kept per service (`<name>.log.1` is the newest).
+const KEEP: usize = 5;
+/// Lines kept in memory per service.
+const RECENT_LINES: usize = 1000;
+/// Longer lines are split, so output without newlines can't grow a line forever.
+const MAX_LINE: u64 = 16 * 1024;
+
+/// One line of output.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogLine {
+    pub ts: String,
+    /// `stdout` or `stderr`.
+    pub stream: &'static str,
+    pub line: String,
+}
+
+pub struct ServiceLog {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+    tx: broadcast::Sender<LogLine>,
+}
+
+struct Inner {
+    file: Option<File>,
+    size: u64,
+    recent: VecDeque<LogLine>,
+}
+
+/// Directory holding the per-service log files.
+pub fn log_dir() -> PathBuf {
+    std::env::var_os("CIRCLEOSD_SERVICE_LOG_DIR")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_LOG_DIR))
+}
+
+impl ServiceLog {
+    /// Open (or create) the log of `service`. The tail of an existing file
+    /// is loaded so `logs` still has output from before a registry restart.
+    pub fn open(service: &str) -> io::Result<Self> {
+        let dir = log_dir();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.log", service));
+        let recent = read_tail(&path, RECENT_LINES);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        let (tx, _) = broadcast::channel(256);
+        Ok(Self { path, inner: Mutex::new(Inner { file: Some(file), size, recent }), tx })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn push(&self, stream: &'static str, line: String) {
+        let line = LogLine { ts: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true), stream, line };
+        let text = format!("{} {} {}\n", line.ts, line.stream, line.line);
+        let mut inner = self.inner.lock();
+        if let Err(e) = inner.write(&self.path, text.as_bytes()) {
+            warn!("failed to write {}: {}", self.path.display(), e);
+        }
+        if inner.recent.len() == RECENT_LINES {
+            inner.recent.pop_front();
+        }
+        inner.recent.push_back(line.clone());
+        // nobody following is fine
+        let _ = self.tx.send(line);
+    }
+
+    /// The last `n` lines, oldest first.
+    pub fn tail(&self, n: usize) -> Vec<LogLine> {
+        let inner = self.inner.lock();
+        inner.recent.iter().skip(inner.recent.len().saturating_sub(n)).cloned().collect()
+    }
+
+    /// The last `n` lines and every line after them, with nothing lost or
+    /// repeated in between.
+    pub fn follow(&self, n: usize) -> (Vec<LogLine>, broadcast::Receiver<LogLine>) {
+        let inner = self.inner.lock();
+        let rx = self.tx.subscribe();
+        let backlog = inner.recent.iter().skip(inner.recent.len().saturating_sub(n)).cloned().collect();
+        (backlog, rx)
+    }
+}
+
+impl Inner {
+    fn write(&mut self, path: &Path, text: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + text.len() as u64 > MAX_BYTES {
+            self.rotate(path)?;
+        }
+        if self.file.is_none() {
+            self.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+            self.size = 0;
+        }
+        self.file.as_mut().expect("opened above").write_all(text)?;
+        self.size += text.len() as u64;
+        Ok(())
+    }
+
+    /// <name>.log -> .1 -> .2 ..., dropping the one past KEEP.
+    fn rotate(&mut self, path: &Path) -> io::Result<()> {
+        self.file = None;
+        let _ = fs::remove_file(numbered(path, KEEP));
+        for n in (1..KEEP).rev() {
+            let from = numbered(path, n);
+            if from.exists() {
+                fs::rename(from, numbered(path, n + 1))?;
+            }
+        }
+        fs::rename(path, numbered(path, 1))
+    }
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Last `n` lines of a log file written by `push`.
+fn read_tail(path: &Path, n: usize) -> VecDeque<LogLine> {
+    let Ok(text) = fs::read_to_string(path) else { return VecDeque::new() };
+    let lines: Vec<&str> = text.lines().collect();
+    lines[lines.len().saturating_sub(n)..]
+        .iter()
+        .filter_map(|l| {
+            let (ts, rest) = l.split_once(' ')?;
+            let (stream, line) = rest.split_once(' ').unwrap_or((rest, ""));
+            let stream = match stream {
+                "stdout" => "stdout",
+                "stderr" => "stderr",
+                _ => return None,
+            };
+            Some(LogLine { ts: ts.to_string(), stream, line: line.to_string() })
+        })
+        .collect()
+}
+
+/// Copy `pipe` into `log` line by line until the process closes it.
+pub fn capture(log: Arc<ServiceLog>, stream: &'static str, pipe: impl AsyncRead + Unpin + Send + 'static) {
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(pipe);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match (&mut reader).take(MAX_LINE).read_until(b'\n', &mut buf).await {
+                Ok(0) => return,
+                Ok(_) => {
+                    if buf.last() == Some(&b'\n') {
+                        buf.pop();
+                    }
+                    log.push(stream, String::from_utf8_lossy(&buf).into_owned());
+                }
+                Err(e) => {
+                    warn!("reading {} of {}: {}", stream, log.path().display(), e);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("circleosd-output-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("web.log")
+    }
+
+    fn inner() -> Inner {
+        Inner { file: None, size: 0, recent: VecDeque::new() }
+    }
+
+    #[test]
+    fn rotate_shifts_and_drops_the_oldest() {
+        let path = log_path("rotate");
+        fs::write(&path, "current").unwrap();
+        for n in 1..=KEEP {
+            fs::write(numbered(&path, n), n.to_string()).unwrap();
+        }
+        inner().rotate(&path).unwrap();
+        assert!(!path.exists());
+        assert_eq!(fs::read_to_string(numbered(&path, 1)).unwrap(), "current");
+        for n in 2..=KEEP {
+            assert_eq!(fs::read_to_string(numbered(&path, n)).unwrap(), (n - 1).to_string());
+        }
+        assert!(!numbered(&path, KEEP + 1).exists());
+    }
+
+    #[test]
+    fn write_rotates_before_passing_max_bytes() {
+        let path = log_path("write");
+        let mut inner = inner();
+        inner.write(&path, b"first\n").unwrap();
+        assert_eq!(inner.size, 6);
+        // pretend the file is full
+        inner.size = MAX_BYTES - 1;
+        inner.write(&path, b"second\n").unwrap();
+        assert_eq!(fs::read_to_string(numbered(&path, 1)).unwrap(), "first\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+        assert_eq!(inner.size, 7);
+    }
+
+    #[test]
+    fn read_tail_keeps_the_last_lines() {
+        let path = log_path("tail");
+        assert!(read_tail(&path, 10).is_empty());
+        fs::write(
+            &path,
+            "t1 stdout one\nt2 stderr two words\nnot a record\nt3 stdout\nt4 stdout four\n",
+        )
+        .unwrap();
+        let tail: Vec<(String, &str, String)> =
+            read_tail(&path, 4).into_iter().map(|l| (l.ts, l.stream, l.line)).collect();
+        assert_eq!(
+            tail,
+            [
+                ("t2".to_string(), "stderr", "two words".to_string()),
+                ("t3".to_string(), "stdout", String::new()),
+                ("t4".to_string(), "stdout", "four".to_string()),
+            ]
+        );
+    }
+}
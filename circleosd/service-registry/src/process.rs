@@ -1,62 +1,155 @@
 use anyhow::Result;
 use tokio::process::{Child, Command};
-use tracing::{info, warn, error};
+use tracing::{info, warn};
 use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::os::fd::RawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
 use std::sync::Arc;
 use std::time::{Instant, Duration};
 use crate::service::RestartPolicy;
+use crate::notify::Notifier;
+use crate::health::Health;
+use crate::exec::ExecContext;
+use crate::output::{self, ServiceLog};
+use crate::resources::Resources;
+
+/// Backoff before the first restart, and again after a stable run.
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest backoff between restarts.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// How long processes left in a group after SIGKILL are waited for.
+const LEFTOVER_WAIT: Duration = Duration::from_secs(1);
+
+/// How a service is asked to stop: `signal` to its process group, then
+/// SIGKILL once `timeout` has passed.
+#[derive(Debug, Clone, Copy)]
+pub struct StopMode {
+    pub signal: libc::c_int,
+    pub timeout: Duration,
+}
 
 /// A supervised process wrapper that keeps runtime state.
 pub struct SupervisedProcess {
     pub child: Option<Child>,
     pub cmd: Vec<String>,
     pub restart_policy: RestartPolicy,
+    /// environment, working directory and credentials of the child
+    pub exec: ExecContext,
     pub restart_count: u32,
     pub last_start: Option<Instant>,
     /// backoff seconds for restarts
     pub backoff: Duration,
+    /// when the monitor restarted it, oldest first, for max_restarts
+    pub recent_restarts: VecDeque<Instant>,
+    /// listen sockets passed to a socket-activated service as fds 3..
+    pub sockets: Vec<RawFd>,
+    /// notify socket of a `type = "notify"` service
+    pub notify: Option<Notifier>,
+    /// probe results of a service with a health_check, reset on every spawn
+    pub health: Option<Health>,
+    /// where stdout/stderr go; inherited from the registry when `None`
+    pub output: Option<Arc<ServiceLog>>,
+    pub stop_mode: StopMode,
+    /// cgroup and rlimits the child is started with
+    pub resources: Resources,
+    /// process group of the current child, the child's pid
+    pub pgid: Option<libc::pid_t>,
 }
 
 impl SupervisedProcess {
-    pub fn new(cmd: Vec<String>, restart_policy: RestartPolicy) -> Self {
+    pub fn new(cmd: Vec<String>, restart_policy: RestartPolicy, exec: ExecContext, stop_mode: StopMode) -> Self {
         Self {
             child: None,
             cmd,
             restart_policy,
+            exec,
             restart_count: 0,
             last_start: None,
-            backoff: Duration::from_secs(1),
+            backoff: INITIAL_BACKOFF,
+            recent_restarts: VecDeque::new(),
+            sockets: Vec::new(),
+            notify: None,
+            health: None,
+            output: None,
+            stop_mode,
+            resources: Resources::default(),
+            pgid: None,
         }
     }
 
-    /// spawn the child process asynchronously
-    pub async fn spawn(&mut self) -> Result<()> {
+    /// Spawn the child process. Nothing here waits, so it can be called with
+    /// the process locked.
+    pub fn spawn(&mut self) -> Result<()> {
         if self.cmd.is_empty() {
             anyhow::bail!("empty command");
         }
-        let mut command = Command::new(&self.cmd[0]);
+        let mut command = if self.sockets.is_empty() {
+            Command::new(&self.cmd[0])
+        } else {
+            // LISTEN_PID has to be the service's own pid, which only exists after
+            // fork; the shell exports its pid and then execs into the service.
+            let mut sh = Command::new("/bin/sh");
+            sh.arg("-c").arg("LISTEN_PID=$$; export LISTEN_PID; exec \"$0\" \"$@\"").arg(&self.cmd[0]);
+            sh
+        };
+        // core-daemon's token is the registry's own; a service that could
+        // read it could publish service.* events in the registry's name
+        command.env_remove("CIRCLEOSD_PUBLISH_TOKEN");
+        self.resources.apply(&mut command)?;
+        self.exec.apply(&mut command);
+        if !self.sockets.is_empty() {
+            pass_sockets(&mut command, &self.sockets);
+        }
         if self.cmd.len() > 1 {
             command.args(&self.cmd[1..]);
         }
-        // Inherit stdio (for now). You can redirect to logs later.
-        command.stdout(std::process::Stdio::inherit());
-        command.stderr(std::process::Stdio::inherit());
+        if let Some(notify) = &self.notify {
+            notify.reset();
+            command.env("NOTIFY_SOCKET", notify.path());
+        }
+        if self.output.is_some() {
+            command.stdout(std::process::Stdio::piped());
+            command.stderr(std::process::Stdio::piped());
+        } else {
+            command.stdout(std::process::Stdio::inherit());
+            command.stderr(std::process::Stdio::inherit());
+        }
+        // its own group, so stopping it reaches whatever it forked as well
+        command.process_group(0);
 
         info!("spawning process: {:?}", self.cmd);
-        let child = command.spawn()?;
+        let mut child = command.spawn()?;
+        if let Some(log) = &self.output {
+            if let Some(stdout) = child.stdout.take() {
+                output::capture(log.clone(), "stdout", stdout);
+            }
+            if let Some(stderr) = child.stderr.take() {
+                output::capture(log.clone(), "stderr", stderr);
+            }
+        }
+        self.pgid = child.id().map(|pid| pid as libc::pid_t);
         self.child = Some(child);
         self.last_start = Some(Instant::now());
+        if let Some(health) = &mut self.health {
+            *health = Health::default();
+        }
         self.restart_count = self.restart_count.saturating_add(1);
         Ok(())
     }
 
     /// Poll whether the child is still running. Returns Ok(Some(exit_status)) if exited,
-    /// Ok(None) if still running.
-    pub async fn poll_exit(&mut self) -> Result<Option<std::process::ExitStatus>> {
+    /// Ok(None) if still running. Anything the child left running in its
+    /// group is killed once it has exited.
+    pub fn poll_exit(&mut self) -> Result<Option<std::process::ExitStatus>> {
         if let Some(child) = &mut self.child {
             match child.try_wait()? {
                 Some(status) => {
                     self.child = None;
+                    if let Some(pgid) = self.pgid.take() {
+                        tokio::spawn(kill_leftovers(pgid));
+                    }
                     Ok(Some(status))
                 }
                 None => Ok(None),
@@ -66,20 +159,46 @@ impl SupervisedProcess {
         }
     }
 
-    /// Kill the child if running.
-    pub async fn kill(&mut self) -> Result<()> {
-        if let Some(child) = &mut self.child {
-            match child.kill().await {
-                Ok(_) => {
-                    info!("killed child {:?}", self.cmd);
-                }
-                Err(e) => {
-                    warn!("failed to kill child: {:?}", e);
-                }
-            }
-            self.child = None;
+    /// Whether the child has exited. The status stays with the child, so
+    /// `poll_exit` still reports it afterwards.
+    pub fn has_exited(&mut self) -> bool {
+        matches!(self.child.as_mut().map(|c| c.try_wait()), Some(Ok(Some(_))))
+    }
+
+    /// Take the child out for `stop_child`, leaving the process idle.
+    pub fn take_child(&mut self) -> Option<Child> {
+        self.pgid = None;
+        self.child.take()
+    }
+
+    /// Count a restart, unless `max` restarts already happened within
+    /// `window`; then the service is crash-looping and must not be restarted.
+    pub fn allow_restart(&mut self, max: Option<u32>, window: Duration) -> bool {
+        let now = Instant::now();
+        while self.recent_restarts.front().is_some_and(|t| now.duration_since(*t) > window) {
+            self.recent_restarts.pop_front();
+        }
+        if max.is_some_and(|max| self.recent_restarts.len() >= max as usize) {
+            return false;
+        }
+        self.recent_restarts.push_back(now);
+        true
+    }
+
+    /// Backoff to wait before this restart; the next one waits twice as
+    /// long, up to MAX_BACKOFF.
+    pub fn next_backoff(&mut self) -> Duration {
+        let backoff = self.backoff;
+        self.backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+        backoff
+    }
+
+    /// Start over from INITIAL_BACKOFF if the last run lasted `stable`: it
+    /// was not part of a crash loop.
+    pub fn reset_backoff_if_stable(&mut self, stable: Duration) {
+        if self.last_start.is_some_and(|t| t.elapsed() >= stable) {
+            self.backoff = INITIAL_BACKOFF;
         }
-        Ok(())
     }
 
     /// Decide if process should be restarted based on policy and exit status.
@@ -98,4 +217,215 @@ impl SupervisedProcess {
     }
 }
 
+/// "exited with code 1", "killed by signal 9", for logs and the failed state.
+pub fn describe_exit(status: ExitStatus) -> String {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exited with code {}", code),
+        (None, Some(sig)) => format!("killed by signal {}", sig),
+        _ => status.to_string(),
+    }
+}
+
+/// Send `mode.signal` to the group of `child`, wait up to `mode.timeout`
+/// for the child to exit, then SIGKILL the group. Whatever is left in the
+/// group after the child exited is killed and reaped as well.
+pub async fn stop_child(cmd: &[String], mut child: Child, mode: StopMode) -> Option<ExitStatus> {
+    let Some(pgid) = child.id().map(|pid| pid as libc::pid_t) else {
+        // already reaped
+        return child.wait().await.ok();
+    };
+    signal_group(pgid, mode.signal);
+    if mode.signal != libc::SIGCONT {
+        // a stopped process only acts on the signal once continued
+        signal_group(pgid, libc::SIGCONT);
+    }
+    let status = match tokio::time::timeout(mode.timeout, child.wait()).await {
+        Ok(status) => status.ok(),
+        Err(_) => {
+            warn!("{:?} still running {:?} after signal {}; sending SIGKILL", cmd, mode.timeout, mode.signal);
+            signal_group(pgid, libc::SIGKILL);
+            child.wait().await.ok()
+        }
+    };
+    kill_leftovers(pgid).await;
+    match status {
+        Some(status) => info!("stopped {:?} ({})", cmd, describe_exit(status)),
+        None => warn!("stopped {:?}, exit status unknown", cmd),
+    }
+    status
+}
+
+fn signal_group(pgid: libc::pid_t, signal: libc::c_int) {
+    // Safety: killpg has no memory-safety preconditions.
+    if unsafe { libc::killpg(pgid, signal) } != 0 {
+        let err = std::io::Error::last_os_error();
+        // ESRCH: the group is already empty
+        if err.raw_os_error() != Some(libc::ESRCH) {
+            warn!("failed to signal process group {}: {}", pgid, err);
+        }
+    }
+}
+
+/// SIGKILL what is left in process group `pgid` once its leader is gone,
+/// and reap those that were reparented to the registry. The rest are
+/// reaped by init.
+async fn kill_leftovers(pgid: libc::pid_t) {
+    let deadline = Instant::now() + LEFTOVER_WAIT;
+    let me = std::process::id() as libc::pid_t;
+    let mut reported = false;
+    loop {
+        // a zombie someone else has to reap is already dead
+        let members: Vec<_> = group_members(pgid).into_iter().filter(|m| !m.2 || m.1 == me).collect();
+        if members.is_empty() {
+            return;
+        }
+        if !reported {
+            info!("killing {} leftover process(es) of group {}", members.len(), pgid);
+            reported = true;
+            signal_group(pgid, libc::SIGKILL);
+        }
+        for (pid, ppid, _) in members {
+            if ppid == me {
+                // Safety: waitpid on a specific pid that is not a tokio Child.
+                unsafe { libc::waitpid(pid, std::ptr::null_mut(), libc::WNOHANG) };
+            }
+        }
+        if Instant::now() >= deadline {
+            warn!("process group {} still has members after SIGKILL", pgid);
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Live processes in group `pgid`.
+pub fn group_pids(pgid: libc::pid_t) -> Vec<libc::pid_t> {
+    group_members(pgid).into_iter().filter(|m| !m.2).map(|m| m.0).collect()
+}
+
+/// (pid, ppid, zombie) of every process in group `pgid`.
+fn group_members(pgid: libc::pid_t) -> Vec<(libc::pid_t, libc::pid_t, bool)> {
+    let Ok(dir) = std::fs::read_dir("/proc") else { return Vec::new() };
+    dir.filter_map(|entry| {
+        let pid: libc::pid_t = entry.ok()?.file_name().to_str()?.parse().ok()?;
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        // "pid (comm) state ppid pgrp ..."; comm may hold spaces and parens
+        let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+        let zombie = *fields.first()? == "Z";
+        let ppid = fields.get(1)?.parse().ok()?;
+        let pgrp: libc::pid_t = fields.get(2)?.parse().ok()?;
+        (pgrp == pgid).then_some((pid, ppid, zombie))
+    })
+    .collect()
+}
+
+/// A signal by name ("SIGTERM", "TERM", case-insensitive) or number.
+pub fn parse_signal(s: &str) -> Result<libc::c_int, String> {
+    if let Ok(n) = s.parse::<libc::c_int>() {
+        return if (1..=64).contains(&n) { Ok(n) } else { Err(format!("no signal {}", n)) };
+    }
+    let upper = s.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    Ok(match name {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        "CONT" => libc::SIGCONT,
+        "WINCH" => libc::SIGWINCH,
+        _ => return Err(format!("unknown signal `{}`", s)),
+    })
+}
+
+/// Hand `fds` to the child as 3, 4, ... and announce them in `LISTEN_FDS`.
+fn pass_sockets(command: &mut Command, fds: &[RawFd]) {
+    let mut fds = fds.to_vec();
+    let first = 3;
+    let count = fds.len() as RawFd;
+    command.env("LISTEN_FDS", count.to_string());
+    // Safety: only fcntl and dup2 run between fork and exec, both async-signal-safe,
+    // and `fds` is rewritten in place so nothing is allocated.
+    unsafe {
+        command.pre_exec(move || {
+            // Move every socket above the target range first so that placing one
+            // never overwrites another. The copies are close-on-exec.
+            for fd in fds.iter_mut() {
+                *fd = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, first + count);
+                if *fd < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            // dup2 clears close-on-exec on the target, so these survive exec.
+            for (i, fd) in fds.iter().enumerate() {
+                if libc::dup2(*fd, first + i as RawFd) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
 pub type SharedProcess = Arc<Mutex<SupervisedProcess>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process() -> SupervisedProcess {
+        let stop_mode = StopMode { signal: libc::SIGTERM, timeout: Duration::from_secs(1) };
+        SupervisedProcess::new(vec!["/bin/false".into()], RestartPolicy::Always, ExecContext::default(), stop_mode)
+    }
+
+    #[test]
+    fn allow_restart_counts_within_the_window() {
+        let mut p = process();
+        let window = Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(p.allow_restart(Some(3), window));
+        }
+        assert!(!p.allow_restart(Some(3), window));
+        assert_eq!(p.recent_restarts.len(), 3);
+
+        // restarts older than the window no longer count
+        let old = Instant::now() - Duration::from_secs(120);
+        p.recent_restarts.iter_mut().for_each(|t| *t = old);
+        assert!(p.allow_restart(Some(3), window));
+        assert_eq!(p.recent_restarts.len(), 1);
+
+        // without max_restarts it never gives up
+        assert!((0..10).all(|_| p.allow_restart(None, window)));
+    }
+
+    #[test]
+    fn backoff_doubles_and_resets_after_a_stable_run() {
+        let mut p = process();
+        let waits: Vec<_> = (0..7).map(|_| p.next_backoff().as_secs()).collect();
+        assert_eq!(waits, [1, 2, 4, 8, 16, 30, 30]);
+
+        let stable = Duration::from_secs(30);
+        p.last_start = Some(Instant::now());
+        p.reset_backoff_if_stable(stable);
+        assert_eq!(p.backoff, MAX_BACKOFF);
+
+        p.last_start = Some(Instant::now() - Duration::from_secs(31));
+        p.reset_backoff_if_stable(stable);
+        assert_eq!(p.backoff, INITIAL_BACKOFF);
+    }
+
+    #[test]
+    fn signals() {
+        assert_eq!(parse_signal("SIGTERM"), Ok(libc::SIGTERM));
+        assert_eq!(parse_signal("term"), Ok(libc::SIGTERM));
+        assert_eq!(parse_signal("SigInt"), Ok(libc::SIGINT));
+        assert_eq!(parse_signal("usr1"), Ok(libc::SIGUSR1));
+        assert_eq!(parse_signal("9"), Ok(libc::SIGKILL));
+        assert_eq!(parse_signal("64"), Ok(64));
+        for bad in ["0", "65", "-15", "SIG", "SIGFOO", "TERM ", ""] {
+            assert!(parse_signal(bad).is_err(), "{} should not parse", bad);
+        }
+    }
+}
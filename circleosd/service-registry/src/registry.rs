@@ -0,0 +1,1505 @@
+use anyhow::Result;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tracing::{info, warn, error};
+
+use crate::service::{
+    self, RestartPolicy, ServiceSpec, ServiceType, DEFAULT_READY_TIMEOUT_SECS, DEFAULT_RESTART_WINDOW_SECS,
+    DEFAULT_STABLE_SECS,
+};
+use crate::process::{self as supervised, SupervisedProcess, SharedProcess};
+use crate::health::{self, Health, HealthStatus};
+use crate::events;
+use circleosd_proto::jsonrpc::{self, RpcError, RpcReply};
+use circleosd_proto::peercred::{AccessList, Peer};
+use crate::activation::Listeners;
+use crate::notify::Notifier;
+use crate::exec::ExecContext;
+use crate::output::{LogLine, ServiceLog};
+use crate::deps;
+use crate::resources::{Limits, Resources};
+use crate::config::{self, Entry};
+use crate::template;
+use crate::timer::{self, Timer, TimerSpec};
+
+use std::os::fd::RawFd;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use tokio::sync::broadcast;
+
+/// How often an activated service is checked for having exited.
+const ACTIVATION_POLL: Duration = Duration::from_millis(500);
+/// Pause before retrying a spawn that failed on activation.
+const ACTIVATION_RETRY: Duration = Duration::from_secs(5);
+/// How often a starting notify service is checked for having exited.
+const READY_POLL: Duration = Duration::from_millis(250);
+/// Lines `logs` and `follow` return when the request does not say.
+const DEFAULT_LOG_LINES: usize = 100;
+/// Longest a timer sleeps before looking at the clock again, which may have
+/// been set in the meantime.
+const TIMER_RECHECK: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct Registry {
+    // map name -> spec
+    services: Arc<RwLock<HashMap<String, ServiceSpec>>>,
+    // map name -> supervised process
+    processes: Arc<RwLock<HashMap<String, SharedProcess>>>,
+    // map name -> bound sockets of a socket-activated service that is started
+    activations: Arc<RwLock<HashMap<String, Activation>>>,
+    // map name -> captured output, kept across restarts until unregistered
+    outputs: Arc<RwLock<HashMap<String, Arc<ServiceLog>>>>,
+    // map name -> why the monitor gave up on it; cleared by reset_failed
+    failed: Arc<RwLock<HashMap<String, Failure>>>,
+    // services added by `apply` (or services.toml), which a later apply may remove
+    managed: Arc<RwLock<HashSet<String>>>,
+    // instances registered by starting them from a template; updated and
+    // removed along with it
+    instances: Arc<RwLock<HashSet<String>>>,
+    // one `apply` or `scale` at a time
+    applying: Arc<tokio::sync::Mutex<()>>,
+    // map name -> armed timer of a service that has one
+    timers: Arc<RwLock<HashMap<String, Armed>>>,
+    // map name -> last time its timer fired; saved for persistent timers
+    last_runs: Arc<RwLock<HashMap<String, DateTime<Utc>>>>,
+}
+
+/// A service that crashed more than max_restarts times within its window.
+#[derive(Debug, Clone, Serialize)]
+struct Failure {
+    /// How the last run ended.
+    reason: String,
+    since: String,
+    /// Restarts within the window when it was given up on.
+    restarts: usize,
+}
+
+/// What `apply` changes, by service name.
+#[derive(Debug, Default, Serialize)]
+struct Plan {
+    add: Vec<String>,
+    update: Vec<Change>,
+    remove: Vec<String>,
+    unchanged: Vec<String>,
+}
+
+/// A service whose spec differs from the registered one; it is restarted
+/// if running.
+#[derive(Debug, Serialize)]
+struct Change {
+    name: String,
+    /// Spec keys that differ.
+    changed: Vec<String>,
+}
+
+/// What `scale` did.
+#[derive(Debug, Default, Serialize)]
+struct Scaled {
+    started: Vec<String>,
+    stopped: Vec<String>,
+    errors: Vec<String>,
+}
+
+/// Sockets held for a socket-activated service and the task waiting on them.
+/// Dropping it stops listening.
+struct Activation {
+    listeners: Arc<Listeners>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+/// The task running a service's timer. Dropping it disarms the timer.
+struct Armed {
+    spec: TimerSpec,
+    next: Arc<parking_lot::Mutex<Option<DateTime<Utc>>>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for Armed {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// What a `follow` request leaves for its connection to stream.
+struct Following {
+    backlog: Vec<LogLine>,
+    rx: broadcast::Receiver<LogLine>,
+}
+
+impl Drop for Activation {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.listeners.remove_files();
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action")]
+enum Request {
+    #[serde(rename = "register")]
+    Register { spec: Box<ServiceSpec> },
+
+    #[serde(rename = "unregister")]
+    Unregister { name: String },
+
+    #[serde(rename = "start")]
+    Start { name: String },
+
+    #[serde(rename = "stop")]
+    Stop { name: String },
+
+    #[serde(rename = "list")]
+    List {},
+
+    #[serde(rename = "status")]
+    Status { name: String },
+
+    /// Last `lines` lines of the service's output.
+    #[serde(rename = "logs")]
+    Logs { name: String, lines: Option<usize> },
+
+    /// Like `logs`, then keep the connection open and send each new line.
+    #[serde(rename = "follow")]
+    Follow { name: String, lines: Option<usize> },
+
+    /// Clear the failed state so the service can be started again.
+    #[serde(rename = "reset_failed")]
+    ResetFailed { name: String },
+
+    /// What the service requires, wants and starts after, as a tree; with
+    /// `reverse`, the services that name it instead.
+    #[serde(rename = "deps")]
+    Deps {
+        name: String,
+        #[serde(default)]
+        reverse: bool,
+    },
+
+    /// Make the services added by `apply` match `services` (by default the
+    /// services file): add, update and remove, or with `dry_run` only plan.
+    #[serde(rename = "apply")]
+    Apply {
+        services: Option<Vec<Entry>>,
+        #[serde(default)]
+        dry_run: bool,
+    },
+
+    /// Set a template's replicas and run its instances 1..=replicas, removing
+    /// the numbered instances above that.
+    #[serde(rename = "scale")]
+    Scale { name: String, replicas: u32 },
+
+    /// Services with a timer and when it fires next and fired last.
+    #[serde(rename = "list_timers")]
+    ListTimers {},
+
+    #[serde(rename = "ping")]
+    Ping {},
+}
+
+/// Action names, also accepted as JSON-RPC 2.0 method names.
+const METHODS: &[&str] = &["register", "unregister", "start", "stop", "list", "status", "logs", "follow", "reset_failed", "deps", "apply", "scale", "list_timers", "ping"];
+
+#[derive(Debug, Serialize)]
+struct Response {
+    ok: bool,
+    message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<serde_json::Value>,
+}
+
+impl RpcReply for Response {
+    fn invalid(message: String) -> Self {
+        Response { ok: false, message: Some(message), data: None }
+    }
+
+    fn into_jsonrpc(self) -> Result<serde_json::Value, RpcError> {
+        jsonrpc::split_reply(self.ok, self.message, self.data, jsonrpc::SERVER_ERROR)
+    }
+}
+
+impl Registry {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            services: Arc::new(RwLock::new(HashMap::new())),
+            processes: Arc::new(RwLock::new(HashMap::new())),
+            activations: Arc::new(RwLock::new(HashMap::new())),
+            outputs: Arc::new(RwLock::new(HashMap::new())),
+            failed: Arc::new(RwLock::new(HashMap::new())),
+            managed: Arc::new(RwLock::new(HashSet::new())),
+            instances: Arc::new(RwLock::new(HashSet::new())),
+            applying: Arc::new(tokio::sync::Mutex::new(())),
+            timers: Arc::new(RwLock::new(HashMap::new())),
+            last_runs: Arc::new(RwLock::new(timer::load_runs())),
+        })
+    }
+
+    pub async fn serve(self: Arc<Self>, socket_path: PathBuf, access: AccessList) -> Result<()> {
+        // ensure old socket removed
+        let _ = std::fs::remove_file(&socket_path);
+        let listener = UnixListener::bind(&socket_path)?;
+        info!("service-registry listening on {}", socket_path.display());
+
+        // owner only
+        access.apply_to_socket(&socket_path)?;
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let peer = match Peer::of(&stream) {
+                Ok(peer) if access.allows(&peer) => peer,
+                Ok(peer) => {
+                    warn!("rejected connection from {}", peer);
+                    let _ = stream.try_write(b"{\"ok\":false,\"message\":\"permission denied: uid not allowed\"}\n");
+                    continue;
+                }
+                Err(e) => {
+                    warn!("rejected connection: cannot read peer credentials: {}", e);
+                    continue;
+                }
+            };
+            let reg = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = reg.handle_connection(stream, peer).await {
+                    error!("connection handler error: {:?}", e);
+                }
+            });
+        }
+    }
+
+    /// After a successful `follow` the connection only carries log lines.
+    async fn handle_connection(self: Arc<Self>, stream: UnixStream, peer: Peer) -> Result<()> {
+        tracing::debug!("connection from {}", peer);
+        let (r, mut w) = stream.into_split();
+        let mut reader = BufReader::new(r).lines();
+        let follow = std::sync::Mutex::new(None);
+
+        while let Some(line) = reader.next_line().await? {
+            if line.trim().is_empty() { continue; }
+            tracing::debug!("registry rpc <- [{}] {}", peer, line);
+            let out = jsonrpc::handle_line(&line, METHODS, |rq| self.clone().process(rq, &follow)).await;
+            if let Some(out) = out {
+                w.write_all(out.as_bytes()).await?;
+                w.write_all(b"\n").await?;
+            }
+
+            let following = follow.lock().unwrap_or_else(|e| e.into_inner()).take();
+            if let Some(Following { backlog, mut rx }) = following {
+                for line in backlog {
+                    w.write_all(serde_json::json!(line).to_string().as_bytes()).await?;
+                    w.write_all(b"\n").await?;
+                }
+                loop {
+                    let out = tokio::select! {
+                        line = rx.recv() => match line {
+                            Ok(line) => serde_json::json!(line),
+                            Err(broadcast::error::RecvError::Lagged(n)) => serde_json::json!({ "lagged": n }),
+                            // unregistered
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        },
+                        // anything the follower sends is ignored; EOF ends the stream
+                        line = reader.next_line() => match line? {
+                            Some(_) => continue,
+                            None => break,
+                        },
+                    };
+                    w.write_all(out.to_string().as_bytes()).await?;
+                    w.write_all(b"\n").await?;
+                }
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn process(self: Arc<Self>, rq: Request, follow: &std::sync::Mutex<Option<Following>>) -> Response {
+        match rq {
+            Request::Register { spec } => {
+                let name = spec.name.clone();
+                let cycle = { deps::check_cycles(&self.services.read(), &spec) };
+                if let Err(e) = check_spec(&spec) {
+                    Response { ok: false, message: Some(e), data: None }
+                } else if self.services.read().contains_key(&name) {
+                    Response { ok: false, message: Some("service exists".into()), data: Some(json!({ "code": "exists" })) }
+                } else if let Err(e) = cycle {
+                    Response { ok: false, message: Some(format!("invalid spec: {}", e)), data: None }
+                } else {
+                    self.put_spec(*spec);
+                    Response { ok: true, message: Some("registered".into()), data: None }
+                }
+            }
+
+            Request::Unregister { name } => {
+                // stop if running
+                let _ = self.stop_service_internal(&name).await;
+                if template::is_template(&name) {
+                    self.remove_instances(&name).await;
+                }
+                self.forget(&name);
+                Response { ok: true, message: Some("unregistered".into()), data: None }
+            }
+
+            Request::Start { name } => {
+                match self.start(&name).await {
+                    Ok(started) => {
+                        let data = serde_json::json!({ "started": started });
+                        Response { ok: true, message: Some("started".into()), data: Some(data) }
+                    }
+                    Err(e) => Response { ok: false, message: Some(format!("start failed: {}", e)), data: None },
+                }
+            }
+
+            Request::Stop { name } => {
+                match self.stop(&name).await {
+                    Ok(stopped) => {
+                        let data = serde_json::json!({ "stopped": stopped });
+                        Response { ok: true, message: Some("stopped".into()), data: Some(data) }
+                    }
+                    Err(e) => Response { ok: false, message: Some(format!("stop failed: {}", e)), data: None },
+                }
+            }
+
+            Request::List {} => {
+                let svc_list: Vec<ServiceSpec> = { self.services.read().values().cloned().collect() };
+                // each spec with its state
+                let list: Vec<serde_json::Value> = svc_list
+                    .into_iter()
+                    .filter_map(|spec| {
+                        let state = self.state(&spec.name);
+                        let mut entry = serde_json::to_value(spec).ok()?;
+                        entry.as_object_mut()?.insert("state".into(), state.into());
+                        Some(entry)
+                    })
+                    .collect();
+                Response { ok: true, message: None, data: Some(serde_json::Value::Array(list)) }
+            }
+
+            Request::Status { name } if template::is_template(&name) => self.template_status(&name),
+
+            Request::Status { name } => {
+                let listening = self.activations.read().contains_key(&name);
+                let proc_map = self.processes.read();
+                if let Some(proc) = proc_map.get(&name) {
+                    let locked = proc.lock();
+                    let running = locked.child.is_some();
+                    let since = locked.last_start.map(|i| i.elapsed().as_secs());
+                    // a simple service counts as ready once running
+                    let readiness = locked.notify.as_ref().map(|n| n.readiness()).unwrap_or_default();
+                    let data = serde_json::json!({
+                        "name": name,
+                        "running": running,
+                        "ready": running && (locked.notify.is_none() || readiness.ready),
+                        "stopping": readiness.stopping,
+                        "status_text": readiness.status,
+                        "listening": listening,
+                        "health": locked.health,
+                        "usage": locked.resources.usage(locked.pgid),
+                        "restart_count": locked.restart_count,
+                        "last_start_secs_ago": since,
+                    });
+                    Response { ok: true, message: None, data: Some(data) }
+                } else if listening {
+                    // socket-activated and idle: nothing runs until a client connects
+                    let data = serde_json::json!({
+                        "name": name,
+                        "running": false,
+                        "listening": true,
+                    });
+                    Response { ok: true, message: Some("waiting for connections".into()), data: Some(data) }
+                } else if let Some(failure) = self.failed.read().get(&name) {
+                    let data = serde_json::json!({
+                        "name": name,
+                        "running": false,
+                        "failed": failure,
+                    });
+                    Response { ok: true, message: Some("failed".into()), data: Some(data) }
+                } else {
+                    Response { ok: false, message: Some("not running".into()), data: None }
+                }
+            }
+
+            Request::Logs { name, lines } => match self.output(&name) {
+                Ok(log) => {
+                    let data = serde_json::to_value(log.tail(lines.unwrap_or(DEFAULT_LOG_LINES))).ok();
+                    Response { ok: true, message: None, data }
+                }
+                Err(e) => Response { ok: false, message: Some(e.to_string()), data: None },
+            },
+
+            Request::Follow { name, lines } => match self.output(&name) {
+                Ok(log) => {
+                    let (backlog, rx) = log.follow(lines.unwrap_or(DEFAULT_LOG_LINES));
+                    *follow.lock().unwrap_or_else(|e| e.into_inner()) = Some(Following { backlog, rx });
+                    Response { ok: true, message: Some("following".into()), data: None }
+                }
+                Err(e) => Response { ok: false, message: Some(e.to_string()), data: None },
+            },
+
+            Request::ResetFailed { name } => {
+                if self.failed.write().remove(&name).is_some() {
+                    info!("service {} reset from failed state", name);
+                    Response { ok: true, message: Some("reset".into()), data: None }
+                } else {
+                    Response { ok: false, message: Some("service is not in failed state".into()), data: None }
+                }
+            }
+
+            Request::Deps { name, reverse } => {
+                // a copy, as `state` takes the lock again
+                let services = self.services.read().clone();
+                if services.contains_key(&name) {
+                    let data = deps::tree(&services, &name, reverse, &|svc| self.state(svc));
+                    Response { ok: true, message: None, data: Some(data) }
+                } else {
+                    Response { ok: false, message: Some("service not found".into()), data: None }
+                }
+            }
+
+            Request::Apply { services, dry_run } => {
+                let entries = match services {
+                    Some(entries) => Ok(entries),
+                    None => config::load(&config::path().0),
+                };
+                match entries {
+                    Ok(entries) => self.apply(entries, dry_run).await,
+                    Err(e) => Response { ok: false, message: Some(format!("cannot read services: {}", e)), data: None },
+                }
+            }
+
+            Request::Scale { name, replicas } => {
+                let _applying = self.applying.lock().await;
+                let found = if template::is_template(&name) {
+                    self.services.write().get_mut(&name).map(|spec| spec.replicas = Some(replicas)).is_some()
+                } else {
+                    false
+                };
+                if !found {
+                    let message = format!("{} is not a registered template (a name ending in @)", name);
+                    return Response { ok: false, message: Some(message), data: None };
+                }
+                info!("scaling {} to {} replica(s)", name, replicas);
+                let scaled = self.scale(&name, replicas).await;
+                events::publish("service.scaled", serde_json::json!({ "name": name, "replicas": replicas }));
+                let message = if scaled.errors.is_empty() {
+                    "scaled".to_string()
+                } else {
+                    format!("scaled with {} error(s)", scaled.errors.len())
+                };
+                let ok = scaled.errors.is_empty();
+                let data = serde_json::json!({
+                    "replicas": replicas,
+                    "started": scaled.started,
+                    "stopped": scaled.stopped,
+                    "errors": scaled.errors,
+                });
+                Response { ok, message: Some(message), data: Some(data) }
+            }
+
+            Request::ListTimers {} => {
+                let mut timers: Vec<(String, TimerSpec, Option<DateTime<Utc>>)> = {
+                    let timers = self.timers.read();
+                    timers.iter().map(|(name, t)| (name.clone(), t.spec.clone(), *t.next.lock())).collect()
+                };
+                // soonest first; timers with nothing left last
+                timers.sort_by_key(|(name, _, next)| (next.is_none(), *next, name.clone()));
+                let last_runs = self.last_runs.read().clone();
+                let now = Utc::now();
+                let list: Vec<_> = timers
+                    .into_iter()
+                    .map(|(name, spec, next)| {
+                        serde_json::json!({
+                            "name": name,
+                            "on": spec.on,
+                            "jitter": spec.jitter,
+                            "persistent": spec.persistent,
+                            "next": next.map(rfc3339),
+                            "next_in_secs": next.map(|n| (n - now).num_seconds().max(0)),
+                            "last": last_runs.get(&name).copied().filter(|at| spec.persistent || *at >= timer::boot()).map(rfc3339),
+                            "state": self.state(&name),
+                        })
+                    })
+                    .collect();
+                Response { ok: true, message: None, data: Some(serde_json::json!(list)) }
+            }
+
+            Request::Ping {} => Response { ok: true, message: Some("pong".into()), data: None },
+        }
+    }
+
+    /// Drop everything kept about a service that is no longer registered.
+    fn forget(&self, name: &str) {
+        self.services.write().remove(name);
+        // ends any `follow` of it; the log file stays
+        self.outputs.write().remove(name);
+        self.failed.write().remove(name);
+        self.managed.write().remove(name);
+        self.instances.write().remove(name);
+        self.timers.write().remove(name);
+    }
+
+    /// Register or replace a spec, and arm, re-arm or disarm its timer.
+    fn put_spec(self: &Arc<Self>, spec: ServiceSpec) {
+        let name = spec.name.clone();
+        let timer = spec.timer.clone();
+        self.services.write().insert(name.clone(), spec);
+        self.arm(&name, timer);
+    }
+
+    /// Run `name`'s timer; an unchanged timer keeps running as it was. A
+    /// template's timer is armed for each of its instances instead.
+    fn arm(self: &Arc<Self>, name: &str, spec: Option<TimerSpec>) {
+        let mut timers = self.timers.write();
+        let Some(spec) = spec.filter(|_| !template::is_template(name)) else {
+            timers.remove(name);
+            return;
+        };
+        if timers.get(name).is_some_and(|armed| armed.spec == spec) {
+            return;
+        }
+        let timer = match Timer::from_spec(&spec) {
+            Ok(timer) => timer,
+            Err(e) => {
+                // register and apply check it first
+                error!("timer of {} not armed: {}", name, e);
+                timers.remove(name);
+                return;
+            }
+        };
+        let next = Arc::new(parking_lot::Mutex::new(None));
+        let task = tokio::spawn(self.clone().timer_loop(name.to_string(), timer, next.clone()));
+        timers.insert(name.to_string(), Armed { spec, next, task });
+        info!("timer of {} armed", name);
+    }
+
+    /// Start `name` each time its timer fires. If the last run is still going
+    /// the trigger is skipped, so runs of a job never overlap.
+    async fn timer_loop(self: Arc<Self>, name: String, timer: Timer, next: Arc<parking_lot::Mutex<Option<DateTime<Utc>>>>) {
+        let armed = Utc::now();
+        loop {
+            // a last run from before this boot only counts for a persistent timer
+            let last = { self.last_runs.read().get(&name).copied() }.filter(|at| timer.persistent || *at >= timer::boot());
+            let Some(at) = timer.next(last, armed, Utc::now()) else {
+                *next.lock() = None;
+                info!("timer of {} has no triggers left", name);
+                return;
+            };
+            // jitter only delays this run; the next one is counted from `at`
+            let due = at + timer.delay();
+            *next.lock() = Some(due);
+            while let Ok(left) = (due - Utc::now()).to_std() {
+                tokio::time::sleep(left.min(TIMER_RECHECK)).await;
+            }
+
+            self.last_runs.write().insert(name.clone(), at);
+            if timer.persistent {
+                let runs = self.last_runs.read().clone();
+                if let Err(e) = timer::save_runs(&runs) {
+                    warn!("cannot save {}: {}", timer::state_file().display(), e);
+                }
+            }
+            let skipped = self.is_up(&name);
+            if skipped {
+                info!("timer of {} fired while it is still running; skipped", name);
+            } else {
+                info!("timer of {} fired, starting it", name);
+                if let Err(e) = self.start_service_internal(&name).await {
+                    warn!("timer could not start {}: {}", name, e);
+                }
+            }
+            events::publish("service.timer", serde_json::json!({ "name": name, "skipped": skipped }));
+        }
+    }
+
+    /// `status` of a template: its replicas and the state of each instance.
+    fn template_status(&self, name: &str) -> Response {
+        let spec_opt = { self.services.read().get(name).cloned() };
+        let Some(spec) = spec_opt else {
+            return Response { ok: false, message: Some("service not found".into()), data: None };
+        };
+        let instances: Vec<_> = self
+            .instances_of(name)
+            .into_iter()
+            .map(|inst| serde_json::json!({ "name": inst, "state": self.state(&inst) }))
+            .collect();
+        let data = serde_json::json!({
+            "name": name,
+            "template": true,
+            "replicas": spec.replicas,
+            "instances": instances,
+        });
+        Response { ok: true, message: Some("template".into()), data: Some(data) }
+    }
+
+    /// Instances created from `template`, named ones first, then replicas
+    /// by number.
+    fn instances_of(&self, template: &str) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .instances
+            .read()
+            .iter()
+            .filter(|name| template::split(name).is_some_and(|(t, _)| t == template))
+            .cloned()
+            .collect();
+        names.sort_by_key(|name| (template::split(name).and_then(|(_, i)| template::replica(i)), name.clone()));
+        names
+    }
+
+    /// Register the template instances that starting `name` needs: `name`
+    /// itself and, transitively, what it requires or wants. Only an invalid
+    /// `name` is an error; a bad dependency is left for start_order to report.
+    fn materialize(self: &Arc<Self>, name: &str) -> Result<()> {
+        let mut todo = vec![name.to_string()];
+        let mut seen = HashSet::new();
+        while let Some(svc) = todo.pop() {
+            if !seen.insert(svc.clone()) {
+                continue;
+            }
+            let spec_opt = { self.services.read().get(&svc).cloned() };
+            let spec = match spec_opt {
+                Some(spec) => spec,
+                None => match self.instance_spec(&svc) {
+                    Ok(Some(spec)) => {
+                        info!("service {} created from template", svc);
+                        self.instances.write().insert(svc.clone());
+                        self.put_spec(spec.clone());
+                        spec
+                    }
+                    Ok(None) => continue,
+                    Err(e) if svc == name => anyhow::bail!(e),
+                    Err(e) => {
+                        warn!("cannot create {} (needed by {}): {}", svc, name, e);
+                        continue;
+                    }
+                },
+            };
+            todo.extend(spec.requires.iter().chain(&spec.wants).cloned());
+        }
+        Ok(())
+    }
+
+    /// Spec of `name` as an instance of a registered template; `None` when
+    /// there is no such template.
+    fn instance_spec(&self, name: &str) -> Result<Option<ServiceSpec>, String> {
+        let Some((template_name, instance)) = template::split(name) else { return Ok(None) };
+        let template_opt = { self.services.read().get(template_name).cloned() };
+        let Some(template) = template_opt else { return Ok(None) };
+        template::check_instance(instance)?;
+        let spec = template::instantiate(&template, instance);
+        check_spec(&spec)?;
+        { deps::check_cycles(&self.services.read(), &spec) }?;
+        Ok(Some(spec))
+    }
+
+    /// Run replicas 1..=`replicas` of `template`, after stopping and removing
+    /// the numbered instances above that. Named instances are left alone.
+    async fn scale(self: &Arc<Self>, template: &str, replicas: u32) -> Scaled {
+        let mut scaled = Scaled::default();
+        for inst in self.instances_of(template) {
+            let number = template::split(&inst).and_then(|(_, i)| template::replica(i));
+            if number.is_none_or(|n| n <= replicas) {
+                continue;
+            }
+            if self.is_up(&inst) {
+                match self.stop_with_dependents(&inst).await {
+                    Ok(stopped) => scaled.stopped.extend(stopped),
+                    Err(e) => scaled.errors.push(format!("{}: stop failed: {}", inst, e)),
+                }
+            }
+            self.forget(&inst);
+            info!("replica {} removed", inst);
+        }
+        for n in 1..=replicas {
+            let inst = template::numbered(template, n);
+            match self.start_service_internal(&inst).await {
+                Ok(started) => scaled.started.extend(started),
+                Err(e) => scaled.errors.push(format!("{}: start failed: {}", inst, e)),
+            }
+        }
+        scaled
+    }
+
+    /// Stop and unregister every instance of `template`.
+    async fn remove_instances(&self, template: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        for inst in self.instances_of(template) {
+            if let Err(e) = self.stop_with_dependents(&inst).await {
+                errors.push(format!("{}: stop failed: {}", inst, e));
+            }
+            self.forget(&inst);
+        }
+        errors
+    }
+
+    /// Give the instances of `template` its new spec. Running instances whose
+    /// spec changed are stopped, with whatever requires them; returns the
+    /// services to start again.
+    async fn update_instances(self: &Arc<Self>, template: &ServiceSpec, errors: &mut Vec<String>) -> Vec<String> {
+        let mut restart = Vec::new();
+        for inst in self.instances_of(&template.name) {
+            let Some((_, instance)) = template::split(&inst) else { continue };
+            let spec = template::instantiate(template, instance);
+            let current = { self.services.read().get(&inst).cloned() };
+            if current.is_some_and(|current| spec_diff(&current, &spec).is_empty()) {
+                continue;
+            }
+            if self.is_up(&inst) {
+                match self.stop_with_dependents(&inst).await {
+                    Ok(stopped) => restart.extend(stopped),
+                    Err(e) => errors.push(format!("{}: stop failed: {}", inst, e)),
+                }
+            }
+            self.put_spec(spec);
+        }
+        restart
+    }
+
+    /// Apply the services file at startup. A missing default file just
+    /// means no services are defined there.
+    pub async fn load_services(self: &Arc<Self>) {
+        let (path, explicit) = config::path();
+        if !explicit && !path.exists() {
+            info!("no {}; no services to load", path.display());
+            return;
+        }
+        match config::load(&path) {
+            Ok(entries) => {
+                info!("loaded {} service(s) from {}", entries.len(), path.display());
+                let resp = self.apply(entries, false).await;
+                if !resp.ok {
+                    warn!("{}", resp.message.unwrap_or_default());
+                }
+            }
+            Err(e) => error!("cannot load services: {}", e),
+        }
+    }
+
+    /// Compare `desired` with what is registered. Every spec is checked
+    /// first; any error refuses the whole set.
+    fn plan(&self, desired: &[Entry]) -> Result<Plan, Vec<String>> {
+        let mut errors = Vec::new();
+        let mut names = HashSet::new();
+        for entry in desired {
+            let name = &entry.spec.name;
+            if !names.insert(name.as_str()) {
+                errors.push(format!("{}: defined more than once", name));
+            }
+            if let Err(e) = check_spec(&entry.spec) {
+                errors.push(format!("{}: {}", name, e));
+            }
+        }
+
+        let services = self.services.read().clone();
+        let managed = self.managed.read().clone();
+        // the set as it will be after the apply, for the cycle check
+        let mut after: HashMap<String, ServiceSpec> = services
+            .iter()
+            .filter(|(name, _)| !managed.contains(*name) || names.contains(name.as_str()))
+            .map(|(name, spec)| (name.clone(), spec.clone()))
+            .collect();
+        after.extend(desired.iter().map(|e| (e.spec.name.clone(), e.spec.clone())));
+        for entry in desired {
+            if let Err(e) = deps::check_cycles(&after, &entry.spec) {
+                errors.push(format!("{}: {}", entry.spec.name, e));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut plan = Plan::default();
+        for entry in desired {
+            let name = entry.spec.name.clone();
+            match services.get(&name) {
+                None => plan.add.push(name),
+                Some(current) => {
+                    let changed = spec_diff(current, &entry.spec);
+                    if changed.is_empty() {
+                        plan.unchanged.push(name);
+                    } else {
+                        plan.update.push(Change { name, changed });
+                    }
+                }
+            }
+        }
+        plan.remove = managed.into_iter().filter(|name| !names.contains(name.as_str())).collect();
+        plan.add.sort();
+        plan.update.sort_by(|a, b| a.name.cmp(&b.name));
+        plan.remove.sort();
+        plan.unchanged.sort();
+        Ok(plan)
+    }
+
+    /// Reconcile with `desired`: remove the managed services not in it,
+    /// restart the updated ones that were running (with whatever requires
+    /// them), and start added services that have autostart.
+    async fn apply(self: &Arc<Self>, desired: Vec<Entry>, dry_run: bool) -> Response {
+        let _applying = self.applying.lock().await;
+        let plan = match self.plan(&desired) {
+            Ok(plan) => plan,
+            Err(errors) => {
+                let message = format!("invalid services: {}", errors.join("; "));
+                return Response { ok: false, message: Some(message), data: Some(serde_json::json!({ "errors": errors })) };
+            }
+        };
+        if dry_run {
+            let data = serde_json::json!({ "dry_run": true, "plan": plan });
+            return Response { ok: true, message: Some("dry run, nothing changed".into()), data: Some(data) };
+        }
+
+        let mut errors = Vec::new();
+        let mut to_start = Vec::new();
+        let entries: HashMap<String, Entry> = desired.into_iter().map(|e| (e.spec.name.clone(), e)).collect();
+
+        for name in &plan.remove {
+            if template::is_template(name) {
+                errors.extend(self.remove_instances(name).await);
+            }
+            if let Err(e) = self.stop_with_dependents(name).await {
+                errors.push(format!("{}: stop failed: {}", name, e));
+            }
+            self.forget(name);
+            info!("service {} removed", name);
+        }
+        for change in &plan.update {
+            let name = &change.name;
+            if self.is_up(name) {
+                match self.stop_with_dependents(name).await {
+                    Ok(stopped) => to_start.extend(stopped),
+                    Err(e) => errors.push(format!("{}: stop failed: {}", name, e)),
+                }
+            }
+            let entry = &entries[name];
+            self.put_spec(entry.spec.clone());
+            self.failed.write().remove(name);
+            if template::is_template(name) {
+                to_start.extend(self.update_instances(&entry.spec, &mut errors).await);
+                // a changed replicas takes effect here
+                if entry.autostart && entry.spec.replicas.is_some() {
+                    to_start.push(name.clone());
+                }
+            }
+            info!("service {} updated ({})", name, change.changed.join(", "));
+        }
+        for name in &plan.add {
+            let entry = &entries[name];
+            self.put_spec(entry.spec.clone());
+            // a template has nothing to start unless it has replicas, and a
+            // service with a timer waits for it
+            if entry.autostart && (!template::is_template(name) || entry.spec.replicas.is_some()) && entry.spec.timer.is_none() {
+                to_start.push(name.clone());
+            }
+            info!("service {} added", name);
+        }
+        self.managed.write().extend(entries.into_keys());
+
+        let mut seen = HashSet::new();
+        for name in to_start {
+            if seen.insert(name.clone()) && !self.is_up(&name) {
+                if let Err(e) = self.start(&name).await {
+                    errors.push(format!("{}: start failed: {}", name, e));
+                }
+            }
+        }
+
+        let message = if errors.is_empty() { "applied".to_string() } else { format!("applied with {} error(s)", errors.len()) };
+        let data = serde_json::json!({ "dry_run": false, "plan": plan, "errors": errors });
+        Response { ok: errors.is_empty(), message: Some(message), data: Some(data) }
+    }
+
+    /// `start`: a template runs its replicas; anything else is started with
+    /// its dependencies.
+    async fn start(self: &Arc<Self>, name: &str) -> Result<Vec<String>> {
+        if !template::is_template(name) {
+            return self.start_service_internal(name).await;
+        }
+        let replicas = { self.services.read().get(name).map(|spec| spec.replicas) };
+        match replicas {
+            None => anyhow::bail!("service not found"),
+            Some(None) => anyhow::bail!("{} is a template: start an instance such as {}name, or set replicas", name, name),
+            Some(Some(n)) => {
+                let scaled = self.scale(name, n).await;
+                if !scaled.errors.is_empty() {
+                    anyhow::bail!(scaled.errors.join("; "));
+                }
+                Ok(scaled.started)
+            }
+        }
+    }
+
+    /// `stop`: a template stops all its instances.
+    async fn stop(&self, name: &str) -> Result<Vec<String>> {
+        if !template::is_template(name) {
+            return self.stop_with_dependents(name).await;
+        }
+        let mut stopped = Vec::new();
+        for inst in self.instances_of(name) {
+            if self.is_up(&inst) {
+                for svc in self.stop_with_dependents(&inst).await? {
+                    if !stopped.contains(&svc) {
+                        stopped.push(svc);
+                    }
+                }
+            }
+        }
+        Ok(stopped)
+    }
+
+    /// Start service by name (public method), after everything it requires or
+    /// wants. An instance of a template is registered first if need be. Returns the services that were not up before, in start order.
+    pub async fn start_service_internal(self: &Arc<Self>, name: &str) -> Result<Vec<String>> {
+        self.materialize(name)?;
+        let order = { deps::start_order(&self.services.read(), name) }.map_err(anyhow::Error::msg)?;
+        let mut started = Vec::new();
+        let mut failed: HashMap<String, String> = HashMap::new();
+        for svc in order {
+            let Some(spec) = ({ self.services.read().get(&svc).cloned() }) else { continue };
+            if let Some((dep, reason)) = spec.requires.iter().find_map(|d| failed.get(d).map(|r| (d, r))) {
+                let reason = format!("required service {} did not start: {}", dep, reason);
+                if svc == name {
+                    anyhow::bail!(reason);
+                }
+                failed.insert(svc, reason);
+                continue;
+            }
+            if self.is_up(&svc) {
+                continue;
+            }
+            match self.start_one(&svc).await {
+                Ok(()) => started.push(svc),
+                Err(e) if svc == name => return Err(e),
+                Err(e) => {
+                    warn!("{} (needed by {}) failed to start: {}", svc, name, e);
+                    failed.insert(svc, e.to_string());
+                }
+            }
+        }
+        Ok(started)
+    }
+
+    /// Stop a service, and before it every running service that requires it.
+    /// Returns the services stopped, in order.
+    async fn stop_with_dependents(&self, name: &str) -> Result<Vec<String>> {
+        let dependents = { deps::dependents(&self.services.read(), name) };
+        let mut stopped = Vec::new();
+        for svc in dependents.into_iter().filter(|d| self.is_up(d)) {
+            info!("stopping {} first, it requires {}", svc, name);
+            self.stop_service_internal(&svc).await?;
+            stopped.push(svc);
+        }
+        self.stop_service_internal(name).await?;
+        stopped.push(name.to_string());
+        Ok(stopped)
+    }
+
+    /// Stop every service, each after the services requiring it. Used when
+    /// the registry itself is shut down.
+    pub async fn stop_all(&self) {
+        let names: Vec<String> = {
+            let processes = self.processes.read();
+            let activations = self.activations.read();
+            processes.keys().chain(activations.keys()).cloned().collect()
+        };
+        for name in names {
+            if self.is_up(&name) {
+                if let Err(e) = self.stop_with_dependents(&name).await {
+                    warn!("failed to stop {}: {}", name, e);
+                }
+            }
+        }
+    }
+
+    /// Whether the service has a process or is listening for one.
+    fn is_up(&self, name: &str) -> bool {
+        self.processes.read().contains_key(name) || self.activations.read().contains_key(name)
+    }
+
+    /// Short state shown by `list` and `deps`.
+    fn state(&self, name: &str) -> &'static str {
+        if self.processes.read().contains_key(name) {
+            "running"
+        } else if self.activations.read().contains_key(name) {
+            "listening"
+        } else if self.failed.read().contains_key(name) {
+            "failed"
+        } else if template::is_template(name) && self.services.read().contains_key(name) {
+            "template"
+        } else if self.services.read().contains_key(name) {
+            "stopped"
+        } else {
+            "not registered"
+        }
+    }
+
+    /// Start one service, without its dependencies. A service with listen
+    /// sockets is only armed here; it is spawned by its activation task. A
+    /// notify service has started once it reports READY=1; if it does not in
+    /// time it is killed.
+    async fn start_one(self: &Arc<Self>, name: &str) -> Result<()> {
+        let spec_opt = { self.services.read().get(name).cloned() };
+        let spec = spec_opt.ok_or_else(|| anyhow::anyhow!("service not found"))?;
+        if template::is_template(name) {
+            anyhow::bail!("{} is a template; only its instances run", name);
+        }
+        if let Some(failure) = self.failed.read().get(name) {
+            anyhow::bail!("service failed ({}); reset_failed it first", failure.reason);
+        }
+        if !spec.listen.is_empty() {
+            return self.activate(&spec);
+        }
+
+        // If already has supervised process, and running, skip
+        {
+            if let Some(proc) = self.processes.read().get(name) {
+                let locked = proc.lock();
+                if locked.child.is_some() {
+                    info!("service {} already running", name);
+                    return Ok(());
+                }
+            }
+        }
+
+        self.spawn_process(&spec, Vec::new()).await?;
+        if spec.kind == ServiceType::Notify {
+            let timeout = Duration::from_secs(spec.ready_timeout_secs.unwrap_or(DEFAULT_READY_TIMEOUT_SECS));
+            if let Err(e) = self.wait_ready(name, timeout).await {
+                self.abandon(name).await;
+                return Err(e);
+            }
+            events::publish("service.ready", serde_json::json!({ "name": name }));
+        }
+        info!("service {} started", name);
+        Ok(())
+    }
+
+    /// Wait for READY=1 from a notify service that was just spawned.
+    async fn wait_ready(&self, name: &str, timeout: Duration) -> Result<()> {
+        let proc_opt = { self.processes.read().get(name).cloned() };
+        let proc = proc_opt.ok_or_else(|| anyhow::anyhow!("exited before reporting ready"))?;
+        let watch_opt = { proc.lock().notify.as_ref().map(|n| n.watch()) };
+        let Some(mut ready) = watch_opt else { return Ok(()) };
+        let deadline = Instant::now() + timeout;
+        loop {
+            if ready.borrow_and_update().ready {
+                return Ok(());
+            }
+            if proc.lock().has_exited() {
+                anyhow::bail!("exited before reporting ready");
+            }
+            if Instant::now() >= deadline {
+                anyhow::bail!("did not report ready within {}s", timeout.as_secs());
+            }
+            let _ = tokio::time::timeout(READY_POLL, ready.changed()).await;
+        }
+    }
+
+    /// Kill a service that failed to start and drop it from supervision.
+    async fn abandon(&self, name: &str) {
+        let Some(proc) = self.processes.write().remove(name) else { return };
+        let (cmd, child, mode) = {
+            let mut locked = proc.lock();
+            (locked.cmd.clone(), locked.take_child(), locked.stop_mode)
+        };
+        if let Some(child) = child {
+            supervised::stop_child(&cmd, child, mode).await;
+        }
+        warn!("service {} did not become ready and was stopped", name);
+    }
+
+    /// Create the supervised process for `spec` and spawn it.
+    async fn spawn_process(&self, spec: &ServiceSpec, sockets: Vec<RawFd>) -> Result<()> {
+        // resolved again on every start: the user or directory may be gone by now
+        let exec = ExecContext::from_spec(spec).map_err(anyhow::Error::msg)?;
+        let stop_mode = spec.stop_mode().map_err(anyhow::Error::msg)?;
+        let mut sp = SupervisedProcess::new(spec.cmd.clone(), spec.restart.clone(), exec, stop_mode);
+        sp.sockets = sockets;
+        let limits = Limits::from_spec(spec).map_err(anyhow::Error::msg)?;
+        sp.resources = Resources::prepare(&spec.name, &limits).map_err(anyhow::Error::msg)?;
+        sp.output = Some(self.output(&spec.name)?);
+        if spec.kind == ServiceType::Notify {
+            let notifier = Notifier::bind(&spec.name)?;
+            // the socket is 0600; the service has to be able to write to it
+            if let Some(creds) = sp.exec.credentials() {
+                std::os::unix::fs::chown(notifier.path(), creds.user.as_ref().map(|u| u.uid), creds.gid)?;
+            }
+            sp.notify = Some(notifier);
+        }
+        if spec.health_check.is_some() {
+            sp.health = Some(Health::default());
+        }
+        sp.spawn()?;
+        let shared = Arc::new(parking_lot::Mutex::new(sp));
+        if let Some(check) = &spec.health_check {
+            // ends by itself once the process is dropped from supervision
+            tokio::spawn(health::watch(spec.name.clone(), check.clone(), Arc::downgrade(&shared)));
+        }
+        self.processes.write().insert(spec.name.clone(), shared);
+        Ok(())
+    }
+
+    /// Give up on a crash-looping service until reset_failed.
+    fn mark_failed(&self, name: &str, reason: String, restarts: usize) {
+        error!("service {} failed: {} after {} restarts; not restarting it", name, reason, restarts);
+        events::publish("service.failed", serde_json::json!({
+            "name": name,
+            "reason": reason,
+            "restarts": restarts,
+        }));
+        let since = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        self.failed.write().insert(name.to_string(), Failure { reason, since, restarts });
+    }
+
+    /// Captured output of a registered service, opened on first use.
+    fn output(&self, name: &str) -> Result<Arc<ServiceLog>> {
+        if let Some(log) = self.outputs.read().get(name) {
+            return Ok(log.clone());
+        }
+        if !self.services.read().contains_key(name) {
+            anyhow::bail!("service not found");
+        }
+        let mut outputs = self.outputs.write();
+        if let Some(log) = outputs.get(name) {
+            return Ok(log.clone());
+        }
+        let log = Arc::new(ServiceLog::open(name)?);
+        outputs.insert(name.to_string(), log.clone());
+        Ok(log)
+    }
+
+    /// Bind the service's listen sockets and wait for the first connection.
+    fn activate(self: &Arc<Self>, spec: &ServiceSpec) -> Result<()> {
+        let mut activations = self.activations.write();
+        if activations.contains_key(&spec.name) {
+            info!("service {} already listening", spec.name);
+            return Ok(());
+        }
+        let addrs = spec.listen_addrs().map_err(anyhow::Error::msg)?;
+        let listeners = Arc::new(Listeners::bind(&addrs)?);
+        let task = tokio::spawn(self.clone().activation_loop(spec.name.clone(), listeners.clone()));
+        activations.insert(spec.name.clone(), Activation { listeners, task });
+        info!("service {} waiting for connections on {}", spec.name, spec.listen.join(", "));
+        events::publish("service.listening", serde_json::json!({
+            "name": spec.name,
+            "listen": spec.listen,
+        }));
+        Ok(())
+    }
+
+    /// Spawn the service whenever a connection arrives while it is not running.
+    /// The monitor drops the process once it exits, idle or not, and the
+    /// sockets are watched again from then on.
+    async fn activation_loop(self: Arc<Self>, name: String, listeners: Arc<Listeners>) {
+        loop {
+            if let Err(e) = listeners.wait_for_connection().await {
+                error!("socket activation of {} stopped: {:?}", name, e);
+                return;
+            }
+            let spec_opt = { self.services.read().get(&name).cloned() };
+            let Some(spec) = spec_opt else { return };
+            match self.spawn_process(&spec, listeners.raw_fds()).await {
+                Ok(()) => {
+                    info!("service {} activated by incoming connection", name);
+                    events::publish("service.activated", serde_json::json!({ "name": name }));
+                }
+                Err(e) => {
+                    // the connection is still pending; don't spin on it
+                    error!("failed to activate {}: {:?}", name, e);
+                    tokio::time::sleep(ACTIVATION_RETRY).await;
+                    continue;
+                }
+            }
+            while self.processes.read().contains_key(&name) {
+                tokio::time::sleep(ACTIVATION_POLL).await;
+            }
+        }
+    }
+
+    /// Stop a service by name (public method). A socket-activated service also
+    /// closes its sockets.
+    pub async fn stop_service_internal(&self, name: &str) -> Result<()> {
+        let activation = self.activations.write().remove(name);
+        if activation.is_some() {
+            info!("service {} no longer listening", name);
+        }
+        drop(activation);
+        let Some(proc) = self.processes.write().remove(name) else {
+            // nothing to stop
+            return Ok(());
+        };
+        // the lock is not held while waiting, so `status` keeps answering
+        let (cmd, child, mode) = {
+            let mut locked = proc.lock();
+            (locked.cmd.clone(), locked.take_child(), locked.stop_mode)
+        };
+        if let Some(child) = child {
+            supervised::stop_child(&cmd, child, mode).await;
+        }
+        info!("service {} stopped", name);
+        Ok(())
+    }
+
+    /// Background monitor loop: poll child processes, restart based on policy, simple backoff.
+    /// The process lock is only taken for short, non-awaiting steps, so
+    /// `status` never waits on a backoff or a stop.
+    pub async fn run_monitor(self: Arc<Self>) {
+        info!("service-registry monitor running");
+        let poll_interval = Duration::from_secs(2);
+
+        loop {
+            // collect keys to inspect to avoid holding lock long
+            let keys: Vec<String> = {
+                let map = self.processes.read();
+                map.keys().cloned().collect()
+            };
+
+            for name in keys {
+                let proc_opt = { self.processes.read().get(&name).cloned() };
+                let Some(shared_proc) = proc_opt else { continue };
+                // clone spec to read restart limits
+                let spec_opt = { self.services.read().get(&name).cloned() };
+                let Some(spec) = spec_opt else {
+                    // No more spec -> stop
+                    let _ = self.stop_service_internal(&name).await;
+                    continue;
+                };
+
+                // Poll
+                let polled = { shared_proc.lock().poll_exit() };
+                match polled {
+                    Ok(Some(status)) => {
+                        info!("service {} {}", name, supervised::describe_exit(status));
+                        events::publish("service.exited", serde_json::json!({
+                            "name": name,
+                            "code": status.code(),
+                            "success": status.success(),
+                        }));
+                        // decide restart; a socket-activated service is started
+                        // again by the next connection instead
+                        let activated = self.activations.read().contains_key(&name);
+                        let should = !activated && { shared_proc.lock().should_restart(Some(status)) };
+                        if should {
+                            let reason = supervised::describe_exit(status);
+                            tokio::spawn(self.clone().restart(name, shared_proc, spec, reason));
+                        } else {
+                            // no restart -> remove from processes map
+                            let _ = self.processes.write().remove(&name);
+                            if activated {
+                                info!("service {} waiting for the next connection", name);
+                            } else {
+                                info!("service {} removed from supervision", name);
+                            }
+                        }
+                    }
+                    Ok(None) => {
+                        // still running; act once its health probe failed too often
+                        let (health, policy) = {
+                            let locked = shared_proc.lock();
+                            (locked.child.as_ref().and(locked.health.clone()), locked.restart_policy.clone())
+                        };
+                        let Some(health) = health.filter(|h| h.status == HealthStatus::Unhealthy) else { continue };
+                        let error = health.last_error.unwrap_or_default();
+                        // an unhealthy service counts as failed: only Never leaves it down
+                        let restart = !matches!(policy, RestartPolicy::Never);
+                        warn!("service {} is unhealthy ({}), {}", name, error, if restart { "restarting" } else { "stopping" });
+                        events::publish("service.unhealthy", serde_json::json!({
+                            "name": name,
+                            "failures": health.failures,
+                            "error": error,
+                        }));
+                        let (cmd, child, mode) = {
+                            let mut locked = shared_proc.lock();
+                            (locked.cmd.clone(), locked.take_child(), locked.stop_mode)
+                        };
+                        if let Some(child) = child {
+                            supervised::stop_child(&cmd, child, mode).await;
+                        }
+                        let reason = format!("unhealthy: {}", error);
+                        if !restart {
+                            let restarts = { shared_proc.lock().recent_restarts.len() };
+                            let _ = self.processes.write().remove(&name);
+                            self.mark_failed(&name, reason, restarts);
+                        } else if self.activations.read().contains_key(&name) {
+                            // the next connection starts it again
+                            let window = Duration::from_secs(spec.restart_window_secs.unwrap_or(DEFAULT_RESTART_WINDOW_SECS));
+                            let allowed = { shared_proc.lock().allow_restart(spec.max_restarts, window) };
+                            let restarts = { shared_proc.lock().recent_restarts.len() };
+                            let _ = self.processes.write().remove(&name);
+                            if !allowed {
+                                self.mark_failed(&name, reason, restarts);
+                            }
+                        } else {
+                            tokio::spawn(self.clone().restart(name, shared_proc, spec, reason));
+                        }
+                    }
+                    Err(e) => {
+                        error!("error polling {}: {:?}", name, e);
+                    }
+                }
+            }
+
+            tokio::time::sleep(poll_interval).await;
+        }
+    }
+
+    /// Spawn a service again after its backoff, or give up on it with
+    /// mark_failed once max_restarts restarts happened within the window.
+    /// A spawn that fails counts as one more crash and is retried the same way.
+    /// Runs as a task of its own, so one service's backoff does not hold up
+    /// the monitor; while it waits the process has no child, which the
+    /// monitor leaves alone.
+    async fn restart(self: Arc<Self>, name: String, shared: SharedProcess, spec: ServiceSpec, mut reason: String) {
+        let window = Duration::from_secs(spec.restart_window_secs.unwrap_or(DEFAULT_RESTART_WINDOW_SECS));
+        let stable = Duration::from_secs(spec.stable_secs.unwrap_or(DEFAULT_STABLE_SECS));
+        shared.lock().reset_backoff_if_stable(stable);
+        loop {
+            let backoff = {
+                let mut locked = shared.lock();
+                if locked.allow_restart(spec.max_restarts, window) {
+                    Ok(locked.next_backoff())
+                } else {
+                    Err(locked.recent_restarts.len())
+                }
+            };
+            let backoff = match backoff {
+                Ok(backoff) => backoff,
+                Err(restarts) => {
+                    let _ = self.processes.write().remove(&name);
+                    self.mark_failed(&name, reason, restarts);
+                    return;
+                }
+            };
+            info!("restarting {} after {:?} backoff", name, backoff);
+            tokio::time::sleep(backoff).await;
+            // stopped, or started anew, while waiting
+            if !self.processes.read().get(&name).is_some_and(|p| Arc::ptr_eq(p, &shared)) {
+                return;
+            }
+            let spawned = {
+                let mut locked = shared.lock();
+                locked.spawn().map(|()| locked.restart_count)
+            };
+            match spawned {
+                Ok(restart_count) => {
+                    events::publish("service.restarted", serde_json::json!({
+                        "name": name,
+                        "restart_count": restart_count,
+                    }));
+                    return;
+                }
+                Err(e) => {
+                    error!("failed to restart {}: {:?}", name, e);
+                    reason = format!("failed to restart: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// The checks `register` and `apply` make on a spec by itself.
+fn check_spec(spec: &ServiceSpec) -> Result<(), String> {
+    service::check_name(&spec.name)?;
+    if template::is_template(&spec.name) {
+        // checked as an instance would be, but its working_dir may only be
+        // created per instance
+        let mut first = template::instantiate(spec, "1");
+        if spec.working_dir.as_ref().is_some_and(|d| d.contains(template::INSTANCE)) {
+            first.working_dir = None;
+        }
+        return check_spec(&first);
+    }
+    if spec.replicas.is_some() {
+        return Err("replicas is only for templates (names ending in @)".into());
+    }
+    if let Some((_, instance)) = template::split(&spec.name) {
+        template::check_instance(instance)?;
+    }
+    spec.listen_addrs().map_err(|e| format!("invalid listen address: {}", e))?;
+    if let Some(check) = &spec.health_check {
+        check.validate().map_err(|e| format!("invalid health_check: {}", e))?;
+    }
+    if let Some(timer) = &spec.timer {
+        Timer::from_spec(timer).map_err(|e| format!("invalid timer: {}", e))?;
+    }
+    ExecContext::from_spec(spec)
+        .and(spec.stop_mode())
+        .and(Limits::from_spec(spec))
+        .map_err(|e| format!("invalid spec: {}", e))?;
+    Ok(())
+}
+
+fn rfc3339(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+/// Top-level spec keys whose values differ between `a` and `b`.
+fn spec_diff(a: &ServiceSpec, b: &ServiceSpec) -> Vec<String> {
+    let (Ok(serde_json::Value::Object(a)), Ok(serde_json::Value::Object(b))) = (serde_json::to_value(a), serde_json::to_value(b)) else {
+        return Vec::new();
+    };
+    let mut keys: Vec<String> = a.keys().chain(b.keys()).filter(|k| a.get(*k) != b.get(*k)).cloned().collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(spec: serde_json::Value) -> Entry {
+        serde_json::from_value(spec).unwrap()
+    }
+
+    #[test]
+    fn spec_diff_lists_changed_keys() {
+        let a = entry(json!({ "name": "web", "cmd": ["./web"] })).spec;
+        let b = entry(json!({ "name": "web", "cmd": ["./web", "-v"], "restart": "Always", "requires": ["db"] })).spec;
+        assert!(spec_diff(&a, &a.clone()).is_empty());
+        assert_eq!(spec_diff(&a, &b), ["cmd", "requires", "restart"]);
+    }
+
+    #[tokio::test]
+    async fn apply_dry_run_only_plans() {
+        let registry = Registry::new();
+        for name in ["db", "old"] {
+            let spec = entry(json!({ "name": name, "cmd": ["/bin/true"] })).spec;
+            registry.services.write().insert(name.to_string(), spec);
+            registry.managed.write().insert(name.to_string());
+        }
+        let desired = vec![
+            entry(json!({ "name": "db", "cmd": ["/bin/true"], "restart": "Always" })),
+            entry(json!({ "name": "web", "cmd": ["/bin/true"], "requires": ["db"] })),
+        ];
+
+        let resp = registry.apply(desired, true).await;
+        assert!(resp.ok);
+        let plan = &resp.data.unwrap()["plan"];
+        assert_eq!(plan["add"], json!(["web"]));
+        assert_eq!(plan["update"], json!([{ "name": "db", "changed": ["restart"] }]));
+        assert_eq!(plan["remove"], json!(["old"]));
+
+        let services = registry.services.read();
+        assert!(services.contains_key("old") && !services.contains_key("web"));
+        assert!(matches!(services["db"].restart, RestartPolicy::OnFailure));
+    }
+
+    #[tokio::test]
+    async fn apply_refuses_the_whole_set_on_any_error() {
+        let registry = Registry::new();
+        let desired = vec![
+            entry(json!({ "name": "web", "cmd": ["/bin/true"] })),
+            entry(json!({ "name": "../x", "cmd": ["/bin/true"] })),
+            entry(json!({ "name": "web", "cmd": ["/bin/true"] })),
+        ];
+        let resp = registry.apply(desired, false).await;
+        assert!(!resp.ok);
+        let errors = resp.data.unwrap()["errors"].clone();
+        assert_eq!(errors.as_array().unwrap().len(), 2, "{}", errors);
+        assert!(registry.services.read().is_empty());
+    }
+}
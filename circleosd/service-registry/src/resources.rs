@@ -0,0 +1,395 @@
+//! Resource limits of a service: memory, CPU, process count and open files.
+//!
+//! When the registry has a cgroup v2 subtree it may manage (CIRCLEOSD_CGROUP,
+//! or the cgroup it was started in), every service runs in a cgroup of its
+//! own under it and the limits are written to the cgroup's control files.
+//! Limits whose controller is not available fall back to setrlimit(2) in the
+//! child: memory_max to RLIMIT_AS and pids_max to RLIMIT_NPROC. CPU limits
+//! have no rlimit equivalent and are only enforced through cgroups.
+
+use serde_json::{json, Value};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::process;
+use crate::service::ServiceSpec;
+
+/// Controllers the registry enables for its services when it can.
+const CONTROLLERS: &[&str] = &["cpu", "memory", "pids"];
+/// cpu.max period; quotas are a share of it.
+const CPU_PERIOD_USEC: u64 = 100_000;
+/// How long a released cgroup may take to empty before it is left in place.
+const CGROUP_EMPTY_WAIT: std::time::Duration = std::time::Duration::from_secs(2);
+
+static ROOT: OnceLock<Option<CgroupRoot>> = OnceLock::new();
+/// Numbers the cgroups handed out, so no two are ever shared.
+static NEXT_CGROUP: AtomicU64 = AtomicU64::new(1);
+
+/// The delegated subtree services are placed in.
+#[derive(Debug)]
+struct CgroupRoot {
+    path: PathBuf,
+    /// Of CONTROLLERS, those enabled for the services' cgroups.
+    controllers: Vec<&'static str>,
+}
+
+/// Limits of one service, parsed from its spec.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    memory_max: Option<u64>,
+    /// Percent of one CPU.
+    cpu_quota: Option<u64>,
+    cpu_weight: Option<u64>,
+    pids_max: Option<u64>,
+    open_files: Option<u64>,
+}
+
+impl Limits {
+    pub fn from_spec(spec: &ServiceSpec) -> Result<Self, String> {
+        let memory_max = spec.memory_max.as_deref().map(parse_bytes).transpose()?;
+        let cpu_quota = match spec.cpu_quota.as_deref() {
+            Some(q) => match q.strip_suffix('%').and_then(|p| p.trim().parse::<u64>().ok()) {
+                Some(p) if p > 0 => Some(p),
+                _ => return Err(format!("cpu_quota `{}` is not a percentage such as \"50%\"", q)),
+            },
+            None => None,
+        };
+        if let Some(w) = spec.cpu_weight {
+            if !(1..=10_000).contains(&w) {
+                return Err(format!("cpu_weight {} is outside 1..=10000", w));
+            }
+        }
+        if spec.pids_max == Some(0) || spec.open_files == Some(0) {
+            return Err("pids_max and open_files must be at least 1".into());
+        }
+        Ok(Self { memory_max, cpu_quota, cpu_weight: spec.cpu_weight, pids_max: spec.pids_max, open_files: spec.open_files })
+    }
+}
+
+/// "512M", "2G", "1048576": bytes, with binary K/M/G/T suffixes.
+fn parse_bytes(s: &str) -> Result<u64, String> {
+    let t = s.trim();
+    let (num, shift) = match t.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&t[..i], 10),
+        Some((i, 'M' | 'm')) => (&t[..i], 20),
+        Some((i, 'G' | 'g')) => (&t[..i], 30),
+        Some((i, 'T' | 't')) => (&t[..i], 40),
+        _ => (t, 0),
+    };
+    num.trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .filter(|n| *n > 0)
+        .ok_or_else(|| format!("memory_max `{}` is not a size such as \"512M\"", s))
+}
+
+/// Find and set up the delegated cgroup subtree. Called once at startup,
+/// before any service is spawned.
+pub fn init() {
+    let root = match setup_root() {
+        Ok(Some(root)) => {
+            info!("services get cgroups under {} (controllers: {})", root.path.display(), root.controllers.join(" "));
+            Some(root)
+        }
+        Ok(None) => {
+            info!("no cgroup v2 subtree to manage; resource limits use setrlimit");
+            None
+        }
+        Err(e) => {
+            warn!("cgroup v2 subtree not usable ({}); resource limits use setrlimit", e);
+            None
+        }
+    };
+    let _ = ROOT.set(root);
+}
+
+fn setup_root() -> Result<Option<CgroupRoot>, String> {
+    let own = own_cgroup();
+    let path = match std::env::var_os("CIRCLEOSD_CGROUP").filter(|p| !p.is_empty()) {
+        Some(p) => PathBuf::from(p),
+        // taking over the root cgroup needs to be asked for
+        None => match &own {
+            Some(own) if own.parent().is_some_and(|p| p.join("cgroup.controllers").exists()) => own.clone(),
+            _ => return Ok(None),
+        },
+    };
+    let err = |what: &str, e: std::io::Error| format!("{}: {}", what, e);
+    fs::create_dir_all(&path).map_err(|e| err(&path.display().to_string(), e))?;
+
+    // A cgroup holding processes can't pass controllers down, so the
+    // registry moves out of the one it shares with its services.
+    if own.as_deref() == Some(path.as_path()) {
+        let supervisor = path.join("registry");
+        fs::create_dir_all(&supervisor).map_err(|e| err("registry cgroup", e))?;
+        write(&supervisor.join("cgroup.procs"), "0").map_err(|e| err("moving the registry", e))?;
+    }
+
+    let available = fs::read_to_string(path.join("cgroup.controllers")).map_err(|e| err("cgroup.controllers", e))?;
+    let mut controllers = Vec::new();
+    for c in CONTROLLERS.iter().filter(|c| available.split_whitespace().any(|a| a == **c)) {
+        match write(&path.join("cgroup.subtree_control"), &format!("+{}", c)) {
+            Ok(()) => controllers.push(*c),
+            Err(e) => warn!("cannot enable the {} controller for services: {}", c, e),
+        }
+    }
+    Ok(Some(CgroupRoot { path, controllers }))
+}
+
+/// The cgroup v2 directory of the registry itself.
+fn own_cgroup() -> Option<PathBuf> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let rel = cgroups.lines().find_map(|l| l.strip_prefix("0::"))?;
+    let mounts = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    // "... <mount point> <options> - cgroup2 <source> <options>"
+    let mount = mounts.lines().find_map(|l| {
+        let (left, right) = l.split_once(" - ")?;
+        if right.split_whitespace().next()? != "cgroup2" {
+            return None;
+        }
+        left.split_whitespace().nth(4).map(PathBuf::from)
+    })?;
+    Some(mount.join(rel.trim_start_matches('/')))
+}
+
+fn write(path: &Path, value: &str) -> std::io::Result<()> {
+    OpenOptions::new().write(true).open(path)?.write_all(value.as_bytes())
+}
+
+/// How the limits of a service are applied on each spawn.
+#[derive(Debug, Default)]
+pub struct Resources {
+    cgroup: Option<PathBuf>,
+    rlimits: Vec<(libc::__rlimit_resource_t, u64)>,
+}
+
+impl Resources {
+    /// Create a cgroup for this run of the service, `<name>-<n>.service`, and
+    /// write its limits; the limits it can't hold become rlimits. Each
+    /// supervised process gets a cgroup of its own (kept across its
+    /// restarts), so releasing an old one never reaches a newer process of
+    /// the same service.
+    pub fn prepare(service: &str, limits: &Limits) -> Result<Self, String> {
+        let root = ROOT.get().and_then(Option::as_ref);
+        let has = |c: &str| root.is_some_and(|r| r.controllers.contains(&c));
+        let mut res = Resources::default();
+
+        if let Some(root) = root {
+            let n = NEXT_CGROUP.fetch_add(1, Ordering::Relaxed);
+            let dir = root.path.join(format!("{}-{}.service", service, n));
+            fs::create_dir_all(&dir).map_err(|e| format!("cgroup {}: {}", dir.display(), e))?;
+            // written even when unset, so a limit dropped from the spec does not linger
+            let max = |v: Option<u64>| v.map_or_else(|| "max".to_string(), |v| v.to_string());
+            let mut settings = Vec::new();
+            if has("memory") {
+                settings.push(("memory.max", max(limits.memory_max)));
+            }
+            if has("pids") {
+                settings.push(("pids.max", max(limits.pids_max)));
+            }
+            if has("cpu") {
+                let quota = limits.cpu_quota.map(|p| p * CPU_PERIOD_USEC / 100);
+                settings.push(("cpu.max", format!("{} {}", max(quota), CPU_PERIOD_USEC)));
+                settings.push(("cpu.weight", limits.cpu_weight.unwrap_or(100).to_string()));
+            }
+            for (file, value) in settings {
+                write(&dir.join(file), &value).map_err(|e| format!("{} {}: {}", file, value, e))?;
+            }
+            res.cgroup = Some(dir);
+        }
+
+        if let Some(bytes) = limits.memory_max.filter(|_| !has("memory")) {
+            res.rlimits.push((libc::RLIMIT_AS, bytes));
+        }
+        if let Some(n) = limits.pids_max.filter(|_| !has("pids")) {
+            // counts every process of the service's user, not just its own
+            res.rlimits.push((libc::RLIMIT_NPROC, n));
+        }
+        if let Some(n) = limits.open_files {
+            res.rlimits.push((libc::RLIMIT_NOFILE, n));
+        }
+        if (limits.cpu_quota.is_some() || limits.cpu_weight.is_some()) && !has("cpu") {
+            warn!("{}: cpu_quota and cpu_weight need the cgroup v2 cpu controller and are not enforced", service);
+        }
+        Ok(res)
+    }
+
+    /// Have `command` join the cgroup and set the rlimits before exec. Comes
+    /// before ExecContext::apply: joining a root-owned cgroup and raising
+    /// hard limits both have to happen before privileges are dropped.
+    pub fn apply(&self, command: &mut Command) -> std::io::Result<()> {
+        // opened here, as the child can't allocate; close-on-exec
+        let procs = match &self.cgroup {
+            Some(dir) => Some(OpenOptions::new().write(true).open(dir.join("cgroup.procs"))?),
+            None => None,
+        };
+        if procs.is_none() && self.rlimits.is_empty() {
+            return Ok(());
+        }
+        let rlimits = self.rlimits.clone();
+        // Safety: write and setrlimit are async-signal-safe; the file and the
+        // rlimit list were prepared before fork.
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(procs) = &procs {
+                    // "0" moves the writing process
+                    if libc::write(procs.as_raw_fd(), b"0".as_ptr().cast(), 1) != 1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                for (resource, value) in &rlimits {
+                    let limit = libc::rlimit { rlim_cur: *value as libc::rlim_t, rlim_max: *value as libc::rlim_t };
+                    if libc::setrlimit(*resource, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Current usage for `status`: from the cgroup if the service has one,
+    /// else summed over the processes of its group `pgid`.
+    pub fn usage(&self, pgid: Option<libc::pid_t>) -> Value {
+        match &self.cgroup {
+            Some(dir) => {
+                let read = |file: &str| fs::read_to_string(dir.join(file)).ok();
+                let num = |file: &str| read(file).and_then(|v| v.trim().parse::<u64>().ok());
+                let cpu_usec = read("cpu.stat").and_then(|stat| {
+                    stat.lines().find_map(|l| l.strip_prefix("usage_usec ")).and_then(|v| v.trim().parse::<u64>().ok())
+                });
+                let pids = num("pids.current").or_else(|| read("cgroup.procs").map(|p| p.lines().count() as u64));
+                json!({
+                    "source": "cgroup",
+                    "cgroup": dir,
+                    "memory_bytes": num("memory.current"),
+                    "memory_peak_bytes": num("memory.peak"),
+                    "cpu_usec": cpu_usec,
+                    "pids": pids,
+                })
+            }
+            None => {
+                let pids = pgid.map(process::group_pids).unwrap_or_default();
+                // Safety: sysconf has no preconditions.
+                let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+                let (mut memory, mut cpu) = (0u64, 0u64);
+                for pid in &pids {
+                    if let Ok(status) = fs::read_to_string(format!("/proc/{}/status", pid)) {
+                        let rss_kb = status.lines().find_map(|l| l.strip_prefix("VmRSS:"));
+                        memory += rss_kb.and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok()).unwrap_or(0) * 1024;
+                    }
+                    if let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", pid)) {
+                        // utime and stime, the 12th and 13th fields after "(comm)"
+                        let after = stat.rfind(')').map_or("", |i| &stat[i + 1..]);
+                        let fields: Vec<&str> = after.split_whitespace().collect();
+                        let time: u64 = [11, 12].iter().filter_map(|i| fields.get(*i)?.parse::<u64>().ok()).sum();
+                        cpu += time * 1_000_000 / ticks;
+                    }
+                }
+                json!({
+                    "source": "procfs",
+                    "memory_bytes": memory,
+                    "cpu_usec": cpu,
+                    "pids": pids.len(),
+                })
+            }
+        }
+    }
+}
+
+impl Drop for Resources {
+    /// Kill whatever is left in the cgroup, including processes that left
+    /// the service's process group, and remove it once empty.
+    fn drop(&mut self) {
+        let Some(dir) = self.cgroup.take() else { return };
+        if populated(&dir) {
+            if let Err(e) = write(&dir.join("cgroup.kill"), "1") {
+                warn!("cannot kill what is left in {}: {}", dir.display(), e);
+            }
+        }
+        let remove = async move {
+            let deadline = std::time::Instant::now() + CGROUP_EMPTY_WAIT;
+            while populated(&dir) && std::time::Instant::now() < deadline {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+            // still busy: left in place, empty dirs are cheap
+            if let Err(e) = fs::remove_dir(&dir) {
+                debug!("cgroup {} not removed: {}", dir.display(), e);
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(rt) => {
+                rt.spawn(remove);
+            }
+            Err(_) => debug!("no runtime left to remove a cgroup"),
+        }
+    }
+}
+
+fn populated(dir: &Path) -> bool {
+    fs::read_to_string(dir.join("cgroup.events")).is_ok_and(|e| e.lines().any(|l| l == "populated 1"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(limits: Value) -> ServiceSpec {
+        let mut v = json!({ "name": "svc", "cmd": ["/bin/true"] });
+        v.as_object_mut().unwrap().extend(limits.as_object().unwrap().clone());
+        serde_json::from_value(v).unwrap()
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(parse_bytes("1048576"), Ok(1 << 20));
+        assert_eq!(parse_bytes("512M"), Ok(512 << 20));
+        assert_eq!(parse_bytes(" 2g "), Ok(2 << 30));
+        assert_eq!(parse_bytes("4 K"), Ok(4 << 10));
+        assert_eq!(parse_bytes("1T"), Ok(1 << 40));
+        for bad in ["", "0", "0M", "M", "-1G", "1.5G", "2P", "99999999999T"] {
+            assert!(parse_bytes(bad).is_err(), "{} should not parse", bad);
+        }
+    }
+
+    #[test]
+    fn limits_from_spec() {
+        let limits = Limits::from_spec(&spec(json!({
+            "memory_max": "256M",
+            "cpu_quota": "150%",
+            "cpu_weight": 200,
+            "pids_max": 64,
+        })))
+        .unwrap();
+        assert_eq!(limits.memory_max, Some(256 << 20));
+        assert_eq!(limits.cpu_quota, Some(150));
+        assert_eq!(limits.cpu_weight, Some(200));
+        assert_eq!(limits.pids_max, Some(64));
+        assert_eq!(limits.open_files, None);
+
+        assert_eq!(Limits::from_spec(&spec(json!({ "cpu_quota": " 50 %" }))).unwrap().cpu_quota, Some(50));
+    }
+
+    #[test]
+    fn limits_rejects() {
+        for bad in [
+            json!({ "cpu_quota": "50" }),
+            json!({ "cpu_quota": "0%" }),
+            json!({ "cpu_quota": "half%" }),
+            json!({ "cpu_weight": 0 }),
+            json!({ "cpu_weight": 10_001 }),
+            json!({ "pids_max": 0 }),
+            json!({ "open_files": 0 }),
+            json!({ "memory_max": "lots" }),
+        ] {
+            assert!(Limits::from_spec(&spec(bad.clone())).is_err(), "{} should be refused", bad);
+        }
+    }
+}
@@ -1,15 +1,32 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use uuid::Uuid;
+use std::time::Duration;
+
+use crate::activation::ListenAddr;
+use crate::health::HealthCheck;
+use crate::process::{self, StopMode};
+use crate::timer::TimerSpec;
 
 /// Simple restart policy for supervised services
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub enum RestartPolicy {
     Never,
+    #[default]
     OnFailure,
     Always,
 }
 
+/// How the registry decides a service has finished starting.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceType {
+    /// Up as soon as the process is spawned.
+    #[default]
+    Simple,
+    /// Up once it sends READY=1 to its NOTIFY_SOCKET (see notify.rs).
+    Notify,
+}
+
 /// Definition of a service
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServiceSpec {
@@ -17,19 +34,134 @@ pub struct ServiceSpec {
     pub cmd: Vec<String>, // first element is executable
     #[serde(default)]
     pub env: Option<HashMap<String, String>>,
+    /// Start from an empty environment instead of service-registry's own;
+    /// `env` is added either way.
+    #[serde(default)]
+    pub clear_env: bool,
     #[serde(default)]
     pub working_dir: Option<String>,
+    /// User to run as, by name or uid. Switching users needs service-registry
+    /// to run as root; see exec.rs.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Primary group, by name or gid; defaults to the user's.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Groups on top of those /etc/group lists for the user.
+    #[serde(default)]
+    pub supplementary_groups: Vec<String>,
+    /// File mode creation mask as an octal string, e.g. "027".
+    #[serde(default)]
+    pub umask: Option<String>,
     #[serde(default)]
     pub restart: RestartPolicy,
+    /// At most this many restarts within restart_window_secs; one more
+    /// crash and the service is marked failed. Unlimited when unset.
+    #[serde(default)]
+    pub max_restarts: Option<u32>,
+    /// Defaults to DEFAULT_RESTART_WINDOW_SECS.
+    #[serde(default)]
+    pub restart_window_secs: Option<u64>,
+    /// A run lasting this long resets the restart backoff; defaults to
+    /// DEFAULT_STABLE_SECS.
+    #[serde(default)]
+    pub stable_secs: Option<u64>,
+    /// Probe run while the service is up, e.g. "http://127.0.0.1:8080/health";
+    /// after too many failures in a row it is restarted (see health.rs).
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    /// Sockets the registry binds for the service, e.g. "unix:/tmp/web.sock" or
+    /// "tcp:127.0.0.1:8080". With any set, `start` only binds them and the
+    /// service is spawned on the first connection (see activation.rs).
+    #[serde(default)]
+    pub listen: Vec<String>,
+    #[serde(default, rename = "type")]
+    pub kind: ServiceType,
+    /// How long `start` waits for READY=1 from a notify service before
+    /// killing it; defaults to DEFAULT_READY_TIMEOUT_SECS.
+    #[serde(default)]
+    pub ready_timeout_secs: Option<u64>,
+    /// Signal `stop` sends to the service's process group first, by name
+    /// ("SIGTERM", "INT") or number; defaults to SIGTERM.
+    #[serde(default)]
+    pub stop_signal: Option<String>,
+    /// How long after stop_signal the group is sent SIGKILL; defaults to
+    /// DEFAULT_STOP_TIMEOUT_SECS.
     #[serde(default)]
-    pub max_restarts: Option<u32>, // optional limit in a time window
+    pub stop_timeout_secs: Option<u64>,
+    /// Memory limit such as "512M" or "2G" (see resources.rs).
     #[serde(default)]
-    pub health_check: Option<String>, // placeholder e.g. "http://127.0.0.1:8080/health"
+    pub memory_max: Option<String>,
+    /// CPU time as a share of one CPU, e.g. "50%" or "200%".
+    #[serde(default)]
+    pub cpu_quota: Option<String>,
+    /// Relative CPU share under contention, 1..=10000 (100 is the default).
+    #[serde(default)]
+    pub cpu_weight: Option<u64>,
+    /// Most processes and threads the service may have at once.
+    #[serde(default)]
+    pub pids_max: Option<u64>,
+    /// RLIMIT_NOFILE of the service.
+    #[serde(default)]
+    pub open_files: Option<u64>,
+    /// Services started before this one and needed by it; stopping one of
+    /// them stops this service too (see deps.rs).
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Services started before this one if registered, without failing it
+    /// when they don't come up.
+    #[serde(default)]
+    pub wants: Vec<String>,
+    /// Services this one starts after when both are being started.
+    #[serde(default)]
+    pub after: Vec<String>,
+    /// For a template (`worker@`): how many numbered instances starting it
+    /// runs; see template.rs.
+    #[serde(default)]
+    pub replicas: Option<u32>,
+    /// Start the service on a schedule (see timer.rs), e.g.
+    /// `timer = { on = ["0 3 * * *"], persistent = true }`.
+    #[serde(default)]
+    pub timer: Option<TimerSpec>,
+}
+
+pub const DEFAULT_READY_TIMEOUT_SECS: u64 = 10;
+/// Longest service name accepted.
+pub const MAX_NAME_LEN: usize = 128;
+
+/// Service names end up as path components (log file, notify socket,
+/// cgroup), so only plain ones are accepted: letters, digits and `_.@:-`,
+/// and not `.` or `..`.
+pub fn check_name(name: &str) -> Result<(), String> {
+    let ok = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '@' | ':' | '-'))
+        && name != "."
+        && name != "..";
+    if ok {
+        Ok(())
+    } else {
+        Err(format!("invalid service name {:?}: use letters, digits, '_', '.', '@', ':' and '-'", name))
+    }
 }
 
+pub const DEFAULT_RESTART_WINDOW_SECS: u64 = 60;
+pub const DEFAULT_STABLE_SECS: u64 = 30;
+pub const DEFAULT_STOP_TIMEOUT_SECS: u64 = 10;
+
 impl ServiceSpec {
-    pub fn id(&self) -> String {
-        // stable id derived from name
-        format!("{}-{}", self.name, Uuid::new_v4().to_string())
+    /// How `stop` ends the service.
+    pub fn stop_mode(&self) -> Result<StopMode, String> {
+        let signal = match &self.stop_signal {
+            Some(sig) => process::parse_signal(sig).map_err(|e| format!("stop_signal: {}", e))?,
+            None => libc::SIGTERM,
+        };
+        let timeout = Duration::from_secs(self.stop_timeout_secs.unwrap_or(DEFAULT_STOP_TIMEOUT_SECS));
+        Ok(StopMode { signal, timeout })
+    }
+
+    /// Parsed `listen` addresses; empty for a service started directly.
+    pub fn listen_addrs(&self) -> Result<Vec<ListenAddr>, String> {
+        self.listen.iter().map(|a| a.parse()).collect()
     }
 }
@@ -0,0 +1,127 @@
+//! Template services. A spec named `worker@` is a template: it is never run
+//! itself, but starting `worker@a` registers an instance of it, a copy with
+//! `%i` replaced by `a` in cmd, env, working_dir, listen and the dependency
+//! lists. With `replicas` set, starting the template runs the numbered
+//! instances `worker@1` ..= `worker@N`, and `scale` changes N.
+
+use crate::service::ServiceSpec;
+
+/// Replaced by the instance name.
+pub const INSTANCE: &str = "%i";
+
+/// Whether `name` is a template name such as `worker@`.
+pub fn is_template(name: &str) -> bool {
+    name.len() > 1 && name.find('@') == Some(name.len() - 1)
+}
+
+/// `worker@a` -> (`worker@`, `a`); `None` for a name that is not an instance.
+pub fn split(name: &str) -> Option<(&str, &str)> {
+    let at = name.find('@')?;
+    let (template, instance) = name.split_at(at + 1);
+    (at > 0 && !instance.is_empty()).then_some((template, instance))
+}
+
+/// Instance names end up in file names (logs, sockets), so they are kept short
+/// and plain.
+pub fn check_instance(instance: &str) -> Result<(), String> {
+    let ok = instance.len() <= 64
+        && instance.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+        && !instance.starts_with('.');
+    if ok {
+        Ok(())
+    } else {
+        Err(format!("invalid instance name {:?}: use letters, digits, '-', '_', '.' and ':'", instance))
+    }
+}
+
+/// The spec of instance `instance` of `template`.
+pub fn instantiate(template: &ServiceSpec, instance: &str) -> ServiceSpec {
+    let sub = |s: &String| s.replace(INSTANCE, instance);
+    let mut spec = template.clone();
+    spec.name = format!("{}{}", template.name, instance);
+    spec.replicas = None;
+    spec.cmd = template.cmd.iter().map(sub).collect();
+    spec.env = template.env.as_ref().map(|env| env.iter().map(|(k, v)| (k.clone(), sub(v))).collect());
+    spec.working_dir = template.working_dir.as_ref().map(sub);
+    spec.listen = template.listen.iter().map(sub).collect();
+    spec.requires = template.requires.iter().map(sub).collect();
+    spec.wants = template.wants.iter().map(sub).collect();
+    spec.after = template.after.iter().map(sub).collect();
+    spec
+}
+
+/// Name of replica `n` of `template`.
+pub fn numbered(template: &str, n: u32) -> String {
+    format!("{}{}", template, n)
+}
+
+/// `n` for an instance named like a replica (`3`, not `03`).
+pub fn replica(instance: &str) -> Option<u32> {
+    instance.parse().ok().filter(|n: &u32| *n > 0 && n.to_string() == instance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn names() {
+        assert!(is_template("worker@"));
+        assert!(!is_template("worker@a"));
+        assert!(!is_template("@"));
+        assert!(!is_template("a@b@"));
+        assert_eq!(split("worker@a"), Some(("worker@", "a")));
+        assert_eq!(split("worker@a@b"), Some(("worker@", "a@b")));
+        assert_eq!(split("worker@"), None);
+        assert_eq!(split("@a"), None);
+        assert_eq!(split("worker"), None);
+    }
+
+    #[test]
+    fn instance_names() {
+        for ok in ["a", "eth0", "web-1.example:8080", "a_b"] {
+            assert!(check_instance(ok).is_ok(), "{} should be allowed", ok);
+        }
+        let long = "x".repeat(65);
+        for bad in [".hidden", "a/b", "a b", "a@b", long.as_str()] {
+            assert!(check_instance(bad).is_err(), "{} should be refused", bad);
+        }
+    }
+
+    #[test]
+    fn instantiate_replaces_every_placeholder() {
+        let template: ServiceSpec = serde_json::from_value(json!({
+            "name": "worker@",
+            "cmd": ["/usr/bin/worker", "--queue", "%i", "--log=/var/log/%i-%i.log"],
+            "env": { "QUEUE": "%i" },
+            "working_dir": "/srv/%i",
+            "listen": ["unix:/run/worker-%i.sock"],
+            "requires": ["db@%i"],
+            "wants": ["cache"],
+            "after": ["net@%i"],
+            "replicas": 3,
+        }))
+        .unwrap();
+        let spec = instantiate(&template, "mail");
+        assert_eq!(spec.name, "worker@mail");
+        assert_eq!(spec.cmd, ["/usr/bin/worker", "--queue", "mail", "--log=/var/log/mail-mail.log"]);
+        assert_eq!(spec.env.unwrap()["QUEUE"], "mail");
+        assert_eq!(spec.working_dir.as_deref(), Some("/srv/mail"));
+        assert_eq!(spec.listen, ["unix:/run/worker-mail.sock"]);
+        assert_eq!(spec.requires, ["db@mail"]);
+        assert_eq!(spec.wants, ["cache"]);
+        assert_eq!(spec.after, ["net@mail"]);
+        assert_eq!(spec.replicas, None);
+    }
+
+    #[test]
+    fn replicas() {
+        assert_eq!(numbered("worker@", 3), "worker@3");
+        assert_eq!(replica("3"), Some(3));
+        assert_eq!(replica("12"), Some(12));
+        for not in ["0", "03", "+3", "-1", "a", ""] {
+            assert_eq!(replica(not), None, "{} is not a replica", not);
+        }
+    }
+}
@@ -0,0 +1,395 @@
+//! Timers: starting a service on a schedule, for periodic jobs such as
+//! cleanups and backups. A spec's `timer` lists its triggers in `on`:
+//!
+//! - `every 10m`: monotonic, that long after the previous trigger (or after
+//!   the timer was armed).
+//! - `5m after boot`: once, that long after service-registry started.
+//! - a calendar expression in cron form, `minute hour day month weekday`
+//!   (e.g. `30 3 * * *`, `*/15 * * * mon-fri`), or `@hourly`, `@daily`,
+//!   `@weekly`, `@monthly`, `@yearly`; in local time.
+//!
+//! The earliest trigger wins. `jitter` delays each run by a random amount
+//! up to it; the schedule itself stays put, so runs do not drift. With
+//! `persistent` the time of the last trigger is kept in a state file, so a
+//! run missed while the registry was down happens once right after it comes
+//! back.
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::warn;
+
+/// Used when core-daemon does not pass CIRCLEOSD_TIMER_STATE.
+const DEFAULT_STATE_FILE: &str = "var/lib/timers.json";
+/// How far ahead a calendar expression is searched for its next match.
+const CALENDAR_HORIZON_DAYS: u32 = 366 * 5;
+
+static BOOT: OnceLock<DateTime<Utc>> = OnceLock::new();
+
+/// When service-registry started; `after boot` triggers count from here.
+pub fn boot() -> DateTime<Utc> {
+    *BOOT.get_or_init(Utc::now)
+}
+
+/// `timer` of a service spec.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimerSpec {
+    /// Triggers, e.g. "every 10m", "5m after boot" or "0 3 * * *".
+    pub on: Vec<String>,
+    /// Random delay added to each trigger, e.g. "30s" or "5m".
+    #[serde(default)]
+    pub jitter: Option<String>,
+    /// Keep the last trigger across registry restarts and catch up on a
+    /// missed one.
+    #[serde(default)]
+    pub persistent: bool,
+}
+
+/// A parsed TimerSpec.
+#[derive(Debug, Clone)]
+pub struct Timer {
+    triggers: Vec<Trigger>,
+    jitter: Duration,
+    pub persistent: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Trigger {
+    Every(Duration),
+    AfterBoot(Duration),
+    Calendar(Calendar),
+}
+
+impl Timer {
+    pub fn from_spec(spec: &TimerSpec) -> Result<Self, String> {
+        if spec.on.is_empty() {
+            return Err("`on` lists no triggers".into());
+        }
+        let triggers = spec.on.iter().map(|t| Trigger::parse(t)).collect::<Result<_, _>>()?;
+        let jitter = spec.jitter.as_deref().map(parse_duration).transpose()?.unwrap_or_default();
+        Ok(Timer { triggers, jitter, persistent: spec.persistent })
+    }
+
+    /// When the timer is scheduled to fire next, given the last scheduled
+    /// trigger (if any) and when it was armed; jitter is not included. A
+    /// trigger already past is due now; that is how a missed run catches up.
+    /// `None` once only spent `after boot` triggers are left.
+    pub fn next(&self, last: Option<DateTime<Utc>>, armed: DateTime<Utc>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let base = last.unwrap_or(armed);
+        let next = self
+            .triggers
+            .iter()
+            .filter_map(|trigger| match trigger {
+                Trigger::Every(d) => Some(base + *d),
+                Trigger::AfterBoot(d) => {
+                    let at = boot() + *d;
+                    // a timer armed after that point has missed it for this boot
+                    (at >= armed && last.is_none_or(|l| l < at)).then_some(at)
+                }
+                Trigger::Calendar(cal) => cal.next_after(base),
+            })
+            .min()?;
+        Some(next.max(now))
+    }
+
+    /// A random delay for one run, up to `jitter`.
+    pub fn delay(&self) -> Duration {
+        random_up_to(self.jitter)
+    }
+}
+
+impl Trigger {
+    fn parse(s: &str) -> Result<Self, String> {
+        let t = s.trim();
+        if let Some(d) = t.strip_prefix("every ") {
+            let d = parse_duration(d)?;
+            if d.is_zero() {
+                return Err(format!("`{}`: the interval must be more than 0", s));
+            }
+            Ok(Trigger::Every(d))
+        } else if let Some(d) = t.strip_suffix(" after boot") {
+            Ok(Trigger::AfterBoot(parse_duration(d)?))
+        } else {
+            Calendar::parse(t).map(Trigger::Calendar).map_err(|e| format!("`{}`: {}", s, e))
+        }
+    }
+}
+
+/// A cron expression, as the set of values each field matches.
+#[derive(Debug, Clone)]
+struct Calendar {
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    /// Day of month and weekday restricted both: either may match, as in cron.
+    /// A field starting with `*` (`*/2` too) does not count as restricted.
+    either_day: bool,
+}
+
+const MONTHS: &[&str] = &["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl Calendar {
+    fn parse(s: &str) -> Result<Self, String> {
+        let expr = match s {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * sun",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            _ => s,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err("not \"every <interval>\", \"<interval> after boot\" or a calendar expression \"minute hour day month weekday\"".into());
+        };
+        let weekdays = field(weekday, 0, 7, WEEKDAYS)?;
+        Ok(Calendar {
+            minutes: field(minute, 0, 59, &[])?,
+            hours: field(hour, 0, 23, &[])? as u32,
+            days: field(day, 1, 31, &[])? as u32,
+            months: field(month, 1, 12, MONTHS)? as u16,
+            // 7 is Sunday too
+            weekdays: ((weekdays | weekdays >> 7) & 0x7f) as u8,
+            either_day: !day.starts_with('*') && !weekday.starts_with('*'),
+        })
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = self.days & 1 << date.day() != 0;
+        let weekday = self.weekdays & 1 << date.weekday().num_days_from_sunday() != 0;
+        self.months & 1 << date.month() != 0 && if self.either_day { day || weekday } else { day && weekday }
+    }
+
+    /// The first matching minute after `after`.
+    fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let after = after.with_timezone(&Local);
+        let mut date = after.date_naive();
+        for _ in 0..CALENDAR_HORIZON_DAYS {
+            if self.matches_day(date) {
+                for hour in (0..24).filter(|h| self.hours & 1 << h != 0) {
+                    for minute in (0..60).filter(|m| self.minutes & 1 << m != 0) {
+                        // a time skipped by a DST change does not match
+                        let Some(at) = date.and_hms_opt(hour, minute, 0).and_then(|t| Local.from_local_datetime(&t).earliest()) else {
+                            continue;
+                        };
+                        if at > after {
+                            return Some(at.with_timezone(&Utc));
+                        }
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+}
+
+/// One cron field as a bit set: `*`, `5`, `1-5`, `*/15`, `10-50/10`, lists
+/// of those, and names where `names` are given (counted from `min`).
+fn field(s: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |v: &str| -> Result<u32, String> {
+        let lower = v.to_ascii_lowercase();
+        let n = match names.iter().position(|n| *n == lower) {
+            Some(i) => i as u32 + min,
+            None => v.parse().map_err(|_| format!("`{}` is not a number", v))?,
+        };
+        if (min..=max).contains(&n) {
+            Ok(n)
+        } else {
+            Err(format!("`{}` is out of range {}-{}", v, min, max))
+        }
+    };
+    let mut bits = 0u64;
+    for part in s.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("`{}`: bad step", part)),
+            },
+            None => (part, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((a, b)) => (value(a)?, value(b)?),
+                // `5/15` runs from 5 to the end
+                None if step > 1 => (value(range)?, max),
+                None => {
+                    let n = value(range)?;
+                    (n, n)
+                }
+            },
+        };
+        if from > to {
+            return Err(format!("`{}`: range runs backwards", part));
+        }
+        for n in (from..=to).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+/// "90s", "10m", "1h30m", "2d"; a bare number is seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let bad = || format!("`{}` is not a duration such as \"30s\", \"10m\" or \"1h30m\"", s);
+    let t = s.trim();
+    if let Ok(secs) = t.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    let mut total = 0u64;
+    let mut num = String::new();
+    for c in t.chars() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return Err(bad()),
+        };
+        let n: u64 = num.parse().map_err(|_| bad())?;
+        total = n.checked_mul(unit).and_then(|n| total.checked_add(n)).ok_or_else(bad)?;
+        num.clear();
+    }
+    if !num.is_empty() || t.is_empty() {
+        return Err(bad());
+    }
+    Ok(Duration::from_secs(total))
+}
+
+/// A random duration below `max` (zero for zero).
+fn random_up_to(max: Duration) -> Duration {
+    let millis = max.as_millis() as u64;
+    if millis == 0 {
+        return Duration::ZERO;
+    }
+    // RandomState is seeded randomly; good enough to spread timers apart
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(Utc::now().timestamp_nanos_opt().unwrap_or_default() as u128);
+    Duration::from_millis(hasher.finish() % millis)
+}
+
+/// File holding the last trigger of each timer.
+pub fn state_file() -> PathBuf {
+    std::env::var_os("CIRCLEOSD_TIMER_STATE")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_FILE))
+}
+
+/// Last triggers saved by an earlier run; empty when there are none.
+pub fn load_runs() -> HashMap<String, DateTime<Utc>> {
+    let path = state_file();
+    match std::fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            warn!("ignoring {}: {}", path.display(), e);
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// Write the last triggers through a temporary file, so a crash never
+/// leaves half of it behind.
+pub fn save_runs(runs: &HashMap<String, DateTime<Utc>>) -> std::io::Result<()> {
+    let path = state_file();
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(runs)?)?;
+    std::fs::rename(&tmp, &path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Local.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn calendar_parse() {
+        let cal = Calendar::parse("*/15 3,4 * * mon-fri").unwrap();
+        assert_eq!(cal.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(cal.hours, 1 << 3 | 1 << 4);
+        assert_eq!(cal.weekdays, 0b0111110);
+        assert!(!cal.either_day);
+
+        // 7 is Sunday as well as 0
+        assert_eq!(Calendar::parse("0 0 * * 7").unwrap().weekdays, 1);
+        assert_eq!(Calendar::parse("0 0 * JAN,dec *").unwrap().months, 1 << 1 | 1 << 12);
+        assert_eq!(Calendar::parse("5/20 * * * *").unwrap().minutes, 1 << 5 | 1 << 25 | 1 << 45);
+        assert_eq!(Calendar::parse("@daily").unwrap().minutes, 1);
+
+        for bad in ["60 * * * *", "* * *", "5-1 * * * *", "*/0 * * * *", "0 0 0 * *", "0 0 * * fr"] {
+            assert!(Calendar::parse(bad).is_err(), "{} should not parse", bad);
+        }
+    }
+
+    #[test]
+    fn calendar_either_day_only_when_both_restricted() {
+        assert!(Calendar::parse("0 0 13 * fri").unwrap().either_day);
+        assert!(!Calendar::parse("0 0 */2 * fri").unwrap().either_day);
+        assert!(!Calendar::parse("0 0 13 * */2").unwrap().either_day);
+        assert!(!Calendar::parse("0 0 13 * *").unwrap().either_day);
+    }
+
+    #[test]
+    fn calendar_next_after() {
+        let daily = Calendar::parse("30 3 * * *").unwrap();
+        assert_eq!(daily.next_after(local(2026, 6, 10, 2, 0)), Some(local(2026, 6, 10, 3, 30)));
+        assert_eq!(daily.next_after(local(2026, 6, 10, 4, 0)), Some(local(2026, 6, 11, 3, 30)));
+        // strictly after
+        assert_eq!(daily.next_after(local(2026, 6, 10, 3, 30)), Some(local(2026, 6, 11, 3, 30)));
+
+        // 2026-06-01 is a Monday: Friday the 5th comes before the 13th
+        let either = Calendar::parse("0 0 13 * fri").unwrap();
+        assert_eq!(either.next_after(local(2026, 6, 1, 0, 0)), Some(local(2026, 6, 5, 0, 0)));
+        // odd days, but only on Fridays: the 5th, then not the 12th
+        let both = Calendar::parse("0 0 */2 * fri").unwrap();
+        assert_eq!(both.next_after(local(2026, 6, 1, 0, 0)), Some(local(2026, 6, 5, 0, 0)));
+        assert_eq!(both.next_after(local(2026, 6, 6, 0, 0)), Some(local(2026, 6, 19, 0, 0)));
+
+        let never = Calendar::parse("0 0 31 feb *").unwrap();
+        assert_eq!(never.next_after(local(2026, 6, 1, 0, 0)), None);
+    }
+
+    #[test]
+    fn every_does_not_drift_with_jitter() {
+        let spec = TimerSpec { on: vec!["every 10m".into()], jitter: Some("5m".into()), persistent: false };
+        let timer = Timer::from_spec(&spec).unwrap();
+        let last = local(2026, 6, 10, 12, 0);
+        for _ in 0..5 {
+            assert_eq!(timer.next(Some(last), last, last), Some(local(2026, 6, 10, 12, 10)));
+            assert!(timer.delay() < Duration::from_secs(300));
+        }
+        // past due: now
+        let now = local(2026, 6, 10, 13, 0);
+        assert_eq!(timer.next(Some(last), last, now), Some(now));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("45"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration(" 2d "), Ok(Duration::from_secs(2 * 86400)));
+        for bad in ["", "m", "10x", "10m5", "1.5h", "-1s"] {
+            assert!(parse_duration(bad).is_err(), "{:?} should not parse", bad);
+        }
+    }
+}